@@ -0,0 +1,5 @@
+-- draws are scoped to an activity: remember which activity each record belongs to
+ALTER TABLE lottery_records
+  ADD COLUMN IF NOT EXISTS activity_id UUID NULL REFERENCES activities(id) ON DELETE SET NULL;
+CREATE INDEX IF NOT EXISTS idx_records_user_activity_created ON lottery_records(user_id, activity_id, created_at);
+CREATE INDEX IF NOT EXISTS idx_prizes_activity_enabled ON prizes(activity_id) WHERE is_enabled;
@@ -0,0 +1,3 @@
+-- per-activity draw cooldown (seconds between two draws of the same user); 0 disables it
+ALTER TABLE activities
+  ADD COLUMN IF NOT EXISTS draw_cooldown_secs INT NOT NULL DEFAULT 60;
@@ -0,0 +1,7 @@
+-- per-user draw quotas of an activity; NULL means unlimited
+ALTER TABLE activities
+  ADD COLUMN IF NOT EXISTS max_draws_total INT NULL,
+  ADD COLUMN IF NOT EXISTS max_draws_daily INT NULL,
+  ADD COLUMN IF NOT EXISTS max_wins_total INT NULL,
+  -- IANA timezone that defines the calendar day for max_draws_daily
+  ADD COLUMN IF NOT EXISTS quota_timezone TEXT NOT NULL DEFAULT 'UTC';
@@ -0,0 +1,10 @@
+-- stored draw responses for Idempotency-Key replays on the SQL-only draw path
+CREATE TABLE IF NOT EXISTS draw_idempotency_keys (
+  user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  idem_key TEXT NOT NULL,
+  activity_id UUID NOT NULL,
+  response TEXT NOT NULL,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+  PRIMARY KEY (user_id, idem_key)
+);
+CREATE INDEX IF NOT EXISTS idx_draw_idempotency_created ON draw_idempotency_keys(created_at);
@@ -0,0 +1,9 @@
+-- sold-delta batches already applied to prizes.remaining_count; makes replays of an
+-- unacknowledged Redis batch (crash between commit and ack) a no-op
+CREATE TABLE IF NOT EXISTS stock_flush_batches (
+  batch_id UUID PRIMARY KEY,
+  prize_id UUID NOT NULL REFERENCES prizes(id) ON DELETE CASCADE,
+  delta BIGINT NOT NULL,
+  applied_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+CREATE INDEX IF NOT EXISTS idx_stock_flush_batches_applied_at ON stock_flush_batches(applied_at);
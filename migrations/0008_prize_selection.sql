@@ -0,0 +1,18 @@
+-- how an activity picks a prize: the selection algorithm and how prizes.probability is read
+DO $$ BEGIN
+  CREATE TYPE prize_selector AS ENUM ('linear','alias');
+EXCEPTION
+  WHEN duplicate_object THEN NULL;
+END $$;
+
+DO $$ BEGIN
+  -- percent: no-win weight is 100 - sum; basis_points: 10000 - sum; weight: activities.no_win_weight
+  CREATE TYPE probability_unit AS ENUM ('percent','basis_points','weight');
+EXCEPTION
+  WHEN duplicate_object THEN NULL;
+END $$;
+
+ALTER TABLE activities
+  ADD COLUMN IF NOT EXISTS prize_selector prize_selector NOT NULL DEFAULT 'linear',
+  ADD COLUMN IF NOT EXISTS probability_unit probability_unit NOT NULL DEFAULT 'percent',
+  ADD COLUMN IF NOT EXISTS no_win_weight INT NOT NULL DEFAULT 0;
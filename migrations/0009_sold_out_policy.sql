@@ -0,0 +1,19 @@
+-- what a draw does when the selected prize is sold out
+DO $$ BEGIN
+  -- lose: no prize; redraw: pick again among in-stock prizes by weight; downgrade: next lower tier in stock
+  CREATE TYPE sold_out_policy AS ENUM ('lose','redraw','downgrade');
+EXCEPTION
+  WHEN duplicate_object THEN NULL;
+END $$;
+
+ALTER TABLE activities
+  ADD COLUMN IF NOT EXISTS sold_out_policy sold_out_policy NOT NULL DEFAULT 'lose';
+
+-- prize tier, 1 = top prize; larger numbers are lower tiers
+ALTER TABLE prizes
+  ADD COLUMN IF NOT EXISTS tier INT NOT NULL DEFAULT 1;
+
+-- existing prizes: the rarer, the higher the tier
+UPDATE prizes p SET tier = r.tier
+FROM (SELECT id, dense_rank() OVER (PARTITION BY activity_id ORDER BY probability) AS tier FROM prizes) r
+WHERE p.id = r.id;
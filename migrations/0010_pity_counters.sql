@@ -0,0 +1,18 @@
+-- guaranteed wins: after `threshold` draws in a row without a prize of tier <= `tier`,
+-- the next draw of the user in the activity is forced to such a prize
+CREATE TABLE IF NOT EXISTS activity_pity_rules (
+  activity_id UUID NOT NULL REFERENCES activities(id) ON DELETE CASCADE,
+  tier INT NOT NULL,
+  threshold INT NOT NULL CHECK (threshold > 0),
+  PRIMARY KEY (activity_id, tier)
+);
+
+-- durable copy of the per-user counters (Redis holds the live ones on the Redis path)
+CREATE TABLE IF NOT EXISTS pity_counters (
+  user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  activity_id UUID NOT NULL REFERENCES activities(id) ON DELETE CASCADE,
+  tier INT NOT NULL,
+  draws_since_win INT NOT NULL DEFAULT 0,
+  updated_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+  PRIMARY KEY (user_id, activity_id, tier)
+);
@@ -0,0 +1,26 @@
+-- provably fair draws: the roll of a draw is HMAC-SHA256(server_seed, "{client_seed}:{nonce}");
+-- sha256(server_seed) is published up front and server_seed itself once the activity has ended
+ALTER TABLE activities
+  ADD COLUMN IF NOT EXISTS provably_fair BOOLEAN NOT NULL DEFAULT false;
+
+CREATE TABLE IF NOT EXISTS activity_fair_seeds (
+  activity_id UUID PRIMARY KEY REFERENCES activities(id) ON DELETE CASCADE,
+  server_seed TEXT NOT NULL,
+  server_seed_hash TEXT NOT NULL,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+  revealed_at TIMESTAMPTZ NULL
+);
+
+-- next nonce per user and activity on the SQL draw path (Redis keeps its own counter)
+CREATE TABLE IF NOT EXISTS fair_nonces (
+  user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  activity_id UUID NOT NULL REFERENCES activities(id) ON DELETE CASCADE,
+  last_nonce BIGINT NOT NULL,
+  PRIMARY KEY (user_id, activity_id)
+);
+
+-- inputs of the roll, NULL for draws of activities that are not provably fair
+ALTER TABLE lottery_records
+  ADD COLUMN IF NOT EXISTS server_seed_hash TEXT NULL,
+  ADD COLUMN IF NOT EXISTS client_seed TEXT NULL,
+  ADD COLUMN IF NOT EXISTS nonce BIGINT NULL;
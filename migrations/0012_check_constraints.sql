@@ -0,0 +1,19 @@
+-- row-level invariants also checked by the admin API (the per-activity probability sum is checked there only)
+DO $$ BEGIN
+  ALTER TABLE activities ADD CONSTRAINT activities_time_window_check CHECK (end_time > start_time);
+  ALTER TABLE activities ADD CONSTRAINT activities_cooldown_check CHECK (draw_cooldown_secs >= 0);
+  ALTER TABLE activities ADD CONSTRAINT activities_quotas_check
+    CHECK (COALESCE(max_draws_total, 0) >= 0 AND COALESCE(max_draws_daily, 0) >= 0 AND COALESCE(max_wins_total, 0) >= 0);
+  ALTER TABLE activities ADD CONSTRAINT activities_no_win_weight_check CHECK (no_win_weight >= 0);
+EXCEPTION
+  WHEN duplicate_object THEN NULL;
+END $$;
+
+DO $$ BEGIN
+  ALTER TABLE prizes ADD CONSTRAINT prizes_total_count_check CHECK (total_count >= 0);
+  ALTER TABLE prizes ADD CONSTRAINT prizes_remaining_count_check CHECK (remaining_count >= 0 AND remaining_count <= total_count);
+  ALTER TABLE prizes ADD CONSTRAINT prizes_probability_check CHECK (probability >= 0);
+  ALTER TABLE prizes ADD CONSTRAINT prizes_tier_check CHECK (tier >= 1);
+EXCEPTION
+  WHEN duplicate_object THEN NULL;
+END $$;
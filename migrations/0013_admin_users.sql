@@ -0,0 +1,17 @@
+-- admin accounts with roles; replaces the single ADMIN_USERNAME/ADMIN_PASSWORD identity
+DO $$ BEGIN
+  CREATE TYPE admin_role AS ENUM ('viewer','operator','superadmin');
+EXCEPTION
+  WHEN duplicate_object THEN NULL;
+END $$;
+
+CREATE TABLE IF NOT EXISTS admin_users (
+  id UUID PRIMARY KEY,
+  username TEXT NOT NULL UNIQUE,
+  password_hash TEXT NOT NULL,
+  role admin_role NOT NULL,
+  is_active BOOLEAN NOT NULL DEFAULT true,
+  last_login_at TIMESTAMPTZ NULL,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+  updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
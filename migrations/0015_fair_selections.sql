@@ -0,0 +1,13 @@
+-- what the roll of a provably fair draw was mapped onto: selector, probability unit, no-win weight and the enabled
+-- prizes in selection order, as JSON whose sha256 is selection_hash; later prize edits do not change past outcomes
+CREATE TABLE IF NOT EXISTS fair_selections (
+  activity_id UUID NOT NULL REFERENCES activities(id) ON DELETE CASCADE,
+  selection_hash TEXT NOT NULL,
+  selection TEXT NOT NULL,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+  PRIMARY KEY (activity_id, selection_hash)
+);
+
+-- NULL for draws made before snapshots existed; those verify against the current prize table
+ALTER TABLE lottery_records
+  ADD COLUMN IF NOT EXISTS selection_hash TEXT NULL;
@@ -0,0 +1,23 @@
+-- 0012 added each table's constraints in one block that stopped at the first one already present; add any
+-- constraint it skipped, one at a time
+DO $$
+DECLARE
+  c RECORD;
+BEGIN
+  FOR c IN SELECT * FROM (VALUES
+    ('activities', 'activities_time_window_check', 'CHECK (end_time > start_time)'),
+    ('activities', 'activities_cooldown_check', 'CHECK (draw_cooldown_secs >= 0)'),
+    ('activities', 'activities_quotas_check',
+      'CHECK (COALESCE(max_draws_total, 0) >= 0 AND COALESCE(max_draws_daily, 0) >= 0 AND COALESCE(max_wins_total, 0) >= 0)'),
+    ('activities', 'activities_no_win_weight_check', 'CHECK (no_win_weight >= 0)'),
+    ('prizes', 'prizes_total_count_check', 'CHECK (total_count >= 0)'),
+    ('prizes', 'prizes_remaining_count_check', 'CHECK (remaining_count >= 0 AND remaining_count <= total_count)'),
+    ('prizes', 'prizes_probability_check', 'CHECK (probability >= 0)'),
+    ('prizes', 'prizes_tier_check', 'CHECK (tier >= 1)')
+  ) AS t(tbl, name, def)
+  LOOP
+    IF NOT EXISTS (SELECT 1 FROM pg_constraint WHERE conname = c.name AND conrelid = c.tbl::regclass) THEN
+      EXECUTE format('ALTER TABLE %I ADD CONSTRAINT %I %s', c.tbl, c.name, c.def);
+    END IF;
+  END LOOP;
+END $$;
@@ -0,0 +1,7 @@
+-- access token denylist for deployments without Redis (the SQL draw backend), so a logout holds on every
+-- instance; rows past expires_at are dead and get pruned on the next revocation
+CREATE TABLE IF NOT EXISTS revoked_tokens (
+  jti TEXT PRIMARY KEY,
+  expires_at TIMESTAMPTZ NOT NULL
+);
+CREATE INDEX IF NOT EXISTS idx_revoked_tokens_expires_at ON revoked_tokens(expires_at);
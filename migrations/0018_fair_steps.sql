@@ -0,0 +1,11 @@
+-- what decided a provably fair draw after its selection roll (pity guarantee, sold-out policy, wins quota, the
+-- candidates found out of stock) as JSON, replayed by verification; NULL for earlier draws
+ALTER TABLE lottery_records
+  ADD COLUMN IF NOT EXISTS fair_steps TEXT NULL;
+
+-- the Redis draw path takes its nonces from fair_nonces too now; carry over the ones it already handed out
+INSERT INTO fair_nonces (user_id, activity_id, last_nonce)
+SELECT user_id, activity_id, max(nonce) FROM lottery_records
+WHERE nonce IS NOT NULL AND activity_id IS NOT NULL
+GROUP BY user_id, activity_id
+ON CONFLICT (user_id, activity_id) DO UPDATE SET last_nonce = GREATEST(fair_nonces.last_nonce, EXCLUDED.last_nonce);
@@ -1,22 +1,52 @@
 use crate::{
     config::Config,
     error::{AppError, AppResult},
+    models::AdminRole,
+    state::{AppState, StateData},
+    services::admin_service,
 };
 use argon2::{
     password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
     Argon2,
 };
-use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Request},
+    http::request::Parts,
+    middleware::Next,
+    response::Response,
+};
+use axum_extra::{
+    headers::{authorization::Bearer, Authorization},
+    TypedHeader,
+};
+use jsonwebtoken::{Header, Validation};
 use rand::rngs::OsRng;
 use serde::{Deserialize, Serialize};
+use sqlx::types::Uuid;
+use std::marker::PhantomData;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Claims {
     pub sub: String,
     pub uid: String,
     pub exp: usize,
-    pub is_admin: bool,
+    /// token id, the key of the revocation denylist
+    pub jti: String,
+    /// set for admin tokens; the role is re-read from `admin_users` on every admin request
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub role: Option<AdminRole>,
+}
+
+impl Claims {
+    /// Id of the user a user token belongs to; admin tokens are not user tokens.
+    pub fn user_id(&self) -> AppResult<Uuid> {
+        if self.role.is_some() {
+            return Err(AppError::Unauthorized);
+        }
+        Uuid::parse_str(&self.uid).map_err(|_| AppError::Unauthorized)
+    }
 }
 
 pub fn now_ts() -> usize {
@@ -26,36 +56,46 @@ pub fn now_ts() -> usize {
         .as_secs() as usize
 }
 
-pub fn sign_jwt(cfg: &Config, uid: &str, is_admin: bool) -> AppResult<String> {
+/// Admin tokens are not refreshed; they end with a logout, or with disabling the admin, which is checked per request.
+pub const ADMIN_TOKEN_TTL_SECS: usize = 8 * 3600;
+
+pub fn sign_jwt(cfg: &Config, uid: &str, role: Option<AdminRole>) -> AppResult<String> {
+    let ttl = if role.is_some() { ADMIN_TOKEN_TTL_SECS } else { cfg.auth.access_token_ttl_secs as usize };
     let claims = Claims {
-        sub: if is_admin {
+        sub: if role.is_some() {
             "admin".into()
         } else {
             "user".into()
         },
         uid: uid.into(),
-        exp: now_ts() + 60 * 60 * 24 * 7,
-        is_admin,
+        exp: now_ts() + ttl,
+        jti: Uuid::new_v4().to_string(),
+        role,
     };
-    let token = jsonwebtoken::encode(
-        &Header::new(Algorithm::HS256),
-        &claims,
-        &EncodingKey::from_secret(cfg.jwt_secret.as_bytes()),
-    )
-    .map_err(|_| AppError::Internal("jwt encode failed"))?;
+    let signing = cfg.jwt.signing();
+    let mut header = Header::new(signing.alg);
+    header.kid = signing.kid.clone();
+    let token = jsonwebtoken::encode(&header, &claims, &signing.key)
+        .map_err(|_| AppError::Internal("jwt encode failed"))?;
     Ok(token)
 }
 
-pub fn verify_jwt(cfg: &Config, token: &str) -> AppResult<Claims> {
-    let data = jsonwebtoken::decode::<Claims>(
-        token,
-        &DecodingKey::from_secret(cfg.jwt_secret.as_bytes()),
-        &Validation::new(Algorithm::HS256),
-    )
-    .map_err(|_| AppError::Unauthorized)?;
+/// Decodes a token with the key named by its `kid` header and rejects it if it was revoked.
+pub async fn verify_jwt(state: &StateData, token: &str) -> AppResult<Claims> {
+    let cfg = state.cfg.get();
+    let header = jsonwebtoken::decode_header(token).map_err(|_| AppError::Unauthorized)?;
+    let (key, alg) = cfg.jwt.verifying(header.kid.as_deref()).ok_or(AppError::Unauthorized)?;
+    // the algorithm comes from our key, never from the token
+    let data = jsonwebtoken::decode::<Claims>(token, key, &Validation::new(alg)).map_err(|_| AppError::Unauthorized)?;
+    if state.denylist.is_denied(&data.claims.jti).await {
+        return Err(AppError::Unauthorized);
+    }
     Ok(data.claims)
 }
 
+/// Shortest password accepted for user and admin accounts alike.
+pub const MIN_PASSWORD_LEN: usize = 8;
+
 pub fn hash_password(pw: &str) -> AppResult<String> {
     let salt = SaltString::generate(&mut OsRng);
     let argon2 = Argon2::default();
@@ -72,4 +112,108 @@ pub fn verify_password(pw: &str, hash: &str) -> AppResult<bool> {
     Ok(argon2.verify_password(pw.as_bytes(), &parsed).is_ok())
 }
 
-// custom extractor removed; use explicit header + state in handlers
+/// A request authenticated with a (not revoked) user token. Resolved once per request and cached in the
+/// request extensions, so the router guard and the handler share the work.
+#[derive(Clone)]
+pub struct AuthUser {
+    pub uid: Uuid,
+    pub claims: Claims,
+}
+
+async fn bearer_claims(parts: &mut Parts, state: &AppState) -> AppResult<Claims> {
+    let TypedHeader(Authorization(bearer)) = TypedHeader::<Authorization<Bearer>>::from_request_parts(parts, state)
+        .await
+        .map_err(|_| AppError::Unauthorized)?;
+    verify_jwt(state, bearer.token()).await
+}
+
+#[async_trait]
+impl FromRequestParts<AppState> for AuthUser {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> AppResult<Self> {
+        if let Some(user) = parts.extensions.get::<AuthUser>() {
+            return Ok(user.clone());
+        }
+        let claims = bearer_claims(parts, state).await?;
+        let user = AuthUser { uid: claims.user_id()?, claims };
+        parts.extensions.insert(user.clone());
+        Ok(user)
+    }
+}
+
+/// Minimum admin role of a route, as a type: `AdminUser<role::Operator>`.
+pub mod role {
+    use crate::models::AdminRole;
+
+    pub trait MinRole: Send + Sync + 'static {
+        const ROLE: AdminRole;
+    }
+
+    pub struct Viewer;
+    pub struct Operator;
+    pub struct Superadmin;
+
+    impl MinRole for Viewer {
+        const ROLE: AdminRole = AdminRole::Viewer;
+    }
+    impl MinRole for Operator {
+        const ROLE: AdminRole = AdminRole::Operator;
+    }
+    impl MinRole for Superadmin {
+        const ROLE: AdminRole = AdminRole::Superadmin;
+    }
+}
+
+/// A request of an active admin whose current role is at least `R`. The role is read from `admin_users`, so
+/// demoting or disabling an admin takes effect without waiting for the token to expire.
+pub struct AdminUser<R: role::MinRole = role::Viewer> {
+    pub id: Uuid,
+    pub role: AdminRole,
+    pub claims: Claims,
+    _min: PhantomData<R>,
+}
+
+#[async_trait]
+impl<R: role::MinRole> FromRequestParts<AppState> for AdminUser<R> {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> AppResult<Self> {
+        let ResolvedAdmin { id, role, claims } = match parts.extensions.get::<ResolvedAdmin>() {
+            Some(resolved) => resolved.clone(),
+            None => {
+                let claims = bearer_claims(parts, state).await?;
+                if claims.role.is_none() {
+                    return Err(AppError::Forbidden);
+                }
+                let id = Uuid::parse_str(&claims.uid).map_err(|_| AppError::Unauthorized)?;
+                let role = admin_service::active_role(&state.pool, id).await?.ok_or(AppError::Unauthorized)?;
+                let resolved = ResolvedAdmin { id, role, claims };
+                parts.extensions.insert(resolved.clone());
+                resolved
+            }
+        };
+        if role < R::ROLE {
+            return Err(AppError::Forbidden);
+        }
+        Ok(AdminUser { id, role, claims, _min: PhantomData })
+    }
+}
+
+#[derive(Clone)]
+struct ResolvedAdmin {
+    id: Uuid,
+    role: AdminRole,
+    claims: Claims,
+}
+
+/// Router guard: rejects requests without a valid user token before any handler runs.
+pub async fn require_user(_: AuthUser, req: Request, next: Next) -> Response {
+    next.run(req).await
+}
+
+/// Router guard: rejects requests of anyone but an active admin before any handler runs; handlers still state
+/// the role they need with `AdminUser<R>`.
+pub async fn require_admin(_: AdminUser, req: Request, next: Next) -> Response {
+    next.run(req).await
+}
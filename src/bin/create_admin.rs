@@ -0,0 +1,43 @@
+use std::io::BufRead;
+
+use dotenvy::dotenv;
+use fast_lottery_engine::{
+    auth::{hash_password, MIN_PASSWORD_LEN},
+    config::Config,
+    models::AdminRole,
+    services::admin_service,
+};
+use sqlx::{postgres::PgPoolOptions, types::Uuid, Pool, Postgres};
+
+// Creates an admin account, e.g. the first superadmin of a fresh database:
+//   cargo run --bin create_admin -- <username> [viewer|operator|superadmin]
+// The password is read from ADMIN_PASSWORD, or from the first line of stdin when that is not set.
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    dotenv().ok();
+    let mut args = std::env::args().skip(1);
+    let username = args.next().ok_or_else(|| anyhow::anyhow!("usage: create_admin <username> [viewer|operator|superadmin]"))?;
+    let role: AdminRole = args.next().as_deref().unwrap_or("superadmin").parse()?;
+    let password = match std::env::var("ADMIN_PASSWORD") {
+        Ok(p) => p,
+        Err(_) => {
+            eprintln!("password for {}:", username);
+            let mut line = String::new();
+            std::io::stdin().lock().read_line(&mut line)?;
+            line.trim_end_matches(['\r', '\n']).to_string()
+        }
+    };
+    if username.trim().is_empty() || password.len() < MIN_PASSWORD_LEN {
+        anyhow::bail!("username must not be empty and the password needs at least {} characters", MIN_PASSWORD_LEN);
+    }
+
+    let cfg = Config::load()?;
+    let pool: Pool<Postgres> = PgPoolOptions::new().max_connections(1).connect(&cfg.db.url).await?;
+    if admin_service::find_admin_credentials(&pool, &username).await?.is_some() {
+        anyhow::bail!("admin {} already exists", username);
+    }
+    let hash = hash_password(&password)?;
+    let admin = admin_service::create_admin(&pool, Uuid::new_v4(), username.trim(), &hash, role).await?;
+    println!("created {:?} admin {} ({})", admin.role, admin.username, admin.id);
+    Ok(())
+}
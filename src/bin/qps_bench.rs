@@ -109,7 +109,7 @@ async fn main() -> anyhow::Result<()> {
                 });
                 submitted += 1;
             }
-            if let Some(r) = js.join_next().await { if let Ok(Some(tok)) = r { tokens.push(tok); } }
+            if let Some(Ok(Some(tok))) = js.join_next().await { tokens.push(tok); }
         }
         while let Some(r) = js.join_next().await { if let Ok(Some(tok)) = r { tokens.push(tok); } }
         // try login for any missing
@@ -135,7 +135,7 @@ async fn main() -> anyhow::Result<()> {
                 });
                 started += 1;
             }
-            for _ in 0..batch { if let Some(r) = js.join_next().await { if let Ok(Some(tok)) = r { tokens.push(tok); } } }
+            for _ in 0..batch { if let Some(Ok(Some(tok))) = js.join_next().await { tokens.push(tok); } }
         }
     }
     if tokens.len() < ops {
@@ -143,11 +143,28 @@ async fn main() -> anyhow::Result<()> {
     }
     println!("prepared {} tokens in {:?}", tokens.len(), start_prep.elapsed());
 
+    // draws are scoped to an activity: BENCH_ACTIVITY_ID, or the activity owning PerfPrize (see db_prepare)
+    let activity_id = match std::env::var("BENCH_ACTIVITY_ID") {
+        Ok(id) => id,
+        Err(_) => {
+            let v: serde_json::Value = client.get(format!("{}/api/lottery/prizes", base)).send().await?.json().await?;
+            let prizes = v.get("prizes").and_then(|p| p.as_array()).cloned().unwrap_or_default();
+            prizes
+                .iter()
+                .find(|p| p.get("name").and_then(|n| n.as_str()) == Some("PerfPrize"))
+                .or_else(|| prizes.first())
+                .and_then(|p| p.get("activity_id").and_then(|a| a.as_str()))
+                .map(|s| s.to_string())
+                .ok_or_else(|| anyhow::anyhow!("no prize found; set BENCH_ACTIVITY_ID or run db_prepare"))?
+        }
+    };
+    println!("bench activity: {}", activity_id);
+    let draw_body = json!({"activity_id": activity_id});
+
     // 2) run draw bench: one draw per token (避免频率限制影响)
     let url_draw = format!("{}/api/lottery/draw", base);
     let cnt = Arc::new(AtomicU64::new(0));
-    let mut lat = Vec::with_capacity(ops);
-    lat.resize(ops, 0u128);
+    let mut lat = vec![0u128; ops];
 
     let t0 = Instant::now();
     let mut idx = 0usize;
@@ -157,17 +174,17 @@ async fn main() -> anyhow::Result<()> {
             let client = client.clone();
             let url_draw = url_draw.clone();
             let token = tokens[idx].clone();
-            let slot = idx;
+            let body = draw_body.clone();
             inflight.spawn(async move {
                 let s = Instant::now();
-                let res = client.post(&url_draw).header("authorization", format!("Bearer {}", token)).send().await;
+                let res = client.post(&url_draw).header("authorization", format!("Bearer {}", token)).json(&body).send().await;
                 let _ = res; // even on error we count attempt
                 s.elapsed().as_micros()
             });
             idx += 1;
         }
-        if let Some(r) = inflight.join_next().await {
-            if let Ok(us) = r { let i = cnt.fetch_add(1, Ordering::Relaxed) as usize; if i < lat.len() { lat[i] = us; } }
+        if let Some(Ok(us)) = inflight.join_next().await {
+            let i = cnt.fetch_add(1, Ordering::Relaxed) as usize; if i < lat.len() { lat[i] = us; }
         }
     }
     let elapsed = t0.elapsed();
@@ -1,30 +1,398 @@
-
+use std::collections::BTreeMap;
 use std::env;
+use std::fmt::Display;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+
+use anyhow::{bail, Context};
+use serde::{Deserialize, Deserializer};
+
+use crate::i18n::Locale;
+use crate::jwt_keys::JwtKeys;
+
+// Configuration comes in three layers: built-in defaults, then an optional TOML or YAML file named by
+// CONFIG_FILE, then environment variables, which override single settings of the file. The result is validated
+// as a whole before anything starts. On SIGHUP the layers are read again and the knobs that are safe to change
+// while running (token lifetimes, the default cooldown, background job intervals) are swapped in; the others
+// need a restart and are left as they are.
+
+/// Where draws take stock, quotas and cooldowns from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DrawBackend {
+    /// Lua scripts against Redis, records persisted through the outbox (the production setup)
+    #[default]
+    Redis,
+    /// row locks in Postgres only; no Redis needed
+    Sql,
+}
+
+impl std::str::FromStr for DrawBackend {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "redis" => Ok(DrawBackend::Redis),
+            "sql" => Ok(DrawBackend::Sql),
+            _ => Err(anyhow::anyhow!("unknown draw backend {}, use redis or sql", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub addr: String,
+    /// locale of error messages when Accept-Language names none we support
+    #[serde(deserialize_with = "parse_str")]
+    pub default_locale: Locale,
+    /// on SIGTERM, how long in-flight requests plus the final stock flush and outbox drain may take
+    pub shutdown_timeout_secs: u64,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self { addr: "0.0.0.0:8080".to_string(), default_locale: Locale::default(), shutdown_timeout_secs: 30 }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DbConfig {
+    pub url: String,
+    pub max_connections: u32,
+}
+
+impl Default for DbConfig {
+    fn default() -> Self {
+        Self { url: String::new(), max_connections: 10 }
+    }
+}
 
-use anyhow::Context;
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RedisConfig {
+    /// only used by the Redis backend
+    pub url: String,
+}
+
+impl Default for RedisConfig {
+    fn default() -> Self {
+        Self { url: "redis://127.0.0.1:6379".to_string() }
+    }
+}
+
+/// Token settings. Keys are either the HS256 `jwt_secret`, or a PEM `private_key_file` signing under `key_id`
+/// plus `public_keys` (kid -> PEM path) to verify with; `jwt_secret` then only verifies tokens issued before
+/// the switch.
+#[derive(Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    pub jwt_secret: Option<String>,
+    pub private_key_file: Option<PathBuf>,
+    pub key_id: Option<String>,
+    pub public_keys: BTreeMap<String, PathBuf>,
+    /// lifetime of user access tokens; keep it short, clients renew them with a refresh token
+    pub access_token_ttl_secs: u64,
+    pub refresh_token_ttl_secs: u64,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            jwt_secret: None,
+            private_key_file: None,
+            key_id: None,
+            public_keys: BTreeMap::new(),
+            access_token_ttl_secs: 15 * 60,
+            refresh_token_ttl_secs: 30 * 24 * 3600,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DrawConfig {
+    #[serde(deserialize_with = "parse_str")]
+    pub backend: DrawBackend,
+    /// cooldown of activities created without one
+    pub default_cooldown_secs: i32,
+}
+
+impl Default for DrawConfig {
+    fn default() -> Self {
+        Self { backend: DrawBackend::default(), default_cooldown_secs: 60 }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct JobsConfig {
+    /// full reload of the prize cache (admin changes reload their activity right away)
+    pub prize_cache_refresh_ms: u64,
+    /// flush of Redis stock deltas to Postgres
+    pub stock_flush_interval_secs: u64,
+}
+
+impl Default for JobsConfig {
+    fn default() -> Self {
+        Self { prize_cache_refresh_ms: 800, stock_flush_interval_secs: 5 }
+    }
+}
+
+/// The layers as read from the file; every section may be left out.
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Layers {
+    server: ServerConfig,
+    db: DbConfig,
+    redis: RedisConfig,
+    auth: AuthConfig,
+    draw: DrawConfig,
+    jobs: JobsConfig,
+}
 
 #[derive(Clone)]
 pub struct Config {
-    pub database_url: String,
-    pub jwt_secret: String,
-    pub server_addr: String,
-    pub admin_username: String,
-    pub admin_password: String,
+    pub server: ServerConfig,
+    pub db: DbConfig,
+    pub redis: RedisConfig,
+    pub auth: AuthConfig,
+    pub draw: DrawConfig,
+    pub jobs: JobsConfig,
+    /// token signing and verification keys, built from `auth`
+    pub jwt: JwtKeys,
 }
 
 impl Config {
-    pub fn from_env() -> anyhow::Result<Self> {
-        let database_url = env::var("DATABASE_URL").context("DATABASE_URL not set")?;
-        let jwt_secret = env::var("JWT_SECRET").context("JWT_SECRET not set")?;
-        let server_addr = env::var("SERVER_ADDR").unwrap_or_else(|_| "0.0.0.0:8080".to_string());
-        let admin_username = env::var("ADMIN_USERNAME").unwrap_or_else(|_| "admin".to_string());
-        let admin_password = env::var("ADMIN_PASSWORD").unwrap_or_else(|_| "admin".to_string());
-        Ok(Self {
-            database_url,
-            jwt_secret,
-            server_addr,
-            admin_username,
-            admin_password,
-        })
+    /// Defaults for everything but the database and the token keys.
+    pub fn new(database_url: String, jwt: JwtKeys) -> Self {
+        Self {
+            server: ServerConfig::default(),
+            db: DbConfig { url: database_url, ..DbConfig::default() },
+            redis: RedisConfig::default(),
+            auth: AuthConfig::default(),
+            draw: DrawConfig::default(),
+            jobs: JobsConfig::default(),
+            jwt,
+        }
+    }
+
+    /// Defaults, then the file named by CONFIG_FILE (if set), then the environment; validated.
+    pub fn load() -> anyhow::Result<Self> {
+        let file = env::var_os("CONFIG_FILE").map(PathBuf::from);
+        Self::load_from(file.as_deref())
+    }
+
+    pub fn load_from(file: Option<&Path>) -> anyhow::Result<Self> {
+        let mut layers = match file {
+            Some(path) => read_file(path)?,
+            None => Layers::default(),
+        };
+        apply_env(&mut layers)?;
+        let jwt = jwt_keys(&layers.auth)?;
+        let cfg = Self {
+            server: layers.server,
+            db: layers.db,
+            redis: layers.redis,
+            auth: layers.auth,
+            draw: layers.draw,
+            jobs: layers.jobs,
+            jwt,
+        };
+        cfg.validate()?;
+        Ok(cfg)
+    }
+
+    /// Checks the settings together and reports every problem at once.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let mut problems = Vec::new();
+        if self.server.addr.parse::<SocketAddr>().is_err() {
+            problems.push(format!("server.addr {:?} is not a socket address", self.server.addr));
+        }
+        if self.server.shutdown_timeout_secs == 0 {
+            problems.push("server.shutdown_timeout_secs must be at least 1".to_string());
+        }
+        if self.db.url.is_empty() {
+            problems.push("db.url (DATABASE_URL) not set".to_string());
+        }
+        if self.db.max_connections == 0 {
+            problems.push("db.max_connections must be at least 1".to_string());
+        }
+        if self.draw.backend == DrawBackend::Redis && self.redis.url.is_empty() {
+            problems.push("redis.url (REDIS_URL) must be set for the redis draw backend".to_string());
+        }
+        if self.auth.access_token_ttl_secs == 0 {
+            problems.push("auth.access_token_ttl_secs must be positive".to_string());
+        }
+        if self.auth.refresh_token_ttl_secs <= self.auth.access_token_ttl_secs {
+            problems.push("auth.refresh_token_ttl_secs must be longer than auth.access_token_ttl_secs".to_string());
+        }
+        if self.draw.default_cooldown_secs < 0 {
+            problems.push("draw.default_cooldown_secs must not be negative".to_string());
+        }
+        if self.jobs.prize_cache_refresh_ms < 100 {
+            problems.push("jobs.prize_cache_refresh_ms must be at least 100".to_string());
+        }
+        if self.jobs.stock_flush_interval_secs == 0 {
+            problems.push("jobs.stock_flush_interval_secs must be at least 1".to_string());
+        }
+        if !problems.is_empty() {
+            bail!("invalid configuration:\n  {}", problems.join("\n  "));
+        }
+        Ok(())
+    }
+
+    /// This config with the hot-reloadable knobs of `next`, and the names of changed settings that need a
+    /// restart and were left alone.
+    fn reloaded(&self, next: &Config) -> (Config, Vec<&'static str>) {
+        let mut cfg = self.clone();
+        cfg.auth.access_token_ttl_secs = next.auth.access_token_ttl_secs;
+        cfg.auth.refresh_token_ttl_secs = next.auth.refresh_token_ttl_secs;
+        cfg.draw.default_cooldown_secs = next.draw.default_cooldown_secs;
+        cfg.jobs = next.jobs.clone();
+        let key_settings = |a: &AuthConfig| (a.jwt_secret.clone(), a.private_key_file.clone(), a.key_id.clone(), a.public_keys.clone());
+        let ignored = [
+            ("server", self.server != next.server),
+            ("db", self.db != next.db),
+            ("redis", self.redis != next.redis),
+            ("auth keys", key_settings(&self.auth) != key_settings(&next.auth)),
+            ("draw.backend", self.draw.backend != next.draw.backend),
+        ];
+        (cfg, ignored.into_iter().filter(|(_, changed)| *changed).map(|(name, _)| name).collect())
     }
 }
+
+/// The running configuration, shared by the handlers and background jobs; a reload swaps in a new snapshot.
+#[derive(Clone)]
+pub struct SharedConfig(Arc<RwLock<Arc<Config>>>);
+
+impl SharedConfig {
+    pub fn new(cfg: Config) -> Self {
+        SharedConfig(Arc::new(RwLock::new(Arc::new(cfg))))
+    }
+
+    /// Current snapshot; hold it for one request or one job pass, not longer.
+    pub fn get(&self) -> Arc<Config> {
+        self.0.read().expect("config lock").clone()
+    }
+
+    /// Takes over the hot-reloadable knobs of `next`; returns the changed settings that need a restart.
+    pub fn reload(&self, next: &Config) -> Vec<&'static str> {
+        let mut guard = self.0.write().expect("config lock");
+        let (cfg, ignored) = guard.reloaded(next);
+        *guard = Arc::new(cfg);
+        ignored
+    }
+}
+
+/// Reloads the configuration on every SIGHUP. A config that does not load or validate is logged and the
+/// running one kept.
+#[cfg(unix)]
+pub fn spawn_reload_on_sighup(shared: SharedConfig) -> anyhow::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangups = signal(SignalKind::hangup())?;
+    tokio::spawn(async move {
+        while hangups.recv().await.is_some() {
+            match Config::load() {
+                Ok(next) => {
+                    let ignored = shared.reload(&next);
+                    tracing::info!("configuration reloaded");
+                    if !ignored.is_empty() {
+                        tracing::warn!(settings = ?ignored, "changed settings need a restart and were not applied");
+                    }
+                }
+                Err(e) => tracing::error!(error = %format!("{:#}", e), "configuration reload rejected, keeping the running one"),
+            }
+        }
+    });
+    Ok(())
+}
+
+fn read_file(path: &Path) -> anyhow::Result<Layers> {
+    let text = std::fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+    let layers = match path.extension().and_then(|e| e.to_str()) {
+        Some("toml") => toml::from_str(&text).map_err(anyhow::Error::from),
+        Some("yaml" | "yml") => serde_yaml::from_str(&text).map_err(anyhow::Error::from),
+        _ => bail!("{}: config files must end in .toml, .yaml or .yml", path.display()),
+    };
+    layers.with_context(|| format!("parsing {}", path.display()))
+}
+
+/// Environment overrides; the names predate the config file and keep working without one.
+fn apply_env(l: &mut Layers) -> anyhow::Result<()> {
+    env_override("SERVER_ADDR", &mut l.server.addr)?;
+    env_override("DEFAULT_LOCALE", &mut l.server.default_locale)?;
+    env_override("SHUTDOWN_TIMEOUT_SECS", &mut l.server.shutdown_timeout_secs)?;
+    env_override("DATABASE_URL", &mut l.db.url)?;
+    env_override("DB_MAX_CONNECTIONS", &mut l.db.max_connections)?;
+    env_override("REDIS_URL", &mut l.redis.url)?;
+    if let Ok(v) = env::var("JWT_SECRET") {
+        l.auth.jwt_secret = Some(v);
+    }
+    if let Some(v) = env::var_os("JWT_PRIVATE_KEY_FILE") {
+        l.auth.private_key_file = Some(v.into());
+    }
+    if let Ok(v) = env::var("JWT_KEY_ID") {
+        l.auth.key_id = Some(v);
+    }
+    if let Ok(v) = env::var("JWT_PUBLIC_KEYS") {
+        l.auth.public_keys = v
+            .split(',')
+            .filter(|s| !s.trim().is_empty())
+            .map(|entry| {
+                let (kid, path) = entry.trim().split_once('=').context("JWT_PUBLIC_KEYS entries look like kid=path")?;
+                Ok((kid.to_string(), PathBuf::from(path)))
+            })
+            .collect::<anyhow::Result<_>>()?;
+    }
+    env_override("ACCESS_TOKEN_TTL_SECS", &mut l.auth.access_token_ttl_secs)?;
+    env_override("REFRESH_TOKEN_TTL_SECS", &mut l.auth.refresh_token_ttl_secs)?;
+    env_override("DRAW_BACKEND", &mut l.draw.backend)?;
+    env_override("DRAW_DEFAULT_COOLDOWN_SECS", &mut l.draw.default_cooldown_secs)?;
+    env_override("PRIZE_CACHE_REFRESH_MS", &mut l.jobs.prize_cache_refresh_ms)?;
+    env_override("STOCK_FLUSH_INTERVAL_SECS", &mut l.jobs.stock_flush_interval_secs)?;
+    Ok(())
+}
+
+fn env_override<T: FromStr>(name: &str, slot: &mut T) -> anyhow::Result<()>
+where
+    T::Err: Display,
+{
+    if let Ok(v) = env::var(name) {
+        *slot = v.parse().map_err(|e| anyhow::anyhow!("{}: {}", name, e))?;
+    }
+    Ok(())
+}
+
+fn parse_str<'de, D: Deserializer<'de>, T: FromStr>(d: D) -> Result<T, D::Error>
+where
+    T::Err: Display,
+{
+    String::deserialize(d)?.parse().map_err(serde::de::Error::custom)
+}
+
+/// HS256 with the secret, or, when a private key file is set, RS256/EdDSA signing with that PEM key under
+/// `key_id` and verification against `public_keys`.
+fn jwt_keys(auth: &AuthConfig) -> anyhow::Result<JwtKeys> {
+    let Some(private_path) = &auth.private_key_file else {
+        let secret = auth.jwt_secret.as_deref().context("auth.jwt_secret (JWT_SECRET) not set")?;
+        return Ok(JwtKeys::hs256(secret));
+    };
+    let kid = auth.key_id.as_deref().context("auth.key_id (JWT_KEY_ID) must name the key in the private key file")?;
+    let private_pem = std::fs::read(private_path).with_context(|| format!("reading {}", private_path.display()))?;
+    if auth.public_keys.is_empty() {
+        bail!("auth.public_keys (JWT_PUBLIC_KEYS) not set");
+    }
+    let public = auth
+        .public_keys
+        .iter()
+        .map(|(kid, path)| {
+            let pem = std::fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+            Ok((kid.clone(), pem))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    JwtKeys::asymmetric(kid, &private_pem, &public, auth.jwt_secret.as_deref())
+}
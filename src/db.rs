@@ -1,11 +1,13 @@
 use sqlx::{postgres::PgPoolOptions, Pool, Postgres};
 
+use crate::config::DbConfig;
+
 pub type DbPool = Pool<Postgres>;
 
-pub async fn connect_pool(database_url: &str) -> anyhow::Result<DbPool> {
+pub async fn connect_pool(cfg: &DbConfig) -> anyhow::Result<DbPool> {
     let pool = PgPoolOptions::new()
-        .max_connections(10)
-        .connect(database_url)
+        .max_connections(cfg.max_connections)
+        .connect(&cfg.url)
         .await?;
     Ok(pool)
 }
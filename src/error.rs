@@ -1,6 +1,7 @@
+use std::borrow::Cow;
 
 use axum::{
-    http::StatusCode,
+    http::{header, HeaderValue, StatusCode},
     response::{IntoResponse, Response},
     Json,
 };
@@ -8,50 +9,212 @@ use serde::Serialize;
 use sqlx::Error as SqlxError;
 use thiserror::Error;
 
+use crate::i18n::{self, Locale};
+use crate::validation::FieldError;
+
+/// Machine-readable cause of an error, sent as `reason` next to the coarser `error_code`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Reason {
+    InvalidInput,
+    InvalidIdempotencyKey,
+    InvalidClientSeed,
+    InvalidRegistration,
+    ValidationFailed,
+    /// a row-level CHECK constraint rejected the write
+    ConstraintViolation,
+    ActivityNotStarted,
+    ActivityPaused,
+    ActivityEnded,
+    /// provably fair seeds are revealed only after the activity ended
+    SeedNotRevealed,
+    DrawQuotaExhausted,
+    DailyQuotaExhausted,
+    Cooldown,
+    InsufficientStock,
+    IdempotencyKeyReused,
+    IdempotencyKeyInProgress,
+    UsernameTaken,
+    ActivityOngoing,
+    /// the seed of a provably fair activity may be public, it must not run again
+    FairSeedPublic,
+    /// the change would leave no active superadmin
+    LastSuperadmin,
+    /// the operation only exists with the Redis draw backend
+    RedisBackendDisabled,
+    /// a unique constraint rejected the write
+    Duplicate,
+    /// a foreign key points at a row that does not exist
+    ReferenceMissing,
+    Unauthorized,
+    Forbidden,
+    NotFound,
+    Internal,
+}
+
+impl Reason {
+    /// Every reason, for checks that have to cover all of them.
+    pub const ALL: [Reason; 27] = [
+        Reason::InvalidInput, Reason::InvalidIdempotencyKey, Reason::InvalidClientSeed, Reason::InvalidRegistration,
+        Reason::ValidationFailed, Reason::ConstraintViolation, Reason::ActivityNotStarted, Reason::ActivityPaused,
+        Reason::ActivityEnded, Reason::SeedNotRevealed, Reason::DrawQuotaExhausted, Reason::DailyQuotaExhausted,
+        Reason::Cooldown, Reason::InsufficientStock, Reason::IdempotencyKeyReused, Reason::IdempotencyKeyInProgress,
+        Reason::UsernameTaken, Reason::ActivityOngoing, Reason::FairSeedPublic, Reason::LastSuperadmin,
+        Reason::RedisBackendDisabled, Reason::Duplicate, Reason::ReferenceMissing, Reason::Unauthorized,
+        Reason::Forbidden, Reason::NotFound, Reason::Internal,
+    ];
+
+    /// The snake_case name sent to clients; matches the serde rename.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Reason::InvalidInput => "invalid_input",
+            Reason::InvalidIdempotencyKey => "invalid_idempotency_key",
+            Reason::InvalidClientSeed => "invalid_client_seed",
+            Reason::InvalidRegistration => "invalid_registration",
+            Reason::ValidationFailed => "validation_failed",
+            Reason::ConstraintViolation => "constraint_violation",
+            Reason::ActivityNotStarted => "activity_not_started",
+            Reason::ActivityPaused => "activity_paused",
+            Reason::ActivityEnded => "activity_ended",
+            Reason::SeedNotRevealed => "seed_not_revealed",
+            Reason::DrawQuotaExhausted => "draw_quota_exhausted",
+            Reason::DailyQuotaExhausted => "daily_quota_exhausted",
+            Reason::Cooldown => "cooldown",
+            Reason::InsufficientStock => "insufficient_stock",
+            Reason::IdempotencyKeyReused => "idempotency_key_reused",
+            Reason::IdempotencyKeyInProgress => "idempotency_key_in_progress",
+            Reason::UsernameTaken => "username_taken",
+            Reason::ActivityOngoing => "activity_ongoing",
+            Reason::FairSeedPublic => "fair_seed_public",
+            Reason::LastSuperadmin => "last_superadmin",
+            Reason::RedisBackendDisabled => "redis_backend_disabled",
+            Reason::Duplicate => "duplicate",
+            Reason::ReferenceMissing => "reference_missing",
+            Reason::Unauthorized => "unauthorized",
+            Reason::Forbidden => "forbidden",
+            Reason::NotFound => "not_found",
+            Reason::Internal => "internal",
+        }
+    }
+}
+
 #[derive(Debug, Error)]
 pub enum AppError {
-    #[error("bad request: {0}")]
-    BadRequest(&'static str),
+    /// rejected request; `details` ends up in `ErrorBody.data`
+    #[error("bad request ({reason:?}): {message}")]
+    BadRequest { reason: Reason, message: Cow<'static, str>, details: Option<serde_json::Value> },
+    /// request fields that failed validation
+    #[error("validation failed: {0:?}")]
+    Validation(Vec<FieldError>),
+    /// draw rejected by the activity cooldown; carries the seconds left (also sent as Retry-After)
+    #[error("too frequent, retry after {0}s")]
+    TooFrequent(i64),
     #[error("unauthorized")]
     Unauthorized,
     #[error("forbidden")]
     Forbidden,
     #[error("not found")]
     NotFound,
+    #[error("conflict ({reason:?}): {message}")]
+    Conflict { reason: Reason, message: Cow<'static, str>, details: Option<serde_json::Value> },
+    /// well-formed request referring to something that does not exist (422)
+    #[error("unprocessable ({reason:?}): {message}")]
+    Unprocessable { reason: Reason, message: Cow<'static, str>, details: Option<serde_json::Value> },
     #[error("internal error: {0}")]
     Internal(&'static str),
     #[error(transparent)]
+    Database(SqlxError),
+    #[error(transparent)]
     Anyhow(#[from] anyhow::Error),
 }
 
+impl AppError {
+    pub fn bad_request(reason: Reason, message: impl Into<Cow<'static, str>>) -> Self {
+        AppError::BadRequest { reason, message: message.into(), details: None }
+    }
+
+    /// Generic 400 for malformed input; prefer a specific reason so the message can be localized.
+    pub fn invalid(message: impl Into<Cow<'static, str>>) -> Self {
+        Self::bad_request(Reason::InvalidInput, message)
+    }
+
+    pub fn conflict(reason: Reason, message: impl Into<Cow<'static, str>>) -> Self {
+        AppError::Conflict { reason, message: message.into(), details: None }
+    }
+
+    /// Attaches details to the variants that carry them; a no-op for the others.
+    pub fn with_details(mut self, value: serde_json::Value) -> Self {
+        if let AppError::BadRequest { details, .. } | AppError::Conflict { details, .. } | AppError::Unprocessable { details, .. } = &mut self {
+            *details = Some(value);
+        }
+        self
+    }
+
+    pub fn reason(&self) -> Reason {
+        match self {
+            AppError::BadRequest { reason, .. } | AppError::Conflict { reason, .. } | AppError::Unprocessable { reason, .. } => *reason,
+            AppError::Validation(_) => Reason::ValidationFailed,
+            AppError::TooFrequent(_) => Reason::Cooldown,
+            AppError::Unauthorized => Reason::Unauthorized,
+            AppError::Forbidden => Reason::Forbidden,
+            AppError::NotFound => Reason::NotFound,
+            AppError::Internal(_) | AppError::Database(_) | AppError::Anyhow(_) => Reason::Internal,
+        }
+    }
+}
+
 #[derive(Serialize)]
 struct ErrorBody<'a> {
     code: u16,
     error_code: &'a str,
+    reason: Reason,
     message: &'a str,
-    data: Option<()>,
+    data: Option<serde_json::Value>,
+}
+
+/// Message of an error in `locale`: the catalog entry of its code and reason, else the error's own message
+/// (written in zh-CN) or the generic entry of its code.
+fn localized_message(locale: Locale, error_code: &str, reason: Reason, own: Option<&str>, params: Option<&serde_json::Value>) -> String {
+    if let Some(template) = i18n::lookup(locale, &format!("{}.{}", error_code, reason.as_str())) {
+        return i18n::render(template, params);
+    }
+    match own {
+        Some(m) if locale == Locale::ZhCn => m.to_string(),
+        _ => i18n::lookup(locale, error_code).unwrap_or_default().to_string(),
+    }
 }
 
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
-        let (status, error_code, msg) = match self {
-            AppError::BadRequest(m) => (StatusCode::BAD_REQUEST, "00-01-00", m),
-            AppError::Unauthorized => (StatusCode::UNAUTHORIZED, "01-01-00", "未授权"),
-            AppError::Forbidden => (StatusCode::FORBIDDEN, "01-02-00", "禁止访问"),
-            AppError::NotFound => (StatusCode::NOT_FOUND, "04-04-00", "未找到"),
-            AppError::Internal(_) | AppError::Anyhow(_) => (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "05-00-00",
-                "服务器内部错误",
-            ),
+        let reason = self.reason();
+        let mut retry_after = None;
+        let (status, error_code, own, data) = match self {
+            AppError::BadRequest { message, details, .. } => (StatusCode::BAD_REQUEST, "00-01-00", Some(message), details),
+            AppError::Validation(fields) => (StatusCode::BAD_REQUEST, "00-01-00", None, Some(serde_json::json!({ "fields": fields }))),
+            AppError::TooFrequent(secs) => {
+                retry_after = Some(secs);
+                (StatusCode::BAD_REQUEST, "00-01-00", None, Some(serde_json::json!({ "retry_after_secs": secs })))
+            }
+            AppError::Unauthorized => (StatusCode::UNAUTHORIZED, "01-01-00", None, None),
+            AppError::Forbidden => (StatusCode::FORBIDDEN, "01-02-00", None, None),
+            AppError::NotFound => (StatusCode::NOT_FOUND, "04-04-00", None, None),
+            AppError::Conflict { message, details, .. } => (StatusCode::CONFLICT, "04-09-00", Some(message), details),
+            AppError::Unprocessable { message, details, .. } => (StatusCode::UNPROCESSABLE_ENTITY, "04-22-00", Some(message), details),
+            AppError::Internal(_) | AppError::Database(_) | AppError::Anyhow(_) => (StatusCode::INTERNAL_SERVER_ERROR, "05-00-00", None, None),
         };
+        let msg = localized_message(i18n::current(), error_code, reason, own.as_deref(), data.as_ref());
         let body = Json(ErrorBody {
             code: status.as_u16(),
             error_code,
-            message: msg,
-            data: None,
+            reason,
+            message: &msg,
+            data,
         });
-        (status, body).into_response()
+        let mut resp = (status, body).into_response();
+        if let Some(secs) = retry_after {
+            resp.headers_mut().insert(header::RETRY_AFTER, HeaderValue::from(secs.max(1)));
+        }
+        resp
     }
 }
 
@@ -59,7 +222,29 @@ pub type AppResult<T> = Result<T, AppError>;
 
 impl From<SqlxError> for AppError {
     fn from(value: SqlxError) -> Self {
+        if let SqlxError::RowNotFound = value {
+            return AppError::NotFound;
+        }
+        if let Some(db) = value.as_database_error() {
+            let details = db.constraint().map(|c| serde_json::json!({ "constraint": c }));
+            let mapped = match db.kind() {
+                sqlx::error::ErrorKind::UniqueViolation => {
+                    Some(AppError::Conflict { reason: Reason::Duplicate, message: "数据已存在".into(), details })
+                }
+                sqlx::error::ErrorKind::ForeignKeyViolation => {
+                    Some(AppError::Unprocessable { reason: Reason::ReferenceMissing, message: "关联数据不存在".into(), details })
+                }
+                sqlx::error::ErrorKind::CheckViolation => {
+                    Some(AppError::BadRequest { reason: Reason::ConstraintViolation, message: "数据不满足约束".into(), details })
+                }
+                _ => None,
+            };
+            if let Some(e) = mapped {
+                tracing::warn!(error = %db, "database constraint rejected the request");
+                return e;
+            }
+        }
         tracing::error!(error = ?value, "sqlx error");
-        AppError::Internal("database error")
+        AppError::Database(value)
     }
 }
@@ -0,0 +1,155 @@
+use axum::{
+    extract::{Request, State},
+    http::header,
+    middleware::Next,
+    response::Response,
+};
+
+// Error messages in the client's language. The catalog is keyed by the stable `error_code` ("00-01-00") for
+// the generic message and by "{error_code}.{reason}" for a specific one; field-level validation messages use
+// "field.{code}". `{name}` placeholders are filled from the error details. The locale comes from
+// Accept-Language, falling back to the configured default.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Locale {
+    #[default]
+    ZhCn,
+    EnUs,
+}
+
+impl Locale {
+    /// Matches a language tag ("en", "en-GB", "zh-Hans-CN", ...) to a supported locale.
+    pub fn from_tag(tag: &str) -> Option<Self> {
+        let primary = tag.trim().split(['-', '_']).next()?.to_ascii_lowercase();
+        match primary.as_str() {
+            "zh" => Some(Locale::ZhCn),
+            "en" => Some(Locale::EnUs),
+            _ => None,
+        }
+    }
+
+    /// Best supported locale of an Accept-Language header by q-value; None if nothing matches.
+    pub fn from_accept_language(header: &str) -> Option<Self> {
+        let mut best: Option<(f32, Self)> = None;
+        for part in header.split(',') {
+            let mut it = part.split(';');
+            let Some(locale) = it.next().and_then(Self::from_tag) else { continue };
+            let q = it
+                .find_map(|p| p.trim().strip_prefix("q="))
+                .and_then(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            // the first tag wins among equal q-values
+            if q > 0.0 && best.is_none_or(|(bq, _)| q > bq) {
+                best = Some((q, locale));
+            }
+        }
+        best.map(|(_, l)| l)
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Locale::ZhCn => "zh-CN",
+            Locale::EnUs => "en-US",
+        }
+    }
+}
+
+impl std::str::FromStr for Locale {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::from_tag(s).ok_or_else(|| anyhow::anyhow!("unsupported locale {}", s))
+    }
+}
+
+tokio::task_local! {
+    static LOCALE: Locale;
+}
+
+/// Locale of the request being handled; the default outside of `negotiate`.
+pub fn current() -> Locale {
+    LOCALE.try_with(|l| *l).unwrap_or_default()
+}
+
+/// Middleware: runs the rest of the request with the locale picked from Accept-Language.
+pub async fn negotiate(State(default): State<Locale>, req: Request, next: Next) -> Response {
+    let locale = req
+        .headers()
+        .get(header::ACCEPT_LANGUAGE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(Locale::from_accept_language)
+        .unwrap_or(default);
+    let mut resp = LOCALE.scope(locale, next.run(req)).await;
+    resp.headers_mut().insert(header::CONTENT_LANGUAGE, header::HeaderValue::from_static(locale.as_str()));
+    resp
+}
+
+// key, zh-CN, en-US
+static CATALOG: &[(&str, &str, &str)] = &[
+    ("00-01-00", "请求参数错误", "Bad request"),
+    ("00-01-00.validation_failed", "参数校验失败", "Validation failed"),
+    ("00-01-00.constraint_violation", "数据不满足约束", "The data violates a constraint"),
+    ("00-01-00.cooldown", "抽奖频率过高，请{retry_after_secs}秒后再试", "Too many draws, retry in {retry_after_secs}s"),
+    ("00-01-00.activity_not_started", "活动尚未开始", "The activity has not started yet"),
+    ("00-01-00.activity_paused", "活动已暂停", "The activity is paused"),
+    ("00-01-00.activity_ended", "活动已结束", "The activity has ended"),
+    ("00-01-00.seed_not_revealed", "活动结束后才能验证", "Draws can be verified once the activity has ended"),
+    ("00-01-00.draw_quota_exhausted", "抽奖次数已用完", "No draws left"),
+    ("00-01-00.daily_quota_exhausted", "今日抽奖次数已用完", "No draws left today"),
+    ("00-01-00.insufficient_stock", "库存不足，无法扣减", "Not enough stock to take back"),
+    ("00-01-00.idempotency_key_reused", "Idempotency-Key 已用于其他请求", "The Idempotency-Key was used for another request"),
+    ("00-01-00.invalid_idempotency_key", "Idempotency-Key 不合法", "Invalid Idempotency-Key"),
+    ("00-01-00.invalid_client_seed", "client_seed 不合法", "Invalid client_seed"),
+    ("00-01-00.invalid_registration", "用户名或密码不合法", "Invalid username or password"),
+    ("01-01-00", "未授权", "Unauthorized"),
+    ("01-02-00", "禁止访问", "Forbidden"),
+    ("04-04-00", "未找到", "Not found"),
+    ("04-09-00", "请求冲突", "Conflict"),
+    ("04-09-00.duplicate", "数据已存在", "Already exists"),
+    ("04-09-00.username_taken", "用户名已存在", "Username already taken"),
+    ("04-09-00.activity_ongoing", "活动进行中，请先暂停或结束", "The activity is ongoing, pause or end it first"),
+    ("04-09-00.fair_seed_public", "公平抽奖活动已结束并公开种子，不能再修改状态或时间", "The seed of this provably fair activity is public, its status and times can no longer change"),
+    ("04-09-00.last_superadmin", "至少需要保留一个超级管理员", "At least one active superadmin must remain"),
+    ("04-09-00.redis_backend_disabled", "未启用 Redis 抽奖后端", "The Redis draw backend is not enabled"),
+    ("04-09-00.idempotency_key_in_progress", "相同 Idempotency-Key 的请求正在处理中", "A request with the same Idempotency-Key is in progress"),
+    ("04-22-00", "请求无法处理", "Unprocessable request"),
+    ("04-22-00.reference_missing", "关联数据不存在", "A referenced record does not exist"),
+    ("05-00-00", "服务器内部错误", "Internal server error"),
+    ("field.name_empty", "名称不能为空", "Name must not be empty"),
+    ("field.end_before_start", "结束时间必须晚于开始时间", "End time must be after start time"),
+    ("field.negative_cooldown", "冷却时间不能为负数", "Cooldown must not be negative"),
+    ("field.negative_quota", "抽奖次数限制不能为负数", "Draw limits must not be negative"),
+    ("field.invalid_timezone", "时区不合法", "Invalid timezone"),
+    ("field.negative_no_win_weight", "未中奖权重不能为负数", "No-win weight must not be negative"),
+    ("field.invalid_pity_rule", "保底规则不合法", "Invalid pity rule"),
+    ("field.duplicate_pity_tier", "保底规则等级重复", "Duplicate pity rule tier"),
+    ("field.negative_probability", "中奖概率不能为负数", "Probability must not be negative"),
+    ("field.invalid_tier", "奖品等级不合法", "Invalid prize tier"),
+    ("field.probability_over_percent", "中奖概率总和不能超过100%", "Probabilities must not add up to more than 100%"),
+    ("field.probability_over_basis_points", "中奖概率总和不能超过10000个基点", "Probabilities must not add up to more than 10000 basis points"),
+    ("field.negative_total_count", "奖品数量不能为负数", "Prize count must not be negative"),
+    ("field.activity_not_found", "活动不存在", "Activity not found"),
+    ("field.password_too_short", "密码至少8位", "Password must be at least 8 characters"),
+];
+
+pub fn lookup(locale: Locale, key: &str) -> Option<&'static str> {
+    CATALOG.iter().find(|(k, _, _)| *k == key).map(|(_, zh, en)| match locale {
+        Locale::ZhCn => *zh,
+        Locale::EnUs => *en,
+    })
+}
+
+/// Fills `{name}` placeholders from a JSON object; unknown names are left as they are.
+pub fn render(template: &str, params: Option<&serde_json::Value>) -> String {
+    let mut out = template.to_string();
+    if let Some(serde_json::Value::Object(map)) = params {
+        for (k, v) in map {
+            let value = match v {
+                serde_json::Value::String(s) => s.clone(),
+                other => other.to_string(),
+            };
+            out = out.replace(&format!("{{{}}}", k), &value);
+        }
+    }
+    out
+}
@@ -0,0 +1,134 @@
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, Context};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey};
+use simple_asn1::ASN1Block;
+
+// Token signing keys. HS256 signs and verifies with one shared secret. RS256 and EdDSA sign with a private key
+// and verify by the `kid` header against a set of public keys, which can hold several keys at once while one
+// is rotated out; the public keys are published as a JWKS so other services verify tokens without any secret.
+// During a move from HS256 to a key pair the old secret can stay configured to verify tokens without `kid`.
+
+const OID_RSA: [u64; 7] = [1, 2, 840, 113549, 1, 1, 1];
+const OID_ED25519: [u64; 4] = [1, 3, 101, 112];
+
+pub struct SigningKey {
+    pub kid: Option<String>,
+    pub alg: Algorithm,
+    pub key: EncodingKey,
+}
+
+pub struct VerifyingKey {
+    pub kid: String,
+    pub alg: Algorithm,
+    pub key: DecodingKey,
+    /// public JWK, as served by the JWKS endpoint
+    pub jwk: serde_json::Value,
+}
+
+pub struct KeySet {
+    pub signing: SigningKey,
+    pub verifying: Vec<VerifyingKey>,
+    /// HS256 secret for tokens without `kid`
+    pub legacy: Option<DecodingKey>,
+}
+
+/// Cheap to clone; shared by every copy of the config.
+#[derive(Clone)]
+pub struct JwtKeys(Arc<KeySet>);
+
+impl JwtKeys {
+    pub fn hs256(secret: &str) -> Self {
+        JwtKeys(Arc::new(KeySet {
+            signing: SigningKey { kid: None, alg: Algorithm::HS256, key: EncodingKey::from_secret(secret.as_bytes()) },
+            verifying: Vec::new(),
+            legacy: Some(DecodingKey::from_secret(secret.as_bytes())),
+        }))
+    }
+
+    /// Signs with the PEM private key `private_pem` under `kid` (RS256 or EdDSA); verifies against the PEM
+    /// public keys `public`, which must include the one of `kid`. `legacy_secret` keeps HS256 tokens valid.
+    pub fn asymmetric(kid: &str, private_pem: &[u8], public: &[(String, Vec<u8>)], legacy_secret: Option<&str>) -> anyhow::Result<Self> {
+        let verifying = public
+            .iter()
+            .map(|(kid, pem)| verifying_key(kid, pem).with_context(|| format!("public key {}", kid)))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let alg = verifying
+            .iter()
+            .find(|k| k.kid == kid)
+            .map(|k| k.alg)
+            .ok_or_else(|| anyhow!("no public key for the signing key {}", kid))?;
+        let key = match alg {
+            Algorithm::RS256 => EncodingKey::from_rsa_pem(private_pem),
+            _ => EncodingKey::from_ed_pem(private_pem),
+        }
+        .context("signing key must be a PEM private key matching its public key type")?;
+        Ok(JwtKeys(Arc::new(KeySet {
+            signing: SigningKey { kid: Some(kid.to_string()), alg, key },
+            verifying,
+            legacy: legacy_secret.map(|s| DecodingKey::from_secret(s.as_bytes())),
+        })))
+    }
+
+    pub fn signing(&self) -> &SigningKey {
+        &self.0.signing
+    }
+
+    /// Key and algorithm to verify a token with the given `kid` header.
+    pub fn verifying(&self, kid: Option<&str>) -> Option<(&DecodingKey, Algorithm)> {
+        match kid {
+            Some(kid) => self.0.verifying.iter().find(|k| k.kid == kid).map(|k| (&k.key, k.alg)),
+            None => self.0.legacy.as_ref().map(|k| (k, Algorithm::HS256)),
+        }
+    }
+
+    /// The public keys as a JWK Set; empty with HS256 only.
+    pub fn jwks(&self) -> serde_json::Value {
+        serde_json::json!({ "keys": self.0.verifying.iter().map(|k| &k.jwk).collect::<Vec<_>>() })
+    }
+}
+
+/// Reads a SubjectPublicKeyInfo PEM ("PUBLIC KEY") of an RSA or Ed25519 key.
+fn verifying_key(kid: &str, pem_bytes: &[u8]) -> anyhow::Result<VerifyingKey> {
+    let pem = pem::parse(pem_bytes)?;
+    if pem.tag() != "PUBLIC KEY" {
+        bail!("expected a PUBLIC KEY PEM, got {}", pem.tag());
+    }
+    let blocks = simple_asn1::from_der(pem.contents())?;
+    let (oid, key_bits) = match blocks.first() {
+        Some(ASN1Block::Sequence(_, spki)) => match spki.as_slice() {
+            [ASN1Block::Sequence(_, alg), ASN1Block::BitString(_, _, bits)] => match alg.first() {
+                Some(ASN1Block::ObjectIdentifier(_, oid)) => (oid.as_vec::<u64>()?, bits),
+                _ => bail!("malformed algorithm identifier"),
+            },
+            _ => bail!("malformed public key"),
+        },
+        _ => bail!("malformed public key"),
+    };
+    let b64 = |bytes: &[u8]| URL_SAFE_NO_PAD.encode(bytes);
+    if oid == OID_RSA {
+        let (n, e) = match simple_asn1::from_der(key_bits)?.first() {
+            Some(ASN1Block::Sequence(_, ints)) => match ints.as_slice() {
+                [ASN1Block::Integer(_, n), ASN1Block::Integer(_, e)] => (n.to_bytes_be().1, e.to_bytes_be().1),
+                _ => bail!("malformed RSA public key"),
+            },
+            _ => bail!("malformed RSA public key"),
+        };
+        Ok(VerifyingKey {
+            kid: kid.to_string(),
+            alg: Algorithm::RS256,
+            key: DecodingKey::from_rsa_raw_components(&n, &e),
+            jwk: serde_json::json!({ "kty": "RSA", "use": "sig", "alg": "RS256", "kid": kid, "n": b64(&n), "e": b64(&e) }),
+        })
+    } else if oid == OID_ED25519 {
+        Ok(VerifyingKey {
+            kid: kid.to_string(),
+            alg: Algorithm::EdDSA,
+            key: DecodingKey::from_ed_der(key_bits),
+            jwk: serde_json::json!({ "kty": "OKP", "use": "sig", "alg": "EdDSA", "crv": "Ed25519", "kid": kid, "x": b64(key_bits) }),
+        })
+    } else {
+        bail!("unsupported key type, use RSA or Ed25519")
+    }
+}
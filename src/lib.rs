@@ -1,9 +1,14 @@
 pub mod models;
 pub mod services;
 pub mod error;
+pub mod i18n;
 pub mod config;
 pub mod routes;
+pub mod state;
+pub mod metrics;
 pub mod auth;
+pub mod jwt_keys;
 pub mod db;
 pub mod redis_client;
 pub mod redis_scripts;
+pub mod validation;
@@ -1,9 +1,7 @@
 use std::net::SocketAddr;
+use std::time::Duration;
 
-use axum::{
-    routing::{get, post},
-    Router,
-};
+use axum::Router;
 use dotenvy::dotenv;
 use tower_http::{
     cors::{Any, CorsLayer},
@@ -11,24 +9,18 @@ use tower_http::{
 };
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
-mod auth;
-mod config;
-mod db;
-mod redis_scripts;
-mod redis_client;
-mod error;
-mod models;
-mod routes;
-mod services;
-
-use crate::{
-    config::Config,
+use fast_lottery_engine::{
+    config::{self, Config, DrawBackend},
     db::connect_pool,
-    routes::{admin_routes, auth_routes, lottery_routes, user_routes},
+    routes::{admin_routes, auth_routes, lottery_routes, ops_routes, user_routes},
+    state::StateData,
 };
+use std::future::IntoFuture;
 use std::sync::Arc;
-use crate::services::stock_sync::spawn_redis_delta_flusher;
-use crate::services::prize_cache;
+
+use tokio_util::sync::CancellationToken;
+use fast_lottery_engine::services::stock_sync::{self, spawn_redis_delta_flusher};
+use fast_lottery_engine::services::{feed_service, record_outbox};
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
@@ -40,18 +32,26 @@ async fn main() -> anyhow::Result<()> {
         .with(tracing_subscriber::fmt::layer())
         .init();
 
-    let cfg = Config::from_env()?;
-    let pool = connect_pool(&cfg.database_url).await?;
-    let redis_url = std::env::var("REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1:6379".to_string());
-    let redis_mgr = crate::redis_client::connect_manager(&redis_url).await?;
-    let redis = Arc::new(redis_mgr);
+    let cfg = Config::load()?;
+    let pool = connect_pool(&cfg.db).await?;
+    let state = StateData::connect(pool.clone(), cfg.clone()).await?;
+    tracing::info!(backend = ?cfg.draw.backend, "draw backend selected");
+    #[cfg(unix)]
+    config::spawn_reload_on_sighup(state.cfg.clone())?;
+    // seed/repair Redis stock from Postgres before serving draws
+    if let Some(redis) = &state.redis {
+        match stock_sync::reconcile_stock(&pool, &mut redis.clone(), &state.metrics, None, true).await {
+            Ok(report) => tracing::info!(prizes = report.len(), "redis stock reconciled"),
+            Err(e) => tracing::error!(error = ?e, "redis stock reconciliation failed"),
+        }
+    }
 
     let api = Router::new()
-        .merge(auth_routes(&pool, &cfg))
-        .merge(user_routes(&pool, &cfg))
-        .merge(lottery_routes(&pool, &cfg))
-        .merge(admin_routes(&pool, &cfg))
-        .route("/healthz", get(|| async { "ok" }));
+        .merge(auth_routes(&state))
+        .merge(user_routes(&state))
+        .merge(lottery_routes(&state))
+        .merge(admin_routes(&state))
+        .merge(ops_routes(&state));
 
     let app = Router::new()
         .nest("/", api)
@@ -63,14 +63,68 @@ async fn main() -> anyhow::Result<()> {
         )
         .layer(TraceLayer::new_for_http());
 
-    let addr: SocketAddr = cfg.server_addr.parse()?;
+    let addr: SocketAddr = cfg.server.addr.parse()?;
     tracing::info!(%addr, "server starting");
-    // spawn background flusher for Redis deltas to DB
-    spawn_redis_delta_flusher(pool.clone(), redis.clone());
+    if let (DrawBackend::Redis, Some(redis)) = (cfg.draw.backend, &state.redis) {
+        // spawn background flusher for Redis deltas to DB
+        spawn_redis_delta_flusher(&state.jobs, pool.clone(), Arc::new(redis.clone()), state.cfg.clone(), state.metrics.clone());
+        // spawn outbox consumer persisting draw records (own connection: it blocks on XREADGROUP)
+        let outbox_redis = fast_lottery_engine::redis_client::connect_manager(&cfg.redis.url).await?;
+        record_outbox::spawn_outbox_consumer(&state.jobs, pool.clone(), outbox_redis, state.metrics.clone(), state.feed.clone());
+        // relay the live feed of every instance to this one's stream clients
+        feed_service::spawn_redis_relay(&state.jobs, cfg.redis.url.clone(), state.feed.clone());
+    }
     // spawn prize cache refresher to avoid DB read per draw
-    prize_cache::spawn_refresh(pool.clone());
+    state.prize_cache.spawn_refresh(&state.jobs, pool.clone(), state.cfg.clone(), state.metrics.clone());
     let listener = tokio::net::TcpListener::bind(addr).await?;
-    axum::serve(listener, app).await?;
+    let stop = CancellationToken::new();
+    let mut server = tokio::spawn(axum::serve(listener, app).with_graceful_shutdown(stop.clone().cancelled_owned()).into_future());
+    tokio::select! {
+        res = &mut server => return Ok(res??),
+        _ = shutdown_signal() => {}
+    }
 
+    // stop accepting, let in-flight draws finish, then the final flush and outbox drain; all within one deadline
+    let timeout = Duration::from_secs(state.cfg.get().server.shutdown_timeout_secs);
+    let deadline = tokio::time::Instant::now() + timeout;
+    tracing::info!(?timeout, "shutting down");
+    stop.cancel();
+    // live streams never end on their own
+    state.feed.close();
+    match tokio::time::timeout_at(deadline, server).await {
+        Ok(res) => res??,
+        Err(_) => tracing::warn!("in-flight requests did not finish in time"),
+    }
+    if !state.jobs.shutdown(deadline.saturating_duration_since(tokio::time::Instant::now())).await {
+        tracing::warn!("background jobs did not finish in time; unflushed deltas and records stay in redis");
+    }
+    tracing::info!("shutdown complete");
     Ok(())
 }
+
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            tracing::error!(error = ?e, "failed to listen for ctrl-c");
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut sig) => {
+                sig.recv().await;
+            }
+            Err(e) => {
+                tracing::error!(error = ?e, "failed to listen for SIGTERM");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+}
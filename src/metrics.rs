@@ -0,0 +1,169 @@
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use prometheus::{
+    Encoder, Gauge, HistogramOpts, HistogramTimer, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+
+use crate::error::AppError;
+use crate::services::lottery_service::DrawResult;
+use crate::services::record_outbox::OutboxStats;
+use crate::state::StateData;
+
+// Prometheus metrics of the draw engine. Hot paths update counters and histograms in place; values that depend
+// on the time of the scrape (cache age, flusher lag, pool usage, outbox stats) are filled in by `render`.
+
+/// Latency buckets in seconds, from a Lua script round trip to a slow Postgres draw.
+const LATENCY_BUCKETS: &[f64] = &[0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5];
+
+/// Process-wide counters, owned by the app state and updated by handlers and background tasks.
+pub struct Metrics {
+    pub outbox: OutboxStats,
+    /// unix time the process started
+    started_at: f64,
+    registry: Registry,
+    /// by backend (redis, sql) and outcome (won, lost, sold_out, cooldown, rejected, error)
+    draws: IntCounterVec,
+    draw_duration: HistogramVec,
+    script_duration: HistogramVec,
+    prize_stock: IntGaugeVec,
+    sold_delta_backlog: IntGauge,
+    stock_flush_last_success: Gauge,
+    stock_flush_lag: Gauge,
+    stock_oversold: IntCounter,
+    prize_cache_last_refresh: Gauge,
+    prize_cache_age: Gauge,
+    db_pool_connections: IntGaugeVec,
+    db_pool_max_connections: IntGauge,
+    outbox_backlog: IntGauge,
+    outbox_pending: IntGauge,
+    outbox_persisted: IntCounter,
+    outbox_failed_batches: IntCounter,
+    outbox_dead_lettered: IntCounter,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        let registry = Registry::new_custom(Some("lottery".to_string()), None).expect("metrics registry");
+        fn register<M: prometheus::core::Collector + Clone + 'static>(registry: &Registry, metric: M) -> M {
+            registry.register(Box::new(metric.clone())).expect("metric names are unique");
+            metric
+        }
+        let histogram = |name: &str, help: &str, labels: &[&str]| {
+            register(&registry, HistogramVec::new(HistogramOpts::new(name, help).buckets(LATENCY_BUCKETS.to_vec()), labels).unwrap())
+        };
+        let gauge = |name: &str, help: &str| register(&registry, Gauge::new(name, help).unwrap());
+        let int_gauge = |name: &str, help: &str| register(&registry, IntGauge::new(name, help).unwrap());
+        let counter = |name: &str, help: &str| register(&registry, IntCounter::new(name, help).unwrap());
+        Metrics {
+            outbox: OutboxStats::default(),
+            started_at: unix_now(),
+            draws: register(&registry, IntCounterVec::new(Opts::new("draws_total", "Draws by backend and outcome"), &["backend", "outcome"]).unwrap()),
+            draw_duration: histogram("draw_duration_seconds", "Draw latency by backend", &["backend"]),
+            script_duration: histogram("redis_script_duration_seconds", "Lua script latency by script", &["script"]),
+            prize_stock: register(&registry, IntGaugeVec::new(Opts::new("prize_stock", "Redis stock of enabled prizes"), &["prize_id"]).unwrap()),
+            sold_delta_backlog: int_gauge("sold_delta_backlog", "Units sold in Redis and not yet flushed to Postgres"),
+            stock_flush_last_success: gauge("stock_flush_last_success_timestamp_seconds", "End of the last flush pass without failures"),
+            stock_flush_lag: gauge("stock_flush_lag_seconds", "Seconds since the last flush pass without failures"),
+            stock_oversold: counter("stock_oversold_units_total", "Sold units a flush could not take from remaining_count"),
+            prize_cache_last_refresh: gauge("prize_cache_last_refresh_timestamp_seconds", "Last full prize cache refresh"),
+            prize_cache_age: gauge("prize_cache_age_seconds", "Seconds since the last full prize cache refresh"),
+            db_pool_connections: register(&registry, IntGaugeVec::new(Opts::new("db_pool_connections", "Postgres pool connections by state"), &["state"]).unwrap()),
+            db_pool_max_connections: int_gauge("db_pool_max_connections", "Postgres pool size limit"),
+            outbox_backlog: int_gauge("outbox_backlog", "Draw records written by draws and not yet in Postgres"),
+            outbox_pending: int_gauge("outbox_pending", "Draw records delivered to a consumer and awaiting ack"),
+            outbox_persisted: counter("outbox_persisted_total", "Draw records persisted to Postgres"),
+            outbox_failed_batches: counter("outbox_failed_batches_total", "Outbox batches that failed to persist"),
+            outbox_dead_lettered: counter("outbox_dead_lettered_total", "Draw records moved to the dead letter stream"),
+            registry,
+        }
+    }
+}
+
+fn unix_now() -> f64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs_f64()).unwrap_or_default()
+}
+
+/// Raises a counter to a total kept elsewhere.
+fn sync_counter(counter: &IntCounter, total: u64) {
+    counter.inc_by(total.saturating_sub(counter.get()));
+}
+
+impl Metrics {
+    /// Counts a finished draw attempt and its latency.
+    pub fn observe_draw(&self, backend: &str, started: Instant, res: &Result<DrawResult, AppError>) {
+        let outcome = match res {
+            Ok(r) if r.won => "won",
+            Ok(r) if r.sold_out => "sold_out",
+            Ok(_) => "lost",
+            Err(AppError::TooFrequent(_)) => "cooldown",
+            Err(AppError::Internal(_) | AppError::Database(_) | AppError::Anyhow(_)) => "error",
+            Err(_) => "rejected",
+        };
+        self.draws.with_label_values(&[backend, outcome]).inc();
+        self.draw_duration.with_label_values(&[backend]).observe(started.elapsed().as_secs_f64());
+    }
+
+    /// Times one Lua script call until the timer is dropped.
+    pub fn script_timer(&self, script: &str) -> HistogramTimer {
+        self.script_duration.with_label_values(&[script]).start_timer()
+    }
+
+    /// Replaces the per-prize stock gauges, so deleted prizes disappear.
+    pub fn set_prize_stock(&self, stock: &[(String, i64)], sold_backlog: i64) {
+        self.prize_stock.reset();
+        for (prize_id, n) in stock {
+            self.prize_stock.with_label_values(&[prize_id]).set(*n);
+        }
+        self.sold_delta_backlog.set(sold_backlog);
+    }
+
+    pub fn stock_oversold(&self, units: i64) {
+        self.stock_oversold.inc_by(units.max(0) as u64);
+    }
+
+    pub fn stock_flushed(&self) {
+        self.stock_flush_last_success.set(unix_now());
+    }
+
+    pub fn prize_cache_refreshed(&self) {
+        self.prize_cache_last_refresh.set(unix_now());
+    }
+
+    /// Seconds since the last full prize cache refresh; None before the first one.
+    pub fn prize_cache_age(&self) -> Option<f64> {
+        let last = self.prize_cache_last_refresh.get();
+        (last > 0.0).then(|| unix_now() - last)
+    }
+
+    /// Seconds since the last flush pass without failures, counted from startup until the first one.
+    pub fn stock_flush_lag(&self) -> f64 {
+        unix_now() - self.stock_flush_last_success.get().max(self.started_at)
+    }
+
+    /// Prometheus text exposition of every metric.
+    pub fn render(&self, state: &StateData) -> String {
+        if state.redis.is_some() {
+            self.stock_flush_lag.set(self.stock_flush_lag());
+        }
+        if let Some(age) = self.prize_cache_age() {
+            self.prize_cache_age.set(age);
+        }
+        let idle = state.pool.num_idle() as i64;
+        self.db_pool_connections.with_label_values(&["idle"]).set(idle);
+        self.db_pool_connections.with_label_values(&["in_use"]).set(i64::from(state.pool.size()) - idle);
+        self.db_pool_max_connections.set(i64::from(state.pool.options().get_max_connections()));
+        let outbox = self.outbox.snapshot();
+        self.outbox_backlog.set(outbox.backlog);
+        self.outbox_pending.set(outbox.pending);
+        sync_counter(&self.outbox_persisted, outbox.persisted_total);
+        sync_counter(&self.outbox_failed_batches, outbox.failed_batches_total);
+        sync_counter(&self.outbox_dead_lettered, outbox.dead_lettered_total);
+
+        let mut buf = Vec::new();
+        if let Err(e) = TextEncoder::new().encode(&self.registry.gather(), &mut buf) {
+            tracing::warn!(error = ?e, "failed to encode metrics");
+        }
+        String::from_utf8(buf).unwrap_or_default()
+    }
+}
@@ -24,6 +24,16 @@ pub struct Activity {
     pub start_time: DateTime<Utc>,
     pub end_time: DateTime<Utc>,
     pub status: ActivityStatus,
+    pub draw_cooldown_secs: i32,
+    pub max_draws_total: Option<i32>,
+    pub max_draws_daily: Option<i32>,
+    pub max_wins_total: Option<i32>,
+    pub quota_timezone: String,
+    pub prize_selector: PrizeSelectorKind,
+    pub probability_unit: ProbabilityUnit,
+    pub no_win_weight: i32,
+    pub sold_out_policy: SoldOutPolicy,
+    pub provably_fair: bool,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
 }
@@ -38,6 +48,88 @@ pub enum ActivityStatus {
     Ended,
 }
 
+/// Admin permissions, each role including the ones before it: viewers read, operators also change activities,
+/// prizes and stock, superadmins also manage admins.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, Type)]
+#[sqlx(type_name = "admin_role", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum AdminRole {
+    Viewer,
+    Operator,
+    Superadmin,
+}
+
+impl std::str::FromStr for AdminRole {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "viewer" => Ok(AdminRole::Viewer),
+            "operator" => Ok(AdminRole::Operator),
+            "superadmin" => Ok(AdminRole::Superadmin),
+            _ => Err(anyhow::anyhow!("unknown admin role {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, Type)]
+#[sqlx(type_name = "prize_selector", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum PrizeSelectorKind {
+    #[default]
+    Linear,
+    Alias,
+}
+
+/// How `prizes.probability` is interpreted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, Type)]
+#[sqlx(type_name = "probability_unit", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum ProbabilityUnit {
+    /// percent of all draws; whatever is left to 100 loses
+    #[default]
+    Percent,
+    /// 1/10000 of all draws; whatever is left to 10000 loses
+    BasisPoints,
+    /// relative weights; losing weighs `activities.no_win_weight`
+    Weight,
+}
+
+impl ProbabilityUnit {
+    /// What all outcomes add up to; None for weights, which have no fixed total.
+    pub fn scale(self) -> Option<u64> {
+        match self {
+            ProbabilityUnit::Percent => Some(100),
+            ProbabilityUnit::BasisPoints => Some(10_000),
+            ProbabilityUnit::Weight => None,
+        }
+    }
+}
+
+/// What a draw does when the selected prize is sold out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, Type)]
+#[sqlx(type_name = "sold_out_policy", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum SoldOutPolicy {
+    /// the draw loses
+    #[default]
+    Lose,
+    /// pick again among the in-stock prizes, weights renormalised
+    Redraw,
+    /// take the next lower tier that is in stock
+    Downgrade,
+}
+
+impl SoldOutPolicy {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SoldOutPolicy::Lose => "lose",
+            SoldOutPolicy::Redraw => "redraw",
+            SoldOutPolicy::Downgrade => "downgrade",
+        }
+    }
+}
+
 #[derive(Debug, Clone, FromRow, Serialize)]
 pub struct Prize {
     pub id: Uuid,
@@ -47,6 +139,8 @@ pub struct Prize {
     pub total_count: i64,
     pub remaining_count: i64,
     pub probability: i32, // 作为权重
+    /// 1 = top prize; larger numbers are lower tiers
+    pub tier: i32,
     pub is_enabled: bool,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
@@ -56,6 +150,7 @@ pub struct Prize {
 pub struct LotteryRecord {
     pub id: Uuid,
     pub user_id: Uuid,
+    pub activity_id: Option<Uuid>,
     pub prize_id: Option<Uuid>,
     pub prize_name: Option<String>,
     pub created_at: DateTime<Utc>,
@@ -76,5 +171,22 @@ pub struct LoginDto {
 
 #[derive(Debug, Serialize)]
 pub struct JwtResponse {
+    /// access token
     pub token: String,
+    pub expires_in: u64,
+    pub refresh_token: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RefreshDto {
+    pub refresh_token: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct LogoutDto {
+    /// revokes this token's family
+    pub refresh_token: Option<String>,
+    /// revokes every refresh token of the user
+    #[serde(default)]
+    pub all: bool,
 }
@@ -1,39 +1,198 @@
 use once_cell::sync::Lazy;
 use redis::Script;
 
-// KEYS[1] = cooldown key, ARGV[1] = ttl seconds
-pub static LUA_COOLDOWN_ONLY: Lazy<Script> = Lazy::new(|| {
+// One atomic draw attempt: cooldown, per-user quotas, (when a prize was selected) stock with the activity's
+// sold-out policy, pity counters and the record outbox.
+// KEYS[1] = cooldown key, KEYS[2] = total-draws key, KEYS[3] = daily-draws key, KEYS[4] = wins key,
+// KEYS[5] = record outbox stream, KEYS[6] = pity counter hash (field = rule tier),
+// KEYS[5+2i], KEYS[6+2i] = stock and sold-delta keys of candidate i;
+// candidate 1 is the selected prize, 2.. its sold-out fallbacks (no candidates when nothing was selected)
+// ARGV[1] = cooldown seconds (0 = no cooldown), ARGV[2] = max total draws, ARGV[3] = max daily draws,
+// ARGV[4] = max wins (-1 = unlimited for ARGV[2..4]), ARGV[5] = unix ts the total/wins/pity counters expire at,
+// ARGV[6] = unix ts the current quota day ends, ARGV[7] = now (unix ts),
+// ARGV[8..10] = record id, user id, activity id, ARGV[11] = sold-out policy ('lose' | 'redraw' | 'downgrade'),
+// ARGV[12] = roll in [0, 1) for 'redraw' (from the seeds for provably fair draws), ARGV[13] = created_at (unix ms),
+// ARGV[14] = provably fair inputs "{server_seed_hash}:{selection_hash}:{nonce}:{client_seed}" ('' if not provably fair),
+// ARGV[15] = "{forced pity tier}:{policy}" of a provably fair draw (else ''), recorded as its steps together with
+// ":{1 if the wins quota allowed a win, else 0}:{comma-separated ids of the candidates found out of stock}",
+// ARGV[16] = number r of pity rules, ARGV[17..16+r] = their tiers,
+// with c = 16 + r: ARGV[c+4i-3], ARGV[c+4i-2], ARGV[c+4i-1], ARGV[c+4i] = id, name, weight, tier of candidate i
+// every accepted draw updates the pity counters (reset when the won tier qualifies, else +1) and appends
+// exactly one record, carrying the new counters, to the outbox stream in the same script
+// returns {code, n}:
+//   1 won candidate n, stock decremented; 2 lost (no prize selected or wins quota reached);
+//   -1 lost, selected prize and its fallbacks out of stock
+//   0 in cooldown (n = seconds left); -2 total quota used up; -3 daily quota used up (n = seconds to reset)
+pub static LUA_DRAW: Lazy<Script> = Lazy::new(|| {
     Script::new(r#"
         local cd = KEYS[1]
         local ttl = tonumber(ARGV[1])
-        if redis.call('EXISTS', cd) == 1 then
-            return 0
+        local max_total = tonumber(ARGV[2])
+        local max_daily = tonumber(ARGV[3])
+        local max_wins = tonumber(ARGV[4])
+        local expire_at = tonumber(ARGV[5])
+        local day_end = tonumber(ARGV[6])
+        local now = tonumber(ARGV[7])
+        if ttl > 0 and redis.call('EXISTS', cd) == 1 then
+            return {0, math.max(1, redis.call('TTL', cd))}
         end
-        redis.call('SET', cd, '1', 'EX', ttl, 'NX')
-        return 1
+        local total = tonumber(redis.call('GET', KEYS[2]) or '0')
+        if max_total >= 0 and total >= max_total then
+            return {-2, 0}
+        end
+        local daily = tonumber(redis.call('GET', KEYS[3]) or '0')
+        if max_daily >= 0 and daily >= max_daily then
+            return {-3, math.max(1, day_end - now)}
+        end
+        -- the draw is accepted from here on: it consumes cooldown and quota even if nothing is won
+        if ttl > 0 then
+            redis.call('SET', cd, '1', 'EX', ttl)
+        end
+        redis.call('INCR', KEYS[2])
+        redis.call('EXPIREAT', KEYS[2], expire_at)
+        redis.call('INCR', KEYS[3])
+        redis.call('EXPIREAT', KEYS[3], day_end + 3600)
+        local rules = tonumber(ARGV[16])
+        local c = 16 + rules
+        local wins = tonumber(redis.call('GET', KEYS[4]) or '0')
+        local capped = max_wins >= 0 and wins >= max_wins
+        local sold_out = {}
+        -- advances the pity counters and records the draw; won_tier is nil for a loss
+        local function record(prize_id, prize_name, won_tier)
+            local counters = {}
+            for j = 1, rules do
+                local tier = ARGV[16 + j]
+                local n = 0
+                if won_tier and won_tier <= tonumber(tier) then
+                    redis.call('HSET', KEYS[6], tier, 0)
+                else
+                    n = redis.call('HINCRBY', KEYS[6], tier, 1)
+                end
+                counters[j] = tier .. ':' .. n
+            end
+            if rules > 0 then
+                redis.call('EXPIREAT', KEYS[6], expire_at)
+            end
+            local steps = ''
+            if ARGV[15] ~= '' then
+                steps = ARGV[15] .. ':' .. (capped and '0' or '1') .. ':' .. table.concat(sold_out, ',')
+            end
+            redis.call('XADD', KEYS[5], '*', 'id', ARGV[8], 'user_id', ARGV[9], 'activity_id', ARGV[10],
+                'prize_id', prize_id, 'prize_name', prize_name, 'ts', ARGV[13], 'pity', table.concat(counters, ','),
+                'fair', ARGV[14], 'steps', steps)
+        end
+        local n = (#KEYS - 6) / 2
+        if n < 1 or capped then
+            record('', '', nil)
+            return {2, 0}
+        end
+        local function in_stock(i)
+            if tonumber(redis.call('GET', KEYS[5 + 2 * i]) or '0') > 0 then
+                return true
+            end
+            sold_out[#sold_out + 1] = ARGV[c + 4 * i - 3]
+            return false
+        end
+        local pick = nil
+        if in_stock(1) then
+            pick = 1
+        elseif ARGV[11] == 'downgrade' then
+            for i = 2, n do
+                if in_stock(i) then
+                    pick = i
+                    break
+                end
+            end
+        elseif ARGV[11] == 'redraw' then
+            local weights, sum, last = {}, 0, nil
+            for i = 2, n do
+                weights[i] = in_stock(i) and tonumber(ARGV[c + 4 * i - 1]) or 0
+                sum = sum + weights[i]
+                if weights[i] > 0 then
+                    last = i
+                end
+            end
+            if sum > 0 then
+                local target, acc = tonumber(ARGV[12]) * sum, 0
+                for i = 2, n do
+                    acc = acc + weights[i]
+                    if target < acc then
+                        pick = i
+                        break
+                    end
+                end
+                pick = pick or last
+            end
+        end
+        if not pick then
+            record('', '', nil)
+            return {-1, 0}
+        end
+        redis.call('DECR', KEYS[5 + 2 * pick])
+        redis.call('INCR', KEYS[6 + 2 * pick])
+        redis.call('INCR', KEYS[4])
+        redis.call('EXPIREAT', KEYS[4], expire_at)
+        record(ARGV[c + 4 * pick - 3], ARGV[c + 4 * pick - 2], tonumber(ARGV[c + 4 * pick]))
+        return {1, pick}
     "#)
 });
 
-// KEYS[1] = cooldown key, KEYS[2] = stock key, KEYS[3] = sold-delta key, ARGV[1] = ttl seconds
-// returns: 1 if cooldown set and stock decremented; 0 if in cooldown; -1 if no stock
-pub static LUA_COOLDOWN_AND_DECR: Lazy<Script> = Lazy::new(|| {
+// Takes stock back from a prize (admin decrease) unless fewer units are left than Postgres and the unflushed
+// sales allow. With a stock key the units come out of it, so draws cannot sell them meanwhile; without one
+// (disabled or not seeded) nothing sells and the check is remaining_count minus the unflushed delta.
+// KEYS[1] = stock key, KEYS[2] = sold-delta key, KEYS[3] = pending batch hash
+// ARGV[1] = units, ARGV[2] = remaining_count in Postgres,
+// ARGV[3] = id of the pending batch if Postgres already applied it ('' otherwise)
+// returns {1, lowered (1/0)} or {0, units left} when there is not enough
+pub static LUA_TAKE_BACK_STOCK: Lazy<Script> = Lazy::new(|| {
     Script::new(r#"
-        local cd = KEYS[1]
-        local sk = KEYS[2]
-        local sold = KEYS[3]
-        local ttl = tonumber(ARGV[1])
-        if redis.call('EXISTS', cd) == 1 then
-            return 0
-        end
-        local stock = tonumber(redis.call('GET', sk) or '0')
-        if stock <= 0 then
-            -- still set cooldown to throttle retries
-            redis.call('SET', cd, '1', 'EX', ttl, 'NX')
-            return -1
-        end
-        redis.call('DECR', sk)
-        redis.call('INCR', sold)
-        redis.call('SET', cd, '1', 'EX', ttl, 'NX')
-        return 1
+        local n = tonumber(ARGV[1])
+        local stock = redis.call('GET', KEYS[1])
+        if stock then
+            if tonumber(stock) < n then
+                return {0, tonumber(stock)}
+            end
+            redis.call('DECRBY', KEYS[1], n)
+            return {1, 1}
+        end
+        local sold = tonumber(redis.call('GET', KEYS[2]) or '0')
+        local batch = redis.call('HGET', KEYS[3], 'batch')
+        if batch and batch ~= ARGV[3] then
+            sold = sold + tonumber(redis.call('HGET', KEYS[3], 'delta') or '0')
+        end
+        local left = tonumber(ARGV[2]) - sold
+        if left < n then
+            return {0, left}
+        end
+        return {1, 0}
+    "#)
+});
+
+// Moves a prize's sold delta into a pending batch for flushing; an unacknowledged batch is handed out again.
+// KEYS[1] = sold-delta key, KEYS[2] = pending batch hash; ARGV[1] = id for a new batch
+// returns {batch id, delta} or {} when there is nothing to flush
+pub static LUA_CLAIM_SOLD: Lazy<Script> = Lazy::new(|| {
+    Script::new(r#"
+        local batch = redis.call('HGET', KEYS[2], 'batch')
+        if batch then
+            return {batch, redis.call('HGET', KEYS[2], 'delta')}
+        end
+        local delta = tonumber(redis.call('GET', KEYS[1]) or '0')
+        if delta <= 0 then
+            return {}
+        end
+        redis.call('DEL', KEYS[1])
+        redis.call('HSET', KEYS[2], 'batch', ARGV[1], 'delta', delta)
+        return {ARGV[1], tostring(delta)}
+    "#)
+});
+
+// Drops a pending batch once Postgres committed it. KEYS[1] = pending batch hash, ARGV[1] = batch id
+pub static LUA_ACK_SOLD: Lazy<Script> = Lazy::new(|| {
+    Script::new(r#"
+        if redis.call('HGET', KEYS[1], 'batch') == ARGV[1] then
+            return redis.call('DEL', KEYS[1])
+        end
+        return 0
     "#)
 });
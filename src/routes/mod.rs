@@ -1,51 +1,38 @@
 
 use axum::{
-    extract::State,
-    routing::{get, post},
+    middleware,
+    routing::{get, patch, post},
     Router,
 };
-use sqlx::PgPool;
-use std::sync::Arc;
 
-use crate::config::Config;
+use crate::auth;
+use crate::i18n;
 
-pub type AppState = Arc<StateData>;
+pub use crate::state::{AppState, StateData};
 
-#[derive(Clone)]
-pub struct StateData {
-    pub pool: PgPool,
-    pub cfg: Config,
-}
-
-pub fn auth_routes(pool: &PgPool, cfg: &Config) -> Router {
-    let state = Arc::new(StateData {
-        pool: pool.clone(),
-        cfg: cfg.clone(),
-    });
+pub fn auth_routes(state: &AppState) -> Router {
     Router::new()
         .route("/api/auth/register", post(self::routes_auth::register))
         .route("/api/auth/login", post(self::routes_auth::login))
-        .with_state(state)
+        .route("/api/auth/refresh", post(self::routes_auth::refresh))
+        .route("/api/auth/logout", post(self::routes_auth::logout))
+        .route("/.well-known/jwks.json", get(self::routes_auth::jwks))
+        .with_state(state.clone())
+        .layer(middleware::from_fn_with_state(state.cfg.get().server.default_locale, i18n::negotiate))
 }
 
-pub fn user_routes(pool: &PgPool, cfg: &Config) -> Router {
-    let state = Arc::new(StateData {
-        pool: pool.clone(),
-        cfg: cfg.clone(),
-    });
+pub fn user_routes(state: &AppState) -> Router {
     Router::new()
         .route("/api/user/profile", get(self::routes_user::profile))
         .route("/api/user/lottery-history", get(self::routes_user::history))
-        .with_state(state)
+        .route_layer(middleware::from_fn_with_state(state.clone(), auth::require_user))
+        .with_state(state.clone())
+        .layer(middleware::from_fn_with_state(state.cfg.get().server.default_locale, i18n::negotiate))
 }
 
-pub fn lottery_routes(pool: &PgPool, cfg: &Config) -> Router {
-    let state = Arc::new(StateData {
-        pool: pool.clone(),
-        cfg: cfg.clone(),
-    });
-    Router::new()
-        .route("/api/lottery/draw", post(self::routes_lottery::draw))
+pub fn lottery_routes(state: &AppState) -> Router {
+    // anything not listed here needs a user, so a new route is never public by accident
+    let public = Router::new()
         .route(
             "/api/lottery/prizes",
             get(self::routes_lottery::list_prizes),
@@ -54,32 +41,73 @@ pub fn lottery_routes(pool: &PgPool, cfg: &Config) -> Router {
             "/api/lottery/global-history",
             get(self::routes_lottery::global_history),
         )
-        .with_state(state)
+        .route(
+            "/api/lottery/fair/:activity_id",
+            get(self::routes_lottery::fair_commitment),
+        )
+        .route(
+            "/api/lottery/verify/:record_id",
+            get(self::routes_lottery::verify_record),
+        )
+        .route("/api/lottery/stream", get(self::routes_lottery::stream))
+        .route("/api/lottery/stream/ws", get(self::routes_lottery::stream_ws));
+    Router::new()
+        .route("/api/lottery/draw", post(self::routes_lottery::draw))
+        .route_layer(middleware::from_fn_with_state(state.clone(), auth::require_user))
+        .merge(public)
+        .with_state(state.clone())
+        .layer(middleware::from_fn_with_state(state.cfg.get().server.default_locale, i18n::negotiate))
 }
 
-pub fn admin_routes(pool: &PgPool, cfg: &Config) -> Router {
-    let state = Arc::new(StateData {
-        pool: pool.clone(),
-        cfg: cfg.clone(),
-    });
+pub fn admin_routes(state: &AppState) -> Router {
+    // every route above the guard needs an active admin; handlers add their minimum role
     Router::new()
-        .route("/admin/api/login", post(self::routes_admin::admin_login))
         .route(
             "/admin/api/activities",
             get(self::routes_admin::list_activities).post(self::routes_admin::create_activity),
         )
+        .route(
+            "/admin/api/activities/:id",
+            patch(self::routes_admin::update_activity).delete(self::routes_admin::delete_activity),
+        )
         .route(
             "/admin/api/prizes",
             get(self::routes_admin::list_prizes).post(self::routes_admin::create_prize),
         )
+        .route(
+            "/admin/api/prizes/:id",
+            patch(self::routes_admin::update_prize).delete(self::routes_admin::delete_prize),
+        )
+        .route("/admin/api/outbox", get(self::routes_admin::outbox_stats))
+        .route("/admin/api/stock/reconcile", post(self::routes_admin::reconcile_stock))
+        .route(
+            "/admin/api/admins",
+            get(self::routes_admin::list_admins).post(self::routes_admin::create_admin),
+        )
+        .route("/admin/api/admins/:id", patch(self::routes_admin::update_admin))
         .route(
             "/admin/api/bench/mint-tokens",
             post(self::routes_admin::bench_mint_tokens),
         )
-        .with_state(state)
+        .route("/admin/api/logout", post(self::routes_admin::admin_logout))
+        .route_layer(middleware::from_fn_with_state(state.clone(), auth::require_admin))
+        .route("/admin/api/login", post(self::routes_admin::admin_login))
+        .with_state(state.clone())
+        .layer(middleware::from_fn_with_state(state.cfg.get().server.default_locale, i18n::negotiate))
+}
+
+/// Operational endpoints for scrapers and orchestrators; not localized and not behind auth.
+pub fn ops_routes(state: &AppState) -> Router {
+    Router::new()
+        .route("/metrics", get(self::routes_ops::metrics))
+        .route("/livez", get(self::routes_ops::livez))
+        .route("/healthz", get(self::routes_ops::livez))
+        .route("/readyz", get(self::routes_ops::readyz))
+        .with_state(state.clone())
 }
 
 pub mod routes_admin;
 pub mod routes_auth;
 pub mod routes_lottery;
+pub mod routes_ops;
 pub mod routes_user;
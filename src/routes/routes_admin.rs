@@ -1,18 +1,15 @@
 
 use crate::{
-    auth::{sign_jwt, verify_jwt},
-    error::{AppError, AppResult},
-    models::{Activity, Prize, ActivityStatus},
+    auth::{hash_password, role, sign_jwt, verify_password, AdminUser, MIN_PASSWORD_LEN},
+    error::{AppError, AppResult, Reason},
+    models::{Activity, Prize, ActivityStatus, AdminRole, PrizeSelectorKind, ProbabilityUnit, SoldOutPolicy},
     routes::AppState,
-    services::{activity_service, prize_service},
-};
-use axum::{extract::State, Json};
-use axum_extra::{
-    headers::{authorization::Bearer, Authorization},
-    TypedHeader,
+    services::{activity_service, admin_service, pity_service::PityRule, prize_selector::SelectionConfig, prize_service, stock_sync},
+    validation::Validator,
 };
+use axum::{extract::{Path, Query, State}, http::StatusCode, Json};
 use chrono::{DateTime, Utc};
-use serde::Deserialize;
+use serde::{Deserialize, Deserializer};
 use sqlx::types::Uuid;
 
 #[derive(Deserialize)]
@@ -25,27 +22,106 @@ pub async fn admin_login(
     State(state): State<AppState>,
     Json(payload): Json<AdminLoginDto>,
 ) -> AppResult<Json<serde_json::Value>> {
-    if payload.username != state.cfg.admin_username || payload.password != state.cfg.admin_password
-    {
+    let admin = admin_service::find_admin_credentials(&state.pool, &payload.username).await?;
+    let admin = match admin {
+        Some(a) if a.is_active => a,
+        _ => return Err(AppError::Unauthorized),
+    };
+    if !verify_password(&payload.password, &admin.password_hash)? {
         return Err(AppError::Unauthorized);
     }
-    let token = sign_jwt(&state.cfg, "admin", true)?;
-    Ok(Json(serde_json::json!({"token": token})))
+    admin_service::touch_login(&state.pool, admin.id).await?;
+    let token = sign_jwt(&state.cfg.get(), &admin.id.to_string(), Some(admin.role))?;
+    Ok(Json(serde_json::json!({"token": token, "role": admin.role})))
+}
+
+/// Revokes the admin token of the request until it would have expired.
+pub async fn admin_logout(State(state): State<AppState>, admin: AdminUser) -> StatusCode {
+    state.denylist.deny(&admin.claims.jti, admin.claims.exp).await;
+    StatusCode::NO_CONTENT
+}
+
+/// Tells a present `null` (Some(None)) apart from a missing field (None).
+fn nullable<'de, D: Deserializer<'de>, T: Deserialize<'de>>(d: D) -> Result<Option<Option<T>>, D::Error> {
+    Option::<T>::deserialize(d).map(Some)
+}
+
+/// Makes an admin change visible to draws right away: the activity is reloaded into the prize cache and the
+/// Redis stock of `prize_ids` is rebuilt from Postgres and announced on the live feed. Failures are logged; the
+/// periodic refresh and a manual reconcile catch up.
+async fn propagate(state: &AppState, activity_id: Uuid, prize_ids: &[Uuid]) {
+    if let Err(e) = state.prize_cache.reload_activity(&state.pool, activity_id).await {
+        tracing::error!(error = ?e, activity_id = %activity_id, "failed to reload activity into prize cache");
+    }
+    if prize_ids.is_empty() {
+        return;
+    }
+    if let Some(mut redis) = state.redis.clone() {
+        if let Err(e) = stock_sync::reconcile_stock(&state.pool, &mut redis, &state.metrics, Some(prize_ids), true).await {
+            tracing::error!(error = ?e, prize_ids = ?prize_ids, "failed to sync redis stock");
+        }
+    }
+    state.feed.announce_stock(&state.pool, activity_id, prize_ids).await;
 }
 
-fn ensure_admin(state: &AppState, token: &str) -> AppResult<()> {
-    let claims = verify_jwt(&state.cfg, token)?;
-    if !claims.is_admin {
-        return Err(AppError::Forbidden);
+fn check_activity_limits(v: &mut Validator, cooldown: Option<i32>, quotas: [Option<i32>; 3], quota_timezone: Option<&str>, no_win_weight: Option<i32>) {
+    v.check(cooldown.is_none_or(|c| c >= 0), "draw_cooldown_secs", "negative_cooldown");
+    for (field, quota) in ["max_draws_total", "max_draws_daily", "max_wins_total"].into_iter().zip(quotas) {
+        v.check(quota.is_none_or(|n| n >= 0), field, "negative_quota");
+    }
+    v.check(quota_timezone.is_none_or(|tz| tz.parse::<chrono_tz::Tz>().is_ok()), "quota_timezone", "invalid_timezone");
+    v.check(no_win_weight.is_none_or(|w| w >= 0), "no_win_weight", "negative_no_win_weight");
+}
+
+fn check_pity_rules(v: &mut Validator, rules: &[PityRule]) {
+    v.check(rules.iter().all(|r| r.tier >= 1 && r.threshold >= 1), "pity_rules", "invalid_pity_rule");
+    v.check(!rules.iter().enumerate().any(|(i, r)| rules[..i].iter().any(|o| o.tier == r.tier)), "pity_rules", "duplicate_pity_tier");
+}
+
+fn check_name(v: &mut Validator, name: Option<&str>) {
+    v.check(name.is_none_or(|n| !n.trim().is_empty()), "name", "name_empty");
+}
+
+impl CreateActivityDto {
+    fn validate(&self) -> AppResult<()> {
+        let mut v = Validator::new();
+        check_name(&mut v, Some(&self.name));
+        v.check(self.end_time > self.start_time, "end_time", "end_before_start");
+        check_activity_limits(
+            &mut v,
+            self.draw_cooldown_secs,
+            [self.max_draws_total, self.max_draws_daily, self.max_wins_total],
+            self.quota_timezone.as_deref(),
+            self.no_win_weight,
+        );
+        check_pity_rules(&mut v, &self.pity_rules);
+        v.finish()
+    }
+}
+
+/// Checks of one prize's own fields, shared by create and update.
+fn check_prize_fields(v: &mut Validator, name: Option<&str>, probability: Option<i32>, tier: Option<i32>) {
+    check_name(v, name);
+    v.check(probability.is_none_or(|p| p >= 0), "probability", "negative_probability");
+    v.check(tier.is_none_or(|t| t >= 1), "tier", "invalid_tier");
+}
+
+impl CreatePrizeDto {
+    async fn validate(&self, pool: &sqlx::PgPool) -> AppResult<()> {
+        let mut v = Validator::new();
+        check_prize_fields(&mut v, Some(&self.name), Some(self.probability), self.tier);
+        v.check(self.total_count >= 0, "total_count", "negative_total_count");
+        // reported with the field errors; the budget is checked when the prize is inserted
+        let budget = prize_service::probability_budget(pool, self.activity_id, None).await?;
+        v.check(budget.is_some(), "activity_id", "activity_not_found");
+        v.finish()
     }
-    Ok(())
 }
 
 pub async fn list_activities(
     State(state): State<AppState>,
-    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    _admin: AdminUser<role::Viewer>,
 ) -> AppResult<Json<serde_json::Value>> {
-    ensure_admin(&state, bearer.token())?;
     let rows: Vec<Activity> = activity_service::list_activities(&state.pool).await?;
     Ok(Json(serde_json::json!({"activities": rows})))
 }
@@ -57,24 +133,151 @@ pub struct CreateActivityDto {
     pub start_time: DateTime<Utc>,
     pub end_time: DateTime<Utc>,
     pub status: ActivityStatus,
+    /// seconds a user must wait between two draws; defaults to 60, 0 disables the cooldown
+    pub draw_cooldown_secs: Option<i32>,
+    /// per-user quotas; omitted means unlimited
+    pub max_draws_total: Option<i32>,
+    pub max_draws_daily: Option<i32>,
+    pub max_wins_total: Option<i32>,
+    /// IANA timezone defining the day for `max_draws_daily`; defaults to UTC
+    pub quota_timezone: Option<String>,
+    /// "linear" (default) or "alias"
+    #[serde(default)]
+    pub prize_selector: PrizeSelectorKind,
+    /// "percent" (default), "basis_points" or "weight"
+    #[serde(default)]
+    pub probability_unit: ProbabilityUnit,
+    /// weight of drawing nothing; only used with "weight"
+    pub no_win_weight: Option<i32>,
+    /// "lose" (default), "redraw" or "downgrade" when the selected prize is sold out
+    #[serde(default)]
+    pub sold_out_policy: SoldOutPolicy,
+    /// guaranteed wins: after `threshold` draws without a prize of tier <= `tier`, the next draw gets one
+    #[serde(default)]
+    pub pity_rules: Vec<PityRule>,
+    /// commit-reveal draws: rolls derive from a server seed published after the activity ends
+    #[serde(default)]
+    pub provably_fair: bool,
 }
 
 pub async fn create_activity(
     State(state): State<AppState>,
-    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    _admin: AdminUser<role::Operator>,
     Json(payload): Json<CreateActivityDto>,
 ) -> AppResult<Json<serde_json::Value>> {
-    ensure_admin(&state, bearer.token())?;
+    payload.validate()?;
     let id = Uuid::new_v4();
-    activity_service::create_activity(&state.pool, id, payload.name, payload.description, payload.start_time, payload.end_time, payload.status).await?;
+    let cooldown = payload.draw_cooldown_secs.unwrap_or(state.cfg.get().draw.default_cooldown_secs);
+    let quota_timezone = payload.quota_timezone.unwrap_or_else(|| activity_service::DEFAULT_QUOTA_TIMEZONE.to_string());
+    let no_win_weight = payload.no_win_weight.unwrap_or(0);
+    activity_service::create_activity(&state.pool, id, activity_service::NewActivity {
+        name: payload.name,
+        description: payload.description,
+        start_time: payload.start_time,
+        end_time: payload.end_time,
+        status: payload.status,
+        draw_cooldown_secs: cooldown,
+        max_draws_total: payload.max_draws_total,
+        max_draws_daily: payload.max_draws_daily,
+        max_wins_total: payload.max_wins_total,
+        quota_timezone,
+        selection: SelectionConfig {
+            prize_selector: payload.prize_selector,
+            probability_unit: payload.probability_unit,
+            no_win_weight,
+            sold_out_policy: payload.sold_out_policy,
+        },
+        pity_rules: payload.pity_rules,
+        provably_fair: payload.provably_fair,
+    }).await?;
+    propagate(&state, id, &[]).await;
     Ok(Json(serde_json::json!({"id": id})))
 }
 
+#[derive(Deserialize)]
+pub struct UpdateActivityDto {
+    pub name: Option<String>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub description: Option<Option<String>>,
+    pub start_time: Option<DateTime<Utc>>,
+    pub end_time: Option<DateTime<Utc>>,
+    /// "paused" pauses draws, "ongoing" resumes them
+    pub status: Option<ActivityStatus>,
+    pub draw_cooldown_secs: Option<i32>,
+    /// `null` removes the limit
+    #[serde(default, deserialize_with = "nullable")]
+    pub max_draws_total: Option<Option<i32>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub max_draws_daily: Option<Option<i32>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub max_wins_total: Option<Option<i32>>,
+    pub quota_timezone: Option<String>,
+    pub prize_selector: Option<PrizeSelectorKind>,
+    pub probability_unit: Option<ProbabilityUnit>,
+    pub no_win_weight: Option<i32>,
+    pub sold_out_policy: Option<SoldOutPolicy>,
+    /// replaces all pity rules; `[]` removes them
+    pub pity_rules: Option<Vec<PityRule>>,
+}
+
+pub async fn update_activity(
+    State(state): State<AppState>,
+    _admin: AdminUser<role::Operator>,
+    Path(id): Path<Uuid>,
+    Json(payload): Json<UpdateActivityDto>,
+) -> AppResult<Json<Activity>> {
+    let mut v = Validator::new();
+    check_name(&mut v, payload.name.as_deref());
+    check_activity_limits(
+        &mut v,
+        payload.draw_cooldown_secs,
+        [payload.max_draws_total.flatten(), payload.max_draws_daily.flatten(), payload.max_wins_total.flatten()],
+        payload.quota_timezone.as_deref(),
+        payload.no_win_weight,
+    );
+    check_pity_rules(&mut v, payload.pity_rules.as_deref().unwrap_or_default());
+    v.finish()?;
+    let updated = activity_service::update_activity(&state.pool, id, activity_service::ActivityPatch {
+        name: payload.name,
+        description: payload.description,
+        start_time: payload.start_time,
+        end_time: payload.end_time,
+        status: payload.status,
+        draw_cooldown_secs: payload.draw_cooldown_secs,
+        max_draws_total: payload.max_draws_total,
+        max_draws_daily: payload.max_draws_daily,
+        max_wins_total: payload.max_wins_total,
+        quota_timezone: payload.quota_timezone,
+        prize_selector: payload.prize_selector,
+        probability_unit: payload.probability_unit,
+        no_win_weight: payload.no_win_weight,
+        sold_out_policy: payload.sold_out_policy,
+        pity_rules: payload.pity_rules,
+    }).await?.ok_or(AppError::NotFound)?;
+    propagate(&state, id, &[]).await;
+    Ok(Json(updated))
+}
+
+pub async fn delete_activity(
+    State(state): State<AppState>,
+    _admin: AdminUser<role::Operator>,
+    Path(id): Path<Uuid>,
+) -> AppResult<Json<serde_json::Value>> {
+    let prize_ids = activity_service::delete_activity(&state.pool, id).await?.ok_or(AppError::NotFound)?;
+    if let Some(mut redis) = state.redis.clone() {
+        if let Err(e) = stock_sync::forget_prizes(&mut redis, &prize_ids).await {
+            tracing::warn!(error = ?e, activity_id = %id, "failed to drop redis keys of deleted prizes");
+        }
+    }
+    // after the keys are gone, so the feed reports the prizes as empty
+    propagate(&state, id, &prize_ids).await;
+    Ok(Json(serde_json::json!({"id": id, "deleted_prizes": prize_ids})))
+}
+
 pub async fn list_prizes(
     State(state): State<AppState>,
-    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    _admin: AdminUser<role::Viewer>,
 ) -> AppResult<Json<serde_json::Value>> {
-    ensure_admin(&state, bearer.token())?;
     let rows: Vec<Prize> = prize_service::list_prizes(&state.pool).await?;
     Ok(Json(serde_json::json!({"prizes": rows})))
 }
@@ -86,29 +289,114 @@ pub struct CreatePrizeDto {
     pub description: Option<String>,
     pub total_count: i64,
     pub probability: i32,
+    /// 1 = top prize (default); larger numbers are lower tiers, used by the "downgrade" sold-out policy
+    pub tier: Option<i32>,
     pub is_enabled: bool,
 }
 
 pub async fn create_prize(
     State(state): State<AppState>,
-    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    _admin: AdminUser<role::Operator>,
     Json(payload): Json<CreatePrizeDto>,
 ) -> AppResult<Json<serde_json::Value>> {
-    ensure_admin(&state, bearer.token())?;
+    payload.validate(&state.pool).await?;
     let id = Uuid::new_v4();
-    prize_service::create_prize(&state.pool, id, payload.activity_id, payload.name, payload.description, payload.total_count, payload.probability, payload.is_enabled).await?;
+    prize_service::create_prize(&state.pool, id, prize_service::NewPrize {
+        activity_id: payload.activity_id,
+        name: payload.name,
+        description: payload.description,
+        total_count: payload.total_count,
+        probability: payload.probability,
+        tier: payload.tier.unwrap_or(prize_service::DEFAULT_PRIZE_TIER),
+        is_enabled: payload.is_enabled,
+    }).await?;
+    // a new prize has no Redis stock key yet; seed it right away instead of waiting for a manual prepare
+    propagate(&state, payload.activity_id, &[id]).await;
+    Ok(Json(serde_json::json!({"id": id})))
+}
+
+#[derive(Deserialize)]
+pub struct UpdatePrizeDto {
+    pub name: Option<String>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub description: Option<Option<String>>,
+    pub probability: Option<i32>,
+    pub tier: Option<i32>,
+    /// false takes the prize out of draws without deleting it
+    pub is_enabled: Option<bool>,
+    /// stock to add (top up) or, when negative, to take back
+    pub stock_delta: Option<i64>,
+}
+
+pub async fn update_prize(
+    State(state): State<AppState>,
+    _admin: AdminUser<role::Operator>,
+    Path(id): Path<Uuid>,
+    Json(payload): Json<UpdatePrizeDto>,
+) -> AppResult<Json<Prize>> {
+    let mut v = Validator::new();
+    check_prize_fields(&mut v, payload.name.as_deref(), payload.probability, payload.tier);
+    v.finish()?;
+    let updated = prize_service::update_prize(&state.pool, state.redis.as_ref(), id, prize_service::PrizePatch {
+        name: payload.name,
+        description: payload.description,
+        probability: payload.probability,
+        tier: payload.tier,
+        is_enabled: payload.is_enabled,
+        stock_delta: payload.stock_delta,
+    }).await?.ok_or(AppError::NotFound)?;
+    // Redis keeps unflushed sales on top of remaining_count, so the stock key is recomputed rather than set
+    propagate(&state, updated.activity_id, &[id]).await;
+    Ok(Json(updated))
+}
+
+pub async fn delete_prize(
+    State(state): State<AppState>,
+    _admin: AdminUser<role::Operator>,
+    Path(id): Path<Uuid>,
+) -> AppResult<Json<serde_json::Value>> {
+    let activity_id = prize_service::delete_prize(&state.pool, id).await?.ok_or(AppError::NotFound)?;
+    if let Some(mut redis) = state.redis.clone() {
+        if let Err(e) = stock_sync::forget_prizes(&mut redis, &[id]).await {
+            tracing::warn!(error = ?e, prize_id = %id, "failed to drop redis keys of deleted prize");
+        }
+    }
+    propagate(&state, activity_id, &[id]).await;
     Ok(Json(serde_json::json!({"id": id})))
 }
 
+pub async fn outbox_stats(
+    State(state): State<AppState>,
+    _admin: AdminUser<role::Viewer>,
+) -> AppResult<Json<serde_json::Value>> {
+    Ok(Json(serde_json::json!({"outbox": state.metrics.outbox.snapshot()})))
+}
+
+#[derive(Deserialize)]
+pub struct ReconcileQuery {
+    /// only report the drift, leave Redis untouched
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+pub async fn reconcile_stock(
+    State(state): State<AppState>,
+    _admin: AdminUser<role::Operator>,
+    Query(q): Query<ReconcileQuery>,
+) -> AppResult<Json<serde_json::Value>> {
+    let mut redis = state.redis.clone().ok_or_else(|| AppError::conflict(Reason::RedisBackendDisabled, "未启用 Redis 抽奖后端"))?;
+    let report = stock_sync::reconcile_stock(&state.pool, &mut redis, &state.metrics, None, !q.dry_run).await?;
+    Ok(Json(serde_json::json!({"applied": !q.dry_run, "prizes": report})))
+}
+
 #[derive(Deserialize)]
 pub struct BenchMintReq { pub count: usize, pub prefix: Option<String> }
 
 pub async fn bench_mint_tokens(
     State(state): State<AppState>,
-    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    _admin: AdminUser<role::Superadmin>,
     Json(payload): Json<BenchMintReq>,
 ) -> AppResult<Json<serde_json::Value>> {
-    ensure_admin(&state, bearer.token())?;
     let prefix = payload.prefix.unwrap_or_else(|| "bench_user_".to_string());
     let mut tokens = Vec::with_capacity(payload.count);
     let mut tx = state.pool.begin().await?;
@@ -134,9 +422,54 @@ pub async fn bench_mint_tokens(
                 id
             }
         };
-        let token = sign_jwt(&state.cfg, &uid.to_string(), false)?;
+        let token = sign_jwt(&state.cfg.get(), &uid.to_string(), None)?;
         tokens.push(token);
     }
     tx.commit().await?;
     Ok(Json(serde_json::json!({"tokens": tokens})))
 }
+
+#[derive(Deserialize)]
+pub struct CreateAdminDto {
+    pub username: String,
+    pub password: String,
+    pub role: AdminRole,
+}
+
+pub async fn list_admins(
+    State(state): State<AppState>,
+    _admin: AdminUser<role::Superadmin>,
+) -> AppResult<Json<serde_json::Value>> {
+    let admins = admin_service::list_admins(&state.pool).await?;
+    Ok(Json(serde_json::json!({"admins": admins})))
+}
+
+pub async fn create_admin(
+    State(state): State<AppState>,
+    _admin: AdminUser<role::Superadmin>,
+    Json(payload): Json<CreateAdminDto>,
+) -> AppResult<Json<admin_service::AdminUser>> {
+    let mut v = Validator::new();
+    v.check(!payload.username.trim().is_empty(), "username", "name_empty")
+        .check(payload.password.len() >= MIN_PASSWORD_LEN, "password", "password_too_short");
+    v.finish()?;
+    let hash = hash_password(&payload.password)?;
+    let admin = admin_service::create_admin(&state.pool, Uuid::new_v4(), payload.username.trim(), &hash, payload.role).await?;
+    Ok(Json(admin))
+}
+
+#[derive(Deserialize)]
+pub struct UpdateAdminDto {
+    pub role: Option<AdminRole>,
+    pub is_active: Option<bool>,
+}
+
+pub async fn update_admin(
+    State(state): State<AppState>,
+    _admin: AdminUser<role::Superadmin>,
+    Path(id): Path<Uuid>,
+    Json(payload): Json<UpdateAdminDto>,
+) -> AppResult<Json<admin_service::AdminUser>> {
+    let admin = admin_service::update_admin(&state.pool, id, payload.role, payload.is_active).await?.ok_or(AppError::NotFound)?;
+    Ok(Json(admin))
+}
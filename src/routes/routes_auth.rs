@@ -1,31 +1,42 @@
 
 use crate::{
-    auth::{hash_password, sign_jwt, verify_password},
-    error::{AppError, AppResult},
-    models::{JwtResponse, LoginDto, RegisterDto},
+    auth::{hash_password, sign_jwt, verify_password, AuthUser, MIN_PASSWORD_LEN},
+    error::{AppError, AppResult, Reason},
+    models::{JwtResponse, LoginDto, LogoutDto, RefreshDto, RegisterDto},
     routes::AppState,
-    services::user_service,
+    services::{token_service, user_service},
+};
+use axum::{
+    extract::State,
+    http::{header, StatusCode},
+    response::IntoResponse,
+    Json,
 };
-use axum::{extract::State, Json};
 use sqlx::types::Uuid;
 
+fn token_pair(state: &AppState, uid: Uuid, refresh_token: String) -> AppResult<Json<JwtResponse>> {
+    let cfg = state.cfg.get();
+    let token = sign_jwt(&cfg, &uid.to_string(), None)?;
+    Ok(Json(JwtResponse { token, expires_in: cfg.auth.access_token_ttl_secs, refresh_token }))
+}
+
 pub async fn register(
     State(state): State<AppState>,
     Json(payload): Json<RegisterDto>,
 ) -> AppResult<Json<JwtResponse>> {
-    if payload.username.trim().is_empty() || payload.password.len() < 6 {
-        return Err(AppError::BadRequest("用户名或密码不合法"));
+    if payload.username.trim().is_empty() || payload.password.len() < MIN_PASSWORD_LEN {
+        return Err(AppError::bad_request(Reason::InvalidRegistration, "用户名或密码不合法"));
     }
     if user_service::is_username_taken(&state.pool, &payload.username).await? {
-        return Err(AppError::BadRequest("用户名已存在"));
+        return Err(AppError::conflict(Reason::UsernameTaken, "用户名已存在"));
     }
 
     let hash = hash_password(&payload.password)?;
     let uid = Uuid::new_v4();
     user_service::create_user(&state.pool, uid, &payload.username, &hash, &payload.email).await?;
 
-    let token = sign_jwt(&state.cfg, &uid.to_string(), false)?;
-    Ok(Json(JwtResponse { token }))
+    let refresh = token_service::issue_refresh(&state.pool, uid, state.cfg.get().auth.refresh_token_ttl_secs).await?;
+    token_pair(&state, uid, refresh)
 }
 
 pub async fn login(
@@ -40,6 +51,37 @@ pub async fn login(
     if !verify_password(&payload.password, &pw_hash)? {
         return Err(AppError::Unauthorized);
     }
-    let token = sign_jwt(&state.cfg, &uid.to_string(), false)?;
-    Ok(Json(JwtResponse { token }))
+    let refresh = token_service::issue_refresh(&state.pool, uid, state.cfg.get().auth.refresh_token_ttl_secs).await?;
+    token_pair(&state, uid, refresh)
+}
+
+/// Trades a refresh token for a new access token and the next refresh token; the old one stops working.
+pub async fn refresh(
+    State(state): State<AppState>,
+    Json(payload): Json<RefreshDto>,
+) -> AppResult<Json<JwtResponse>> {
+    let (uid, refresh) = token_service::rotate_refresh(&state.pool, &payload.refresh_token, state.cfg.get().auth.refresh_token_ttl_secs).await?;
+    token_pair(&state, uid, refresh)
+}
+
+/// Revokes the presented access token and, when given, a refresh token family or all of the user's.
+pub async fn logout(
+    State(state): State<AppState>,
+    user: AuthUser,
+    payload: Option<Json<LogoutDto>>,
+) -> AppResult<StatusCode> {
+    let uid = user.uid;
+    let Json(payload) = payload.unwrap_or_default();
+    if payload.all {
+        token_service::revoke_all_refresh(&state.pool, uid).await?;
+    } else if let Some(refresh) = &payload.refresh_token {
+        token_service::revoke_refresh(&state.pool, uid, refresh).await?;
+    }
+    state.denylist.deny(&user.claims.jti, user.claims.exp).await;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Public token verification keys (JWK Set) for gateways and other services.
+pub async fn jwks(State(state): State<AppState>) -> impl IntoResponse {
+    ([(header::CACHE_CONTROL, "public, max-age=300")], Json(state.cfg.get().jwt.jwks()))
 }
@@ -1,17 +1,25 @@
 use crate::{
-    auth::verify_jwt,
-    error::{AppError, AppResult},
+    auth::AuthUser,
+    error::{AppError, AppResult, Reason},
     routes::AppState,
-    services::{lottery_service, prize_service},
+    services::{fair_service, feed_service::FeedEvent, lottery_service},
 };
-use axum::{extract::State, Json};
-use axum_extra::{
-    headers::{authorization::Bearer, Authorization},
-    TypedHeader,
+use axum::{
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        Path, Query, State,
+    },
+    http::{header, HeaderMap, HeaderValue},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+    Json,
 };
 use chrono::{DateTime, Utc};
-use rand::Rng;
-use serde::Serialize;
+use futures_util::{Stream, StreamExt};
+use std::{convert::Infallible, sync::Arc};
+use serde::{Deserialize, Serialize};
 use sqlx::types::Uuid;
 
 #[derive(serde::Serialize)]
@@ -19,6 +27,8 @@ pub struct DrawResult {
     pub won: bool,
     pub prize_id: Option<Uuid>,
     pub prize_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fair: Option<lottery_service::FairProof>,
 }
 
 pub async fn list_prizes(State(state): State<AppState>) -> AppResult<Json<serde_json::Value>> {
@@ -33,19 +43,92 @@ pub async fn list_prizes(State(state): State<AppState>) -> AppResult<Json<serde_
     Ok(Json(serde_json::json!({"prizes": prizes})))
 }
 
+#[derive(Deserialize)]
+pub struct DrawReq {
+    pub activity_id: Uuid,
+    /// provably fair activities only; a random one is used when missing
+    #[serde(default)]
+    pub client_seed: Option<String>,
+}
+
 #[axum::debug_handler]
 pub async fn draw(
     State(state): State<AppState>,
-    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
-) -> AppResult<Json<DrawResult>> {
-    let claims = verify_jwt(&state.cfg, bearer.token())?;
-    let uid = Uuid::parse_str(&claims.uid).map_err(|_| AppError::Unauthorized)?;
+    AuthUser { uid, .. }: AuthUser,
+    headers: HeaderMap,
+    Json(payload): Json<DrawReq>,
+) -> AppResult<Response> {
+
+    // retries carrying the same Idempotency-Key get the stored body of the first draw back verbatim
+    if let Some(key) = headers.get("idempotency-key") {
+        let key = key.to_str().map_err(|_| AppError::bad_request(Reason::InvalidIdempotencyKey, "Idempotency-Key 不合法"))?;
+        let (body, replayed) = lottery_service::draw_idempotent(&state, uid, payload.activity_id, key, payload.client_seed.as_deref()).await?;
+        let mut resp = ([(header::CONTENT_TYPE, HeaderValue::from_static("application/json"))], body).into_response();
+        if replayed {
+            resp.headers_mut().insert("idempotent-replayed", HeaderValue::from_static("true"));
+        }
+        return Ok(resp);
+    }
+
+    let res = lottery_service::draw_with_seed(&state, uid, payload.activity_id, payload.client_seed.as_deref()).await?;
+    Ok(Json(DrawResult { won: res.won, prize_id: res.prize_id, prize_name: res.prize_name, fair: res.fair }).into_response())
+}
+
+/// Server seed commitment of a provably fair activity; the seed itself once the activity has ended.
+pub async fn fair_commitment(State(state): State<AppState>, Path(activity_id): Path<Uuid>) -> AppResult<Json<fair_service::Commitment>> {
+    Ok(Json(fair_service::commitment(&state.pool, activity_id).await?))
+}
 
-    let res = lottery_service::draw(&state.pool, uid).await?;
-    Ok(Json(DrawResult { won: res.won, prize_id: res.prize_id, prize_name: res.prize_name }))
+pub async fn verify_record(State(state): State<AppState>, Path(record_id): Path<Uuid>) -> AppResult<Json<fair_service::Verification>> {
+    Ok(Json(fair_service::verify_record(&state.pool, record_id).await?))
 }
 
 pub async fn global_history(State(state): State<AppState>) -> AppResult<Json<serde_json::Value>> {
     let rows = lottery_service::global_history(&state.pool).await?;
     Ok(Json(serde_json::json!({"records": rows})))
 }
+
+#[derive(Deserialize)]
+pub struct FeedQuery {
+    /// only events of this activity; all when missing
+    pub activity_id: Option<Uuid>,
+}
+
+/// Live wins and stock changes as Server-Sent Events (`event: win` / `event: stock`, JSON data).
+pub async fn stream(State(state): State<AppState>, Query(q): Query<FeedQuery>) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let events = state.feed.subscribe(q.activity_id).map(|e| {
+        Ok(Event::default().event(e.kind()).data(serde_json::to_string(&*e).unwrap_or_default()))
+    });
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
+
+/// The same feed over WebSocket, one JSON text message per event; messages from the client are ignored.
+pub async fn stream_ws(State(state): State<AppState>, Query(q): Query<FeedQuery>, ws: WebSocketUpgrade) -> Response {
+    let events = state.feed.subscribe(q.activity_id);
+    ws.on_upgrade(move |socket| relay_feed(socket, events))
+}
+
+async fn relay_feed(mut socket: WebSocket, events: impl Stream<Item = Arc<FeedEvent>>) {
+    let mut events = std::pin::pin!(events);
+    loop {
+        tokio::select! {
+            event = events.next() => match event {
+                Some(event) => {
+                    let text = serde_json::to_string(&*event).unwrap_or_default();
+                    if socket.send(Message::Text(text)).await.is_err() {
+                        break;
+                    }
+                }
+                // feed closed for shutdown
+                None => {
+                    let _ = socket.send(Message::Close(None)).await;
+                    break;
+                }
+            },
+            msg = socket.recv() => match msg {
+                Some(Ok(Message::Close(_)) | Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+}
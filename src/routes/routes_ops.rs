@@ -0,0 +1,25 @@
+use crate::{routes::AppState, services::health_service};
+use axum::{
+    extract::State,
+    http::{header, StatusCode},
+    response::IntoResponse,
+    Json,
+};
+
+/// Prometheus scrape endpoint (text exposition format).
+pub async fn metrics(State(state): State<AppState>) -> impl IntoResponse {
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], state.metrics.render(&state))
+}
+
+/// Liveness: the process serves requests. Dependencies are left to `/readyz` so an outage of Postgres or
+/// Redis takes instances out of rotation instead of restarting them all.
+pub async fn livez() -> &'static str {
+    "ok"
+}
+
+/// Readiness with a breakdown per check; 503 while any check fails.
+pub async fn readyz(State(state): State<AppState>) -> impl IntoResponse {
+    let readiness = health_service::readiness(&state).await;
+    let status = if readiness.ready { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    (status, Json(readiness))
+}
@@ -1,25 +1,18 @@
 use crate::{
-    auth::verify_jwt,
-    error::{AppError, AppResult},
+    auth::AuthUser,
+    error::AppResult,
     routes::AppState,
-    services::user_service,
+    services::{pity_service, quota_service, user_service},
 };
 use axum::{extract::State, Json};
-use axum_extra::{
-    headers::{authorization::Bearer, Authorization},
-    TypedHeader,
-};
-use chrono::{DateTime, Utc};
-use serde::Serialize;
-use sqlx::types::Uuid;
 
 pub async fn profile(
     State(state): State<AppState>,
-    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    AuthUser { uid, .. }: AuthUser,
 ) -> AppResult<Json<serde_json::Value>> {
-    let claims = verify_jwt(&state.cfg, bearer.token())?;
-    let uid = Uuid::parse_str(&claims.uid).map_err(|_| AppError::Unauthorized)?;
     let user = user_service::get_profile(&state.pool, uid).await?;
+    let quotas = quota_service::remaining_for_user(&state.pool, state.redis.as_ref(), uid).await?;
+    let pity = pity_service::status_for_user(&state.pool, state.redis.as_ref(), uid).await?;
     Ok(Json(serde_json::json!({
         "id": user.id,
         "username": user.username,
@@ -27,15 +20,15 @@ pub async fn profile(
         "last_lottery_at": user.last_lottery_at,
         "created_at": user.created_at,
         "updated_at": user.updated_at,
+        "quotas": quotas,
+        "pity": pity,
     })))
 }
 
 pub async fn history(
     State(state): State<AppState>,
-    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    AuthUser { uid, .. }: AuthUser,
 ) -> AppResult<Json<serde_json::Value>> {
-    let claims = verify_jwt(&state.cfg, bearer.token())?;
-    let uid = Uuid::parse_str(&claims.uid).map_err(|_| AppError::Unauthorized)?;
     let records = user_service::get_history(&state.pool, uid).await?;
     Ok(Json(serde_json::json!({"records": records})))
 }
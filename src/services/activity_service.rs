@@ -1,36 +1,188 @@
-use sqlx::PgPool;
+use sqlx::{PgConnection, PgPool};
 use uuid::Uuid;
-use crate::models::{Activity, ActivityStatus};
+use crate::error::{AppError, Reason};
+use crate::models::{Activity, ActivityStatus, PrizeSelectorKind, ProbabilityUnit, SoldOutPolicy};
+use crate::services::{fair_service, prize_service};
+use crate::validation::Validator;
+use crate::services::pity_service::{self, PityRule};
+use crate::services::prize_selector::SelectionConfig;
 use chrono::{DateTime, Utc};
 
+pub const DEFAULT_QUOTA_TIMEZONE: &str = "UTC";
+
+const ACTIVITY_SELECT: &str = "SELECT id, name, description, start_time, end_time, status, draw_cooldown_secs, \
+    max_draws_total, max_draws_daily, max_wins_total, quota_timezone, \
+    prize_selector, probability_unit, no_win_weight, sold_out_policy, provably_fair, created_at, updated_at FROM activities";
+
 pub async fn list_activities(pool: &PgPool) -> sqlx::Result<Vec<Activity>> {
-    sqlx::query_as::<_, Activity>(
-        r#"SELECT id, name, description, start_time, end_time, status, created_at, updated_at FROM activities ORDER BY created_at DESC"#
-    )
-    .fetch_all(pool)
-    .await
+    sqlx::query_as::<_, Activity>(&format!("{} ORDER BY created_at DESC", ACTIVITY_SELECT))
+        .fetch_all(pool)
+        .await
+}
+
+async fn lock_activity(conn: &mut PgConnection, id: Uuid) -> sqlx::Result<Option<Activity>> {
+    sqlx::query_as::<_, Activity>(&format!("{} WHERE id=$1 FOR UPDATE", ACTIVITY_SELECT))
+        .bind(id)
+        .fetch_optional(conn)
+        .await
+}
+
+pub struct NewActivity {
+    pub name: String,
+    pub description: Option<String>,
+    pub start_time: DateTime<Utc>,
+    pub end_time: DateTime<Utc>,
+    pub status: ActivityStatus,
+    pub draw_cooldown_secs: i32,
+    pub max_draws_total: Option<i32>,
+    pub max_draws_daily: Option<i32>,
+    pub max_wins_total: Option<i32>,
+    pub quota_timezone: String,
+    pub selection: SelectionConfig,
+    pub pity_rules: Vec<PityRule>,
+    pub provably_fair: bool,
 }
 
-pub async fn create_activity(
-    pool: &PgPool,
-    id: Uuid,
-    name: String,
-    description: Option<String>,
-    start_time: DateTime<Utc>,
-    end_time: DateTime<Utc>,
-    status: ActivityStatus,
-) -> sqlx::Result<()> {
+pub async fn create_activity(pool: &PgPool, id: Uuid, a: NewActivity) -> sqlx::Result<()> {
+    let mut tx = pool.begin().await?;
     sqlx::query(
-        r#"INSERT INTO activities (id, name, description, start_time, end_time, status, created_at, updated_at)
-           VALUES ($1,$2,$3,$4,$5,$6, now(), now())"#
+        r#"INSERT INTO activities (id, name, description, start_time, end_time, status, draw_cooldown_secs,
+                                   max_draws_total, max_draws_daily, max_wins_total, quota_timezone,
+                                   prize_selector, probability_unit, no_win_weight, sold_out_policy, provably_fair,
+                                   created_at, updated_at)
+           VALUES ($1,$2,$3,$4,$5,$6,$7,$8,$9,$10,$11,$12,$13,$14,$15,$16, now(), now())"#
+    )
+    .bind(id)
+    .bind(a.name)
+    .bind(a.description)
+    .bind(a.start_time)
+    .bind(a.end_time)
+    .bind(a.status)
+    .bind(a.draw_cooldown_secs)
+    .bind(a.max_draws_total)
+    .bind(a.max_draws_daily)
+    .bind(a.max_wins_total)
+    .bind(a.quota_timezone)
+    .bind(a.selection.prize_selector)
+    .bind(a.selection.probability_unit)
+    .bind(a.selection.no_win_weight)
+    .bind(a.selection.sold_out_policy)
+    .bind(a.provably_fair)
+    .execute(&mut *tx)
+    .await?;
+    pity_service::insert_rules(&mut *tx, id, &a.pity_rules).await?;
+    if a.provably_fair {
+        fair_service::ensure_seed(&mut tx, id).await?;
+    }
+    tx.commit().await
+}
+
+/// Fields to change on an activity; None keeps the current value. The quota limits take `Some(None)` to
+/// remove a limit. `provably_fair` cannot change once the activity exists, its seed is already committed.
+#[derive(Debug, Default)]
+pub struct ActivityPatch {
+    pub name: Option<String>,
+    pub description: Option<Option<String>>,
+    pub start_time: Option<DateTime<Utc>>,
+    pub end_time: Option<DateTime<Utc>>,
+    pub status: Option<ActivityStatus>,
+    pub draw_cooldown_secs: Option<i32>,
+    pub max_draws_total: Option<Option<i32>>,
+    pub max_draws_daily: Option<Option<i32>>,
+    pub max_wins_total: Option<Option<i32>>,
+    pub quota_timezone: Option<String>,
+    pub prize_selector: Option<PrizeSelectorKind>,
+    pub probability_unit: Option<ProbabilityUnit>,
+    pub no_win_weight: Option<i32>,
+    pub sold_out_policy: Option<SoldOutPolicy>,
+    /// replaces all pity rules of the activity
+    pub pity_rules: Option<Vec<PityRule>>,
+}
+
+/// Applies `patch` and returns the updated activity; None if it does not exist. A provably fair activity
+/// whose seed is public keeps its status and times; a new probability unit must still hold the enabled prizes.
+pub async fn update_activity(pool: &PgPool, id: Uuid, patch: ActivityPatch) -> Result<Option<Activity>, AppError> {
+    let mut tx = pool.begin().await?;
+    let Some(mut a) = lock_activity(&mut tx, id).await? else { return Ok(None) };
+    let reweighs = patch.probability_unit.is_some_and(|v| v != a.probability_unit)
+        || patch.no_win_weight.is_some_and(|v| v != a.no_win_weight);
+    let reschedules = patch.status.is_some_and(|v| v != a.status)
+        || patch.start_time.is_some_and(|v| v != a.start_time)
+        || patch.end_time.is_some_and(|v| v != a.end_time);
+    if a.provably_fair && reschedules && fair_service::seed_public(&mut tx, id, a.status, a.end_time).await? {
+        return Err(AppError::conflict(Reason::FairSeedPublic, "公平抽奖活动已结束并公开种子，不能再修改状态或时间"));
+    }
+    if let Some(v) = patch.name { a.name = v; }
+    if let Some(v) = patch.description { a.description = v; }
+    if let Some(v) = patch.start_time { a.start_time = v; }
+    if let Some(v) = patch.end_time { a.end_time = v; }
+    if let Some(v) = patch.status { a.status = v; }
+    if let Some(v) = patch.draw_cooldown_secs { a.draw_cooldown_secs = v; }
+    if let Some(v) = patch.max_draws_total { a.max_draws_total = v; }
+    if let Some(v) = patch.max_draws_daily { a.max_draws_daily = v; }
+    if let Some(v) = patch.max_wins_total { a.max_wins_total = v; }
+    if let Some(v) = patch.quota_timezone { a.quota_timezone = v; }
+    if let Some(v) = patch.prize_selector { a.prize_selector = v; }
+    if let Some(v) = patch.probability_unit { a.probability_unit = v; }
+    if let Some(v) = patch.no_win_weight { a.no_win_weight = v; }
+    if let Some(v) = patch.sold_out_policy { a.sold_out_policy = v; }
+    let mut v = Validator::new();
+    v.check(a.end_time > a.start_time, "end_time", "end_before_start");
+    v.finish()?;
+    let updated = sqlx::query_as::<_, Activity>(
+        r#"UPDATE activities SET name=$2, description=$3, start_time=$4, end_time=$5, status=$6, draw_cooldown_secs=$7,
+                  max_draws_total=$8, max_draws_daily=$9, max_wins_total=$10, quota_timezone=$11,
+                  prize_selector=$12, probability_unit=$13, no_win_weight=$14, sold_out_policy=$15, updated_at=now()
+           WHERE id=$1
+           RETURNING id, name, description, start_time, end_time, status, draw_cooldown_secs,
+                     max_draws_total, max_draws_daily, max_wins_total, quota_timezone,
+                     prize_selector, probability_unit, no_win_weight, sold_out_policy, provably_fair, created_at, updated_at"#
     )
     .bind(id)
-    .bind(name)
-    .bind(description)
-    .bind(start_time)
-    .bind(end_time)
-    .bind(status)
-    .execute(pool)
+    .bind(a.name)
+    .bind(a.description)
+    .bind(a.start_time)
+    .bind(a.end_time)
+    .bind(a.status)
+    .bind(a.draw_cooldown_secs)
+    .bind(a.max_draws_total)
+    .bind(a.max_draws_daily)
+    .bind(a.max_wins_total)
+    .bind(a.quota_timezone)
+    .bind(a.prize_selector)
+    .bind(a.probability_unit)
+    .bind(a.no_win_weight)
+    .bind(a.sold_out_policy)
+    .fetch_one(&mut *tx)
     .await?;
-    Ok(())
+    if reweighs {
+        if let Some(budget) = prize_service::probability_budget(&mut *tx, id, None).await? {
+            let mut v = Validator::new();
+            v.check(budget.fits(0), "probability_unit", budget.over_code());
+            v.finish()?;
+        }
+    }
+    if let Some(rules) = patch.pity_rules {
+        sqlx::query("DELETE FROM activity_pity_rules WHERE activity_id=$1").bind(id).execute(&mut *tx).await?;
+        pity_service::insert_rules(&mut *tx, id, &rules).await?;
+    }
+    tx.commit().await?;
+    Ok(Some(updated))
+}
+
+/// Deletes an activity and its prizes; draw records keep their prize names. Returns the ids of the deleted
+/// prizes (for cleaning up their Redis keys), None if the activity does not exist.
+pub async fn delete_activity(pool: &PgPool, id: Uuid) -> Result<Option<Vec<Uuid>>, AppError> {
+    let mut tx = pool.begin().await?;
+    let Some(a) = lock_activity(&mut tx, id).await? else { return Ok(None) };
+    if a.status == ActivityStatus::Ongoing {
+        return Err(AppError::conflict(Reason::ActivityOngoing, "活动进行中，请先暂停或结束"));
+    }
+    let prize_ids: Vec<Uuid> = sqlx::query_scalar("SELECT id FROM prizes WHERE activity_id=$1")
+        .bind(id)
+        .fetch_all(&mut *tx)
+        .await?;
+    sqlx::query("DELETE FROM activities WHERE id=$1").bind(id).execute(&mut *tx).await?;
+    tx.commit().await?;
+    Ok(Some(prize_ids))
 }
@@ -0,0 +1,99 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::types::Uuid;
+use sqlx::PgPool;
+
+use crate::error::{AppError, Reason};
+use crate::models::AdminRole;
+
+#[derive(Serialize, sqlx::FromRow, Debug, Clone)]
+pub struct AdminUser {
+    pub id: Uuid,
+    pub username: String,
+    pub role: AdminRole,
+    pub is_active: bool,
+    pub last_login_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(sqlx::FromRow, Debug, Clone)]
+pub struct AdminCredentials {
+    pub id: Uuid,
+    pub password_hash: String,
+    pub role: AdminRole,
+    pub is_active: bool,
+}
+
+const ADMIN_COLUMNS: &str = "id, username, role, is_active, last_login_at, created_at, updated_at";
+
+pub async fn create_admin(pool: &PgPool, id: Uuid, username: &str, password_hash: &str, role: AdminRole) -> sqlx::Result<AdminUser> {
+    sqlx::query_as::<_, AdminUser>(&format!(
+        r#"INSERT INTO admin_users (id, username, password_hash, role, is_active, created_at, updated_at)
+           VALUES ($1,$2,$3,$4, true, now(), now()) RETURNING {}"#,
+        ADMIN_COLUMNS
+    ))
+    .bind(id)
+    .bind(username)
+    .bind(password_hash)
+    .bind(role)
+    .fetch_one(pool)
+    .await
+}
+
+pub async fn find_admin_credentials(pool: &PgPool, username: &str) -> sqlx::Result<Option<AdminCredentials>> {
+    sqlx::query_as::<_, AdminCredentials>("SELECT id, password_hash, role, is_active FROM admin_users WHERE username=$1")
+        .bind(username)
+        .fetch_optional(pool)
+        .await
+}
+
+/// Current role of an active admin; None once the admin was disabled or removed.
+pub async fn active_role(pool: &PgPool, id: Uuid) -> sqlx::Result<Option<AdminRole>> {
+    sqlx::query_scalar("SELECT role FROM admin_users WHERE id=$1 AND is_active")
+        .bind(id)
+        .fetch_optional(pool)
+        .await
+}
+
+pub async fn touch_login(pool: &PgPool, id: Uuid) -> sqlx::Result<()> {
+    sqlx::query("UPDATE admin_users SET last_login_at = now() WHERE id=$1")
+        .bind(id)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+pub async fn list_admins(pool: &PgPool) -> sqlx::Result<Vec<AdminUser>> {
+    sqlx::query_as::<_, AdminUser>(&format!("SELECT {} FROM admin_users ORDER BY created_at", ADMIN_COLUMNS))
+        .fetch_all(pool)
+        .await
+}
+
+/// Changes role and/or active flag; None if there is no such admin. The last active superadmin cannot be
+/// demoted or deactivated, so nobody is locked out.
+pub async fn update_admin(pool: &PgPool, id: Uuid, role: Option<AdminRole>, is_active: Option<bool>) -> Result<Option<AdminUser>, AppError> {
+    let mut tx = pool.begin().await?;
+    let demotes = role.is_some_and(|r| r != AdminRole::Superadmin) || is_active == Some(false);
+    if demotes {
+        // locking every active superadmin makes concurrent demotions count one after another
+        let superadmins: Vec<Uuid> = sqlx::query_scalar("SELECT id FROM admin_users WHERE role='superadmin' AND is_active ORDER BY id FOR UPDATE")
+            .fetch_all(&mut *tx)
+            .await?;
+        if superadmins == [id] {
+            return Err(AppError::conflict(Reason::LastSuperadmin, "至少需要保留一个超级管理员"));
+        }
+    }
+    let admin = sqlx::query_as::<_, AdminUser>(&format!(
+        r#"UPDATE admin_users SET role = COALESCE($2, role), is_active = COALESCE($3, is_active), updated_at = now()
+           WHERE id=$1 RETURNING {}"#,
+        ADMIN_COLUMNS
+    ))
+    .bind(id)
+    .bind(role)
+    .bind(is_active)
+    .fetch_optional(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    Ok(admin)
+}
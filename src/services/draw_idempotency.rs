@@ -0,0 +1,115 @@
+use redis::aio::ConnectionManager as RedisManager;
+use sqlx::{types::Uuid, Postgres, Transaction};
+
+use crate::error::{AppError, Reason};
+
+// Idempotency-Key support for POST /api/lottery/draw: the serialized response of the first successful
+// draw is stored per (user, key) and returned verbatim to retries within the retention window.
+pub const RETENTION_SECS: i64 = 24 * 3600;
+// upper bound for one draw; a crashed request frees its key after this long
+const IN_FLIGHT_SECS: i64 = 30;
+const MAX_KEY_LEN: usize = 255;
+
+pub fn validate_key(key: &str) -> Result<(), AppError> {
+    if key.is_empty() || key.len() > MAX_KEY_LEN || !key.bytes().all(|b| b.is_ascii_graphic()) {
+        return Err(AppError::bad_request(Reason::InvalidIdempotencyKey, "Idempotency-Key 不合法"));
+    }
+    Ok(())
+}
+
+/// Outcome of claiming a key before drawing.
+pub enum Claim {
+    /// the key is ours: draw, then `complete` (or `release` on error)
+    Acquired,
+    /// an earlier request already drew; replay its body
+    Replay(String),
+}
+
+fn redis_key(uid: Uuid, key: &str) -> String {
+    format!("lottery:idem:{}:{}", uid, key)
+}
+
+// stored value: "pending|{activity}" while the first request runs, "done|{activity}|{body}" afterwards
+fn replay(activity_id: Uuid, stored: &str) -> Result<Claim, AppError> {
+    let mut parts = stored.splitn(3, '|');
+    let state = parts.next().unwrap_or_default();
+    if parts.next() != Some(activity_id.to_string().as_str()) {
+        return Err(AppError::bad_request(Reason::IdempotencyKeyReused, "Idempotency-Key 已用于其他请求"));
+    }
+    match (state, parts.next()) {
+        ("done", Some(body)) => Ok(Claim::Replay(body.to_string())),
+        _ => Err(AppError::conflict(Reason::IdempotencyKeyInProgress, "相同 Idempotency-Key 的请求正在处理中")),
+    }
+}
+
+fn redis_err(e: redis::RedisError) -> AppError {
+    tracing::error!(error = ?e, "idempotency redis error");
+    AppError::Internal("redis error")
+}
+
+pub async fn claim_redis(redis: &mut RedisManager, uid: Uuid, activity_id: Uuid, key: &str) -> Result<Claim, AppError> {
+    let k = redis_key(uid, key);
+    // two attempts: the stored value may expire between SET NX and GET
+    for _ in 0..2 {
+        let set: Option<String> = redis::cmd("SET")
+            .arg(&k).arg(format!("pending|{}", activity_id)).arg("NX").arg("EX").arg(IN_FLIGHT_SECS)
+            .query_async(redis)
+            .await
+            .map_err(redis_err)?;
+        if set.is_some() {
+            return Ok(Claim::Acquired);
+        }
+        let stored: Option<String> = redis::cmd("GET").arg(&k).query_async(redis).await.map_err(redis_err)?;
+        if let Some(stored) = stored {
+            return replay(activity_id, &stored);
+        }
+    }
+    Err(AppError::conflict(Reason::IdempotencyKeyInProgress, "相同 Idempotency-Key 的请求正在处理中"))
+}
+
+pub async fn complete_redis(redis: &mut RedisManager, uid: Uuid, activity_id: Uuid, key: &str, body: &str) -> Result<(), AppError> {
+    redis::cmd("SET")
+        .arg(redis_key(uid, key)).arg(format!("done|{}|{}", activity_id, body)).arg("EX").arg(RETENTION_SECS)
+        .query_async::<_, ()>(redis)
+        .await
+        .map_err(redis_err)
+}
+
+pub async fn release_redis(redis: &mut RedisManager, uid: Uuid, key: &str) {
+    let _: redis::RedisResult<()> = redis::cmd("DEL").arg(redis_key(uid, key)).query_async(redis).await;
+}
+
+/// SQL path: must run inside the draw transaction after the user row is locked, which serializes retries.
+pub async fn claim_sql(tx: &mut Transaction<'_, Postgres>, uid: Uuid, activity_id: Uuid, key: &str) -> Result<Claim, AppError> {
+    sqlx::query("DELETE FROM draw_idempotency_keys WHERE user_id=$1 AND created_at < now() - make_interval(secs => $2)")
+        .bind(uid)
+        .bind(RETENTION_SECS as f64)
+        .execute(&mut **tx)
+        .await?;
+    let stored: Option<(Uuid, String)> = sqlx::query_as(
+        "SELECT activity_id, response FROM draw_idempotency_keys WHERE user_id=$1 AND idem_key=$2"
+    )
+    .bind(uid)
+    .bind(key)
+    .fetch_optional(&mut **tx)
+    .await?;
+    match stored {
+        None => Ok(Claim::Acquired),
+        Some((aid, _)) if aid != activity_id => Err(AppError::bad_request(Reason::IdempotencyKeyReused, "Idempotency-Key 已用于其他请求")),
+        Some((_, body)) => Ok(Claim::Replay(body)),
+    }
+}
+
+pub async fn complete_sql(tx: &mut Transaction<'_, Postgres>, uid: Uuid, activity_id: Uuid, key: &str, body: &str) -> Result<(), AppError> {
+    sqlx::query(
+        r#"INSERT INTO draw_idempotency_keys (user_id, idem_key, activity_id, response, created_at)
+           VALUES ($1,$2,$3,$4, now())"#
+    )
+    .bind(uid)
+    .bind(key)
+    .bind(activity_id)
+    .bind(body)
+    .execute(&mut **tx)
+    .await?;
+    Ok(())
+}
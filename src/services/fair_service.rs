@@ -0,0 +1,436 @@
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::{types::Uuid, PgConnection, PgExecutor, PgPool};
+
+use crate::error::{AppError, Reason};
+use crate::models::{ActivityStatus, PrizeSelectorKind, ProbabilityUnit, SoldOutPolicy};
+use crate::services::prize_cache;
+use crate::services::prize_selector::{PrizeSelector, SelectionConfig};
+use crate::services::lottery_service;
+
+// Provably fair draws (commit-reveal). Each provably fair activity has a secret server seed whose SHA-256 is
+// published while the activity runs; every draw takes a client seed from the user and a per-user nonce, and
+// its selection roll is HMAC-SHA256(key = server seed, message = "{client_seed}:{nonce}"), first 8 bytes as a
+// big-endian u64, top 53 bits divided by 2^53. Once the activity has ended the seed is revealed and anyone can
+// recompute the roll of a record. The other random picks of a draw come from the same HMAC with the message
+// "{client_seed}:{nonce}:{step}" (see `RollStep`), and the record stores what else decided the outcome (pity
+// guarantee, sold-out policy, wins quota, candidates out of stock) so verification replays the whole draw.
+// Each draw also references a stored snapshot of the prize table it was rolled against, so editing prizes later
+// cannot change what a past roll selects. Nonces come from `fair_nonces` on both draw paths.
+
+pub const MAX_CLIENT_SEED_LEN: usize = 64;
+
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct FairSeed {
+    pub server_seed: String,
+    pub server_seed_hash: String,
+}
+
+/// What a user needs to check a draw: stored with the record and returned with the draw result.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct FairInputs {
+    pub server_seed_hash: String,
+    pub client_seed: String,
+    pub nonce: i64,
+    /// sha256 of the selection snapshot the roll is mapped onto
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub selection_hash: Option<String>,
+}
+
+impl FairInputs {
+    /// "{hash}:{selection_hash}:{nonce}:{client_seed}" as carried in the record outbox
+    pub fn encode(&self) -> String {
+        format!("{}:{}:{}:{}", self.server_seed_hash, self.selection_hash.as_deref().unwrap_or_default(), self.nonce, self.client_seed)
+    }
+
+    pub fn decode(s: &str) -> Option<Self> {
+        let (server_seed_hash, rest) = s.split_once(':')?;
+        // entries written before selection snapshots go straight to the nonce
+        let (selection_hash, rest) = match rest.split_once(':')? {
+            (nonce, _) if nonce.parse::<i64>().is_ok() => (None, rest),
+            (hash, rest) => (Some(hash).filter(|h| !h.is_empty()), rest),
+        };
+        let (nonce, client_seed) = rest.split_once(':')?;
+        Some(Self {
+            server_seed_hash: server_seed_hash.to_string(),
+            client_seed: client_seed.to_string(),
+            nonce: nonce.parse().ok()?,
+            selection_hash: selection_hash.map(str::to_string),
+        })
+    }
+}
+
+/// What the roll of a provably fair draw is mapped onto: the selection settings and the enabled prizes in
+/// selection order with their probability, and their tiers for pity guarantees and downgrades.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SelectionSnapshot {
+    pub prize_selector: PrizeSelectorKind,
+    pub probability_unit: ProbabilityUnit,
+    pub no_win_weight: i32,
+    pub prizes: Vec<(Uuid, i32)>,
+    /// empty in snapshots stored before tiers were
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tiers: Vec<i32>,
+}
+
+impl SelectionSnapshot {
+    /// `prizes` as (id, probability, tier)
+    pub fn new(selection: &SelectionConfig, prizes: impl IntoIterator<Item = (Uuid, i32, i32)>) -> Self {
+        let (prizes, tiers) = prizes.into_iter().map(|(id, probability, tier)| ((id, probability), tier)).unzip();
+        SelectionSnapshot {
+            prize_selector: selection.prize_selector,
+            probability_unit: selection.probability_unit,
+            no_win_weight: selection.no_win_weight,
+            prizes,
+            tiers,
+        }
+    }
+
+    fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("selection snapshots serialize")
+    }
+
+    /// sha256 of the stored JSON, hex
+    pub fn hash(&self) -> String {
+        hash_seed(&self.to_json())
+    }
+
+    fn selector(&self) -> std::sync::Arc<dyn PrizeSelector> {
+        let config = SelectionConfig {
+            prize_selector: self.prize_selector,
+            probability_unit: self.probability_unit,
+            no_win_weight: self.no_win_weight,
+            ..Default::default()
+        };
+        let probabilities: Vec<i32> = self.prizes.iter().map(|p| p.1).collect();
+        config.build(&probabilities)
+    }
+
+    /// The prize a roll selects; None when it lands on no-win.
+    pub fn select(&self, roll: f64) -> Option<Uuid> {
+        self.selector().select(roll).and_then(|i| self.prizes.get(i)).map(|p| p.0)
+    }
+
+    /// The prize a draw ended on, replayed from its rolls and recorded steps the way the draw paths resolve
+    /// it; None is a loss.
+    pub fn replay(&self, steps: &FairSteps, roll: impl Fn(RollStep) -> f64) -> Option<Uuid> {
+        if !steps.may_win {
+            return None;
+        }
+        let weights: Vec<(i32, i32)> =
+            self.prizes.iter().enumerate().map(|(i, p)| (self.tiers.get(i).copied().unwrap_or(1), p.1)).collect();
+        let (candidates, policy) = lottery_service::draw_candidates(steps.policy, &*self.selector(), &weights, steps.forced_tier, &roll);
+        let in_stock = |i: usize| !steps.sold_out.contains(&self.prizes[i].0);
+        lottery_service::resolve_in_stock(&candidates, policy, in_stock, |i| self.prizes[i].1.max(0) as u64, roll(RollStep::Redraw))
+            .map(|i| self.prizes[i].0)
+    }
+}
+
+/// Stores a selection snapshot unless it already is; returns its hash. A stored snapshot costs a read only.
+pub async fn save_selection(conn: &mut PgConnection, activity_id: Uuid, snapshot: &SelectionSnapshot) -> sqlx::Result<String> {
+    let json = snapshot.to_json();
+    let hash = hash_seed(&json);
+    let stored: bool = sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM fair_selections WHERE activity_id=$1 AND selection_hash=$2)")
+        .bind(activity_id)
+        .bind(&hash)
+        .fetch_one(&mut *conn)
+        .await?;
+    if !stored {
+        sqlx::query(
+            r#"INSERT INTO fair_selections (activity_id, selection_hash, selection, created_at)
+               VALUES ($1,$2,$3, now()) ON CONFLICT (activity_id, selection_hash) DO NOTHING"#
+        )
+        .bind(activity_id)
+        .bind(&hash)
+        .bind(json)
+        .execute(&mut *conn)
+        .await?;
+    }
+    Ok(hash)
+}
+
+/// How a provably fair draw went on from its selection roll, stored with the record for verification.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct FairSteps {
+    /// tier a pity rule guaranteed; its prize is picked with `RollStep::Pity` instead of the selection
+    pub forced_tier: Option<i32>,
+    /// sold-out policy applied; a redraw picks with `RollStep::Redraw`
+    pub policy: SoldOutPolicy,
+    /// false when the wins quota turned the draw into a loss
+    pub may_win: bool,
+    /// candidates found out of stock
+    pub sold_out: Vec<Uuid>,
+}
+
+impl FairSteps {
+    /// "{forced_tier}:{policy}", what `LUA_DRAW` is given; it appends ":{may_win 1|0}:{sold-out ids}"
+    pub fn encode_choice(forced_tier: Option<i32>, policy: SoldOutPolicy) -> String {
+        format!("{}:{}", forced_tier.map(|t| t.to_string()).unwrap_or_default(), policy.as_str())
+    }
+
+    /// Parses the outbox form written by `LUA_DRAW`.
+    pub fn decode(s: &str) -> Option<Self> {
+        let mut parts = s.splitn(4, ':');
+        let forced_tier = match parts.next()? {
+            "" => None,
+            t => Some(t.parse().ok()?),
+        };
+        let policy = match parts.next()? {
+            "lose" => SoldOutPolicy::Lose,
+            "redraw" => SoldOutPolicy::Redraw,
+            "downgrade" => SoldOutPolicy::Downgrade,
+            _ => return None,
+        };
+        let may_win = parts.next()? == "1";
+        let sold_out = parts.next()?.split(',').filter(|id| !id.is_empty()).map(Uuid::parse_str).collect::<Result<_, _>>().ok()?;
+        Some(Self { forced_tier, policy, may_win, sold_out })
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("fair steps serialize")
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Commitment {
+    pub activity_id: Uuid,
+    pub server_seed_hash: String,
+    /// only once the activity has ended
+    pub server_seed: Option<String>,
+    pub revealed_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Verification {
+    pub record_id: Uuid,
+    pub activity_id: Uuid,
+    pub server_seed: String,
+    pub server_seed_hash: String,
+    /// sha256(server_seed) equals the hash committed to before the draw
+    pub hash_matches: bool,
+    pub client_seed: String,
+    pub nonce: i64,
+    pub roll: f64,
+    /// the prize table the draw was rolled against; None for draws made before snapshots existed
+    pub selection_hash: Option<String>,
+    pub selection: Option<SelectionSnapshot>,
+    /// the prize the roll selects from that table, or from the current one without a snapshot
+    pub selected_prize_id: Option<Uuid>,
+    /// what decided the outcome after the selection; None for draws made before steps were recorded
+    pub steps: Option<FairSteps>,
+    /// the prize replaying `steps` ends on; the selected prize without steps
+    pub outcome_prize_id: Option<Uuid>,
+    pub recorded_prize_id: Option<Uuid>,
+    /// the outcome equals the recorded prize
+    pub matches: bool,
+}
+
+pub fn hash_seed(server_seed: &str) -> String {
+    hex::encode(Sha256::digest(server_seed.as_bytes()))
+}
+
+/// The random picks of a draw; each has its own roll from the seeds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RollStep {
+    /// the prize selection
+    Select = 0,
+    /// the prize a pity rule guarantees
+    Pity = 1,
+    /// the pick among in-stock prizes after a sold-out one
+    Redraw = 2,
+}
+
+/// HMAC roll of `step`; the selection keeps the message "{client_seed}:{nonce}" it had before the other steps.
+pub fn roll(server_seed: &str, client_seed: &str, nonce: i64, step: RollStep) -> f64 {
+    let mut mac = Hmac::<Sha256>::new_from_slice(server_seed.as_bytes()).expect("hmac accepts any key length");
+    match step {
+        RollStep::Select => mac.update(format!("{}:{}", client_seed, nonce).as_bytes()),
+        _ => mac.update(format!("{}:{}:{}", client_seed, nonce, step as u8).as_bytes()),
+    }
+    let out = mac.finalize().into_bytes();
+    let mut head = [0u8; 8];
+    head.copy_from_slice(&out[..8]);
+    (u64::from_be_bytes(head) >> 11) as f64 / (1u64 << 53) as f64
+}
+
+pub fn validate_client_seed(seed: &str) -> Result<(), AppError> {
+    if seed.is_empty() || seed.len() > MAX_CLIENT_SEED_LEN || !seed.bytes().all(|b| b.is_ascii_graphic()) {
+        return Err(AppError::bad_request(Reason::InvalidClientSeed, "client_seed 不合法"));
+    }
+    Ok(())
+}
+
+/// Client seed for a draw: the user's, or a random one when none was given.
+pub fn client_seed_or_random(seed: Option<&str>) -> Result<String, AppError> {
+    match seed {
+        Some(s) => validate_client_seed(s).map(|_| s.to_string()),
+        None => {
+            let mut bytes = [0u8; 8];
+            rand::thread_rng().fill_bytes(&mut bytes);
+            Ok(hex::encode(bytes))
+        }
+    }
+}
+
+pub async fn seed_of<'e, E: PgExecutor<'e>>(ex: E, activity_id: Uuid) -> sqlx::Result<Option<FairSeed>> {
+    sqlx::query_as::<_, FairSeed>("SELECT server_seed, server_seed_hash FROM activity_fair_seeds WHERE activity_id=$1")
+        .bind(activity_id)
+        .fetch_optional(ex)
+        .await
+}
+
+/// Returns the activity's seed, creating it on first use.
+pub async fn ensure_seed(conn: &mut PgConnection, activity_id: Uuid) -> sqlx::Result<FairSeed> {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    let seed = hex::encode(bytes);
+    sqlx::query(
+        r#"INSERT INTO activity_fair_seeds (activity_id, server_seed, server_seed_hash, created_at)
+           VALUES ($1,$2,$3, now()) ON CONFLICT (activity_id) DO NOTHING"#
+    )
+    .bind(activity_id)
+    .bind(&seed)
+    .bind(hash_seed(&seed))
+    .execute(&mut *conn)
+    .await?;
+    Ok(seed_of(&mut *conn, activity_id).await?.expect("seed row exists after insert"))
+}
+
+/// Next nonce of a user in an activity; one counter for both draw paths, so switching backends never repeats one.
+pub async fn next_nonce(conn: &mut PgConnection, activity_id: Uuid, uid: Uuid) -> sqlx::Result<i64> {
+    sqlx::query_scalar(
+        r#"INSERT INTO fair_nonces (user_id, activity_id, last_nonce) VALUES ($1,$2,1)
+           ON CONFLICT (user_id, activity_id) DO UPDATE SET last_nonce = fair_nonces.last_nonce + 1
+           RETURNING last_nonce"#
+    )
+    .bind(uid)
+    .bind(activity_id)
+    .fetch_one(conn)
+    .await
+}
+
+fn revealable(status: ActivityStatus, end_time: DateTime<Utc>) -> bool {
+    status == ActivityStatus::Ended || end_time < Utc::now()
+}
+
+/// Whether the seed of a provably fair activity is or may be public: it was revealed, or the activity ended.
+/// Such an activity must not take draws again, their rolls could be predicted.
+pub async fn seed_public(conn: &mut PgConnection, activity_id: Uuid, status: ActivityStatus, end_time: DateTime<Utc>) -> sqlx::Result<bool> {
+    if revealable(status, end_time) {
+        return Ok(true);
+    }
+    let revealed_at: Option<Option<DateTime<Utc>>> = sqlx::query_scalar("SELECT revealed_at FROM activity_fair_seeds WHERE activity_id=$1")
+        .bind(activity_id)
+        .fetch_optional(conn)
+        .await?;
+    Ok(revealed_at.flatten().is_some())
+}
+
+#[derive(sqlx::FromRow)]
+struct SeedRow {
+    status: ActivityStatus,
+    end_time: DateTime<Utc>,
+    server_seed: String,
+    server_seed_hash: String,
+    revealed_at: Option<DateTime<Utc>>,
+}
+
+/// The published commitment of an activity; the seed itself once the activity has ended.
+pub async fn commitment(pool: &PgPool, activity_id: Uuid) -> Result<Commitment, AppError> {
+    let SeedRow { status, end_time, server_seed: seed, server_seed_hash: hash, mut revealed_at } = sqlx::query_as(
+        r#"SELECT a.status, a.end_time, s.server_seed, s.server_seed_hash, s.revealed_at
+           FROM activity_fair_seeds s JOIN activities a ON a.id = s.activity_id WHERE s.activity_id=$1"#
+    )
+    .bind(activity_id)
+    .fetch_optional(pool)
+    .await?
+    .ok_or(AppError::NotFound)?;
+    let reveal = revealable(status, end_time);
+    if reveal && revealed_at.is_none() {
+        revealed_at = sqlx::query_scalar(
+            "UPDATE activity_fair_seeds SET revealed_at = COALESCE(revealed_at, now()) WHERE activity_id=$1 RETURNING revealed_at"
+        )
+        .bind(activity_id)
+        .fetch_one(pool)
+        .await?;
+    }
+    Ok(Commitment { activity_id, server_seed_hash: hash, server_seed: reveal.then_some(seed), revealed_at })
+}
+
+#[derive(sqlx::FromRow)]
+struct FairRecordRow {
+    activity_id: Uuid,
+    prize_id: Option<Uuid>,
+    server_seed_hash: String,
+    client_seed: String,
+    nonce: i64,
+    selection_hash: Option<String>,
+    fair_steps: Option<String>,
+}
+
+/// Recomputes the roll of a record of an ended provably fair activity.
+pub async fn verify_record(pool: &PgPool, record_id: Uuid) -> Result<Verification, AppError> {
+    let rec = sqlx::query_as::<_, FairRecordRow>(
+        r#"SELECT activity_id, prize_id, server_seed_hash, client_seed, nonce, selection_hash, fair_steps FROM lottery_records
+           WHERE id=$1 AND activity_id IS NOT NULL AND nonce IS NOT NULL"#
+    )
+    .bind(record_id)
+    .fetch_optional(pool)
+    .await?
+    .ok_or(AppError::NotFound)?;
+    let Some(server_seed) = commitment(pool, rec.activity_id).await?.server_seed else {
+        return Err(AppError::bad_request(Reason::SeedNotRevealed, "活动结束后才能验证"));
+    };
+    let roll_of = |step| roll(&server_seed, &rec.client_seed, rec.nonce, step);
+    let roll = roll_of(RollStep::Select);
+    let selection = match &rec.selection_hash {
+        Some(hash) => {
+            let json: String = sqlx::query_scalar("SELECT selection FROM fair_selections WHERE activity_id=$1 AND selection_hash=$2")
+                .bind(rec.activity_id)
+                .bind(hash)
+                .fetch_optional(pool)
+                .await?
+                .ok_or(AppError::Internal("selection snapshot missing"))?;
+            Some(serde_json::from_str::<SelectionSnapshot>(&json).map_err(|_| AppError::Internal("selection snapshot unreadable"))?)
+        }
+        None => None,
+    };
+    let table = match &selection {
+        Some(s) => s.clone(),
+        None => {
+            let mut conn = pool.acquire().await?;
+            let activity = prize_cache::fetch_activity(&mut conn, rec.activity_id).await?.ok_or(AppError::NotFound)?;
+            let prizes = prize_cache::fetch_activity_prizes(pool, &activity).await?;
+            SelectionSnapshot::new(&activity.selection, prizes.prizes.iter().map(|p| (p.id, p.probability, p.tier)))
+        }
+    };
+    let selected_prize_id = table.select(roll);
+    let steps = match &rec.fair_steps {
+        Some(json) => Some(serde_json::from_str::<FairSteps>(json).map_err(|_| AppError::Internal("fair steps unreadable"))?),
+        None => None,
+    };
+    let outcome_prize_id = match &steps {
+        Some(s) => table.replay(s, roll_of),
+        None => selected_prize_id,
+    };
+    Ok(Verification {
+        record_id,
+        activity_id: rec.activity_id,
+        hash_matches: hash_seed(&server_seed) == rec.server_seed_hash,
+        server_seed,
+        server_seed_hash: rec.server_seed_hash,
+        client_seed: rec.client_seed,
+        nonce: rec.nonce,
+        roll,
+        selection_hash: rec.selection_hash,
+        selection,
+        matches: outcome_prize_id == rec.prize_id,
+        selected_prize_id,
+        steps,
+        outcome_prize_id,
+        recorded_prize_id: rec.prize_id,
+    })
+}
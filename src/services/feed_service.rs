@@ -0,0 +1,243 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use futures_util::{future, Stream, StreamExt};
+use redis::aio::ConnectionManager as RedisManager;
+use serde::{Deserialize, Serialize};
+use sqlx::{types::Uuid, PgPool};
+use tokio::sync::broadcast;
+use tokio_util::sync::CancellationToken;
+
+use crate::state::Jobs;
+
+// Live feed of wins and stock changes for big screens. With the Redis backend events are published to the
+// channel `lottery:feed` and every instance relays what it receives to its own SSE and WebSocket clients, so
+// each client sees the wins of all instances; without Redis they go straight to the local clients. A win is
+// announced once it is persisted: by the outbox consumer with the Redis backend, after the draw's transaction
+// commits with the SQL backend. Usernames are masked before they leave the server.
+
+pub const CHANNEL: &str = "lottery:feed";
+/// events a slow client may fall behind before it skips the oldest
+const CAPACITY: usize = 1024;
+const RESUBSCRIBE_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum FeedEvent {
+    Win { activity_id: Uuid, prize_id: Uuid, prize_name: String, user: String, at: DateTime<Utc> },
+    /// stock left of a prize after a win or an admin change; 0 once deleted
+    Stock { activity_id: Uuid, prize_id: Uuid, remaining: i64 },
+}
+
+impl FeedEvent {
+    pub fn activity_id(&self) -> Uuid {
+        match self {
+            FeedEvent::Win { activity_id, .. } | FeedEvent::Stock { activity_id, .. } => *activity_id,
+        }
+    }
+
+    /// SSE event name
+    pub fn kind(&self) -> &'static str {
+        match self {
+            FeedEvent::Win { .. } => "win",
+            FeedEvent::Stock { .. } => "stock",
+        }
+    }
+}
+
+/// A persisted winning draw to announce.
+#[derive(Debug, Clone)]
+pub struct Win {
+    pub user_id: Uuid,
+    pub activity_id: Uuid,
+    pub prize_id: Uuid,
+    pub prize_name: String,
+    pub at: DateTime<Utc>,
+}
+
+/// Keeps the first and last character: "alice" -> "a***e", "张三" -> "张*". The number of stars does not
+/// reveal the length.
+pub fn mask_username(name: &str) -> String {
+    let chars: Vec<char> = name.chars().collect();
+    match chars.as_slice() {
+        [] | [_] => "*".to_string(),
+        [first, _] => format!("{}*", first),
+        [first, .., last] => format!("{}***{}", first, last),
+    }
+}
+
+/// Cheap to clone; clones share the subscribers.
+#[derive(Clone)]
+pub struct LiveFeed(Arc<FeedInner>);
+
+struct FeedInner {
+    local: broadcast::Sender<Arc<FeedEvent>>,
+    /// publishes to the channel when set, else delivers locally
+    redis: Option<RedisManager>,
+    closed: CancellationToken,
+}
+
+impl LiveFeed {
+    pub fn new(redis: Option<RedisManager>) -> Self {
+        let (local, _) = broadcast::channel(CAPACITY);
+        LiveFeed(Arc::new(FeedInner { local, redis, closed: CancellationToken::new() }))
+    }
+
+    /// Events of one activity (all when None) from now on; ends when the feed is closed.
+    pub fn subscribe(&self, activity_id: Option<Uuid>) -> impl Stream<Item = Arc<FeedEvent>> + Send + 'static {
+        let events = futures_util::stream::unfold(self.0.local.subscribe(), |mut rx| async move {
+            loop {
+                match rx.recv().await {
+                    Ok(event) => return Some((event, rx)),
+                    // a slow client misses the oldest events instead of holding everyone up
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        });
+        events
+            .filter(move |e| future::ready(activity_id.is_none_or(|id| e.activity_id() == id)))
+            .take_until(self.0.closed.clone().cancelled_owned())
+    }
+
+    /// Ends every open stream, so graceful shutdown does not wait for clients that never disconnect.
+    pub fn close(&self) {
+        self.0.closed.cancel();
+    }
+
+    fn deliver(&self, event: FeedEvent) {
+        // no subscribers is not an error
+        let _ = self.0.local.send(Arc::new(event));
+    }
+
+    /// Publishes the wins and the stock left of their prizes. Best effort: a failure is logged, never returned.
+    pub async fn announce_wins(&self, pool: &PgPool, wins: &[Win]) {
+        // without Redis nobody else could be listening
+        if wins.is_empty() || (self.0.redis.is_none() && self.0.local.receiver_count() == 0) {
+            return;
+        }
+        let events = match self.build_events(pool, wins).await {
+            Ok(events) => events,
+            Err(e) => {
+                tracing::warn!(error = ?e, "failed to build live feed events");
+                return;
+            }
+        };
+        self.publish(events).await;
+    }
+
+    /// Publishes the stock left of prizes an admin changed or deleted. Best effort like `announce_wins`.
+    pub async fn announce_stock(&self, pool: &PgPool, activity_id: Uuid, prize_ids: &[Uuid]) {
+        if prize_ids.is_empty() || (self.0.redis.is_none() && self.0.local.receiver_count() == 0) {
+            return;
+        }
+        match self.stock_left(pool, prize_ids).await {
+            Ok(remaining) => {
+                let events = prize_ids
+                    .iter()
+                    .map(|&prize_id| FeedEvent::Stock { activity_id, prize_id, remaining: remaining.get(&prize_id).copied().unwrap_or(0) })
+                    .collect();
+                self.publish(events).await;
+            }
+            Err(e) => tracing::warn!(error = ?e, "failed to build live feed events"),
+        }
+    }
+
+    async fn publish(&self, events: Vec<FeedEvent>) {
+        match self.0.redis.clone() {
+            Some(mut redis) => {
+                let mut pipe = redis::pipe();
+                for event in &events {
+                    pipe.cmd("PUBLISH").arg(CHANNEL).arg(serde_json::to_string(event).unwrap_or_default()).ignore();
+                }
+                if let Err(e) = pipe.query_async::<_, ()>(&mut redis).await {
+                    tracing::warn!(error = ?e, "failed to publish live feed events");
+                }
+            }
+            None => events.into_iter().for_each(|e| self.deliver(e)),
+        }
+    }
+
+    async fn build_events(&self, pool: &PgPool, wins: &[Win]) -> anyhow::Result<Vec<FeedEvent>> {
+        let user_ids: Vec<Uuid> = wins.iter().map(|w| w.user_id).collect();
+        let names: HashMap<Uuid, String> = sqlx::query_as::<_, (Uuid, String)>("SELECT id, username FROM users WHERE id = ANY($1)")
+            .bind(&user_ids)
+            .fetch_all(pool)
+            .await?
+            .into_iter()
+            .collect();
+        // last win per prize decides which activity its stock event belongs to
+        let prizes: HashMap<Uuid, Uuid> = wins.iter().map(|w| (w.prize_id, w.activity_id)).collect();
+        let prize_ids: Vec<Uuid> = prizes.keys().copied().collect();
+        let remaining = self.stock_left(pool, &prize_ids).await?;
+        let mut events: Vec<FeedEvent> = wins
+            .iter()
+            .map(|w| FeedEvent::Win {
+                activity_id: w.activity_id,
+                prize_id: w.prize_id,
+                prize_name: w.prize_name.clone(),
+                user: mask_username(names.get(&w.user_id).map(String::as_str).unwrap_or_default()),
+                at: w.at,
+            })
+            .collect();
+        for (prize_id, activity_id) in prizes {
+            if let Some(&remaining) = remaining.get(&prize_id) {
+                events.push(FeedEvent::Stock { activity_id, prize_id, remaining });
+            }
+        }
+        Ok(events)
+    }
+
+    /// Stock left per prize: the Redis stock with that backend, else (or without a stock key) Postgres.
+    /// Prizes that no longer exist are missing.
+    async fn stock_left(&self, pool: &PgPool, prize_ids: &[Uuid]) -> anyhow::Result<HashMap<Uuid, i64>> {
+        let mut remaining = HashMap::new();
+        if let Some(mut redis) = self.0.redis.clone() {
+            let keys: Vec<String> = prize_ids.iter().map(|id| format!("lottery:stock:{}", id)).collect();
+            let stock: Vec<Option<i64>> = redis::cmd("MGET").arg(&keys).query_async(&mut redis).await?;
+            remaining.extend(prize_ids.iter().zip(stock).filter_map(|(id, n)| Some((*id, n?))));
+        }
+        let missing: Vec<Uuid> = prize_ids.iter().filter(|id| !remaining.contains_key(*id)).copied().collect();
+        if !missing.is_empty() {
+            remaining.extend(
+                sqlx::query_as::<_, (Uuid, i64)>("SELECT id, remaining_count FROM prizes WHERE id = ANY($1)")
+                    .bind(&missing)
+                    .fetch_all(pool)
+                    .await?,
+            );
+        }
+        Ok(remaining)
+    }
+}
+
+/// Relays the events every instance publishes to the clients of this one; resubscribes after a lost
+/// connection. Runs with the Redis backend only.
+pub fn spawn_redis_relay(jobs: &Jobs, redis_url: String, feed: LiveFeed) {
+    let stop = jobs.stop_token();
+    jobs.spawn(async move {
+        loop {
+            let relay = async {
+                let mut pubsub = redis::Client::open(redis_url.as_str())?.get_async_connection().await?.into_pubsub();
+                pubsub.subscribe(CHANNEL).await?;
+                let mut messages = pubsub.on_message();
+                while let Some(msg) = messages.next().await {
+                    match serde_json::from_str::<FeedEvent>(&msg.get_payload::<String>()?) {
+                        Ok(event) => feed.deliver(event),
+                        Err(e) => tracing::warn!(error = ?e, "malformed live feed event"),
+                    }
+                }
+                Ok::<_, redis::RedisError>(())
+            };
+            tokio::select! {
+                res = relay => tracing::warn!(error = ?res.err(), "live feed subscription lost, resubscribing"),
+                _ = stop.cancelled() => break,
+            }
+            tokio::select! {
+                _ = tokio::time::sleep(RESUBSCRIBE_DELAY) => {}
+                _ = stop.cancelled() => break,
+            }
+        }
+    });
+}
@@ -0,0 +1,103 @@
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::state::StateData;
+
+// Readiness of one instance: it takes traffic only while Postgres and (with the Redis backend) Redis answer,
+// its background jobs keep up and the schema matches the migrations it was built with. A job is stale after
+// missing several of its configured runs.
+
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+/// the prize cache is stale after this many missed refreshes, and never younger than the minimum
+const CACHE_STALE_AFTER_RUNS: u32 = 10;
+const CACHE_STALE_MIN: Duration = Duration::from_secs(5);
+/// the same for the stock flusher, whose passes take longer
+const FLUSH_STALE_AFTER_RUNS: u32 = 6;
+const FLUSH_STALE_MIN: Duration = Duration::from_secs(30);
+
+static MIGRATOR: sqlx::migrate::Migrator = sqlx::migrate!("./migrations");
+
+#[derive(Serialize, Debug)]
+pub struct Check {
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub latency_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub age_secs: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_age_secs: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+impl Check {
+    fn ok() -> Self {
+        Check { ok: true, latency_ms: None, age_secs: None, max_age_secs: None, detail: None }
+    }
+
+    fn failed(detail: impl Into<String>) -> Self {
+        Check { ok: false, detail: Some(detail.into()), ..Check::ok() }
+    }
+
+    fn fresh(age: Option<f64>, max_age: Duration) -> Self {
+        let max = max_age.as_secs_f64();
+        match age {
+            Some(age) => Check { ok: age <= max, age_secs: Some(age), max_age_secs: Some(max), ..Check::ok() },
+            None => Check { max_age_secs: Some(max), ..Check::failed("not run yet") },
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct Readiness {
+    pub ready: bool,
+    pub checks: BTreeMap<&'static str, Check>,
+}
+
+/// Runs a connectivity probe with a timeout and records its latency.
+async fn probe<E: std::fmt::Display>(fut: impl Future<Output = Result<(), E>>) -> Check {
+    let started = Instant::now();
+    let check = match tokio::time::timeout(PROBE_TIMEOUT, fut).await {
+        Ok(Ok(())) => Check::ok(),
+        Ok(Err(e)) => Check::failed(e.to_string()),
+        Err(_) => Check::failed(format!("no answer within {:?}", PROBE_TIMEOUT)),
+    };
+    Check { latency_ms: Some(started.elapsed().as_millis() as u64), ..check }
+}
+
+async fn migrations(state: &StateData) -> Check {
+    let expected = MIGRATOR.iter().map(|m| m.version).max().unwrap_or(0);
+    let row: sqlx::Result<(Option<i64>, bool)> = sqlx::query_as(
+        "SELECT max(version) FILTER (WHERE success), bool_or(NOT success) IS TRUE FROM _sqlx_migrations"
+    )
+    .fetch_one(&state.pool)
+    .await;
+    match row {
+        Ok((_, true)) => Check::failed("a migration failed half-way"),
+        Ok((applied, false)) => {
+            let applied = applied.unwrap_or(0);
+            Check { ok: applied == expected, detail: Some(format!("applied {}, expected {}", applied, expected)), ..Check::ok() }
+        }
+        Err(e) => Check::failed(e.to_string()),
+    }
+}
+
+pub async fn readiness(state: &StateData) -> Readiness {
+    let cfg = state.cfg.get();
+    let mut checks = BTreeMap::new();
+    checks.insert("db", probe(async { sqlx::query("SELECT 1").execute(&state.pool).await.map(|_| ()) }).await);
+    checks.insert("migrations", migrations(state).await);
+    let refresh = Duration::from_millis(cfg.jobs.prize_cache_refresh_ms);
+    checks.insert("prize_cache", Check::fresh(state.metrics.prize_cache_age(), (refresh * CACHE_STALE_AFTER_RUNS).max(CACHE_STALE_MIN)));
+    if let Some(redis) = &state.redis {
+        let mut conn = redis.clone();
+        checks.insert("redis", probe(async move { redis::cmd("PING").query_async::<_, String>(&mut conn).await.map(|_| ()) }).await);
+        let flush = Duration::from_secs(cfg.jobs.stock_flush_interval_secs);
+        let max_lag = (flush * FLUSH_STALE_AFTER_RUNS).max(FLUSH_STALE_MIN);
+        checks.insert("stock_flush", Check::fresh(Some(state.metrics.stock_flush_lag()), max_lag));
+    }
+    Readiness { ready: checks.values().all(|c| c.ok), checks }
+}
@@ -1,17 +1,40 @@
+use std::time::Instant;
+
 use chrono::{DateTime, Utc};
-use rand::Rng;
 use serde::Serialize;
-use sqlx::{types::Uuid, PgPool};
+use sqlx::{types::Uuid, PgPool, Postgres, Transaction};
 
-use crate::error::AppError;
+use crate::error::{AppError, Reason};
+use crate::models::{ActivityStatus, SoldOutPolicy};
 use crate::services::prize_service::EnabledPrize;
-use crate::services::prize_cache::{snapshot as prize_snapshot, PrizeLite};
+use crate::services::prize_cache::{self, PrizeCache, PrizeLite};
+use crate::services::fair_service::{self, FairInputs, FairSeed, FairSteps, RollStep, SelectionSnapshot};
+use crate::services::feed_service::Win;
+use crate::services::{draw_idempotency::{self, Claim}, pity_service, prize_selector::{self, PrizeSelector}, quota_service, record_outbox};
 use redis::aio::ConnectionManager as RedisManager;
-use crate::redis_scripts::{LUA_COOLDOWN_ONLY, LUA_COOLDOWN_AND_DECR};
-use crate::redis_client::global_manager_from_env;
+use crate::redis_scripts::LUA_DRAW;
+use crate::metrics::Metrics;
+use crate::state::StateData;
 
 #[derive(Serialize, Debug)]
-pub struct DrawResult { pub won: bool, pub prize_id: Option<Uuid>, pub prize_name: Option<String> }
+pub struct DrawResult {
+    pub won: bool,
+    pub prize_id: Option<Uuid>,
+    pub prize_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fair: Option<FairProof>,
+    /// lost because the selected prize and its fallbacks were out of stock (metrics only)
+    #[serde(skip)]
+    pub sold_out: bool,
+}
+
+/// Returned with draws of provably fair activities; check it via `/api/lottery/verify/{record_id}` once the activity ended.
+#[derive(Serialize, Debug)]
+pub struct FairProof {
+    pub record_id: Uuid,
+    #[serde(flatten)]
+    pub inputs: FairInputs,
+}
 
 pub async fn list_enabled_prizes(pool: &PgPool) -> sqlx::Result<Vec<EnabledPrize>> {
     super::prize_service::list_enabled_prizes(pool).await
@@ -19,159 +42,411 @@ pub async fn list_enabled_prizes(pool: &PgPool) -> sqlx::Result<Vec<EnabledPrize
 
 pub async fn global_history(pool: &PgPool) -> sqlx::Result<Vec<GlobalRecordRow>> {
     sqlx::query_as::<_, GlobalRecordRow>(
-        r#"SELECT id, user_id, prize_id, prize_name, created_at FROM lottery_records ORDER BY created_at DESC LIMIT 200"#
+        r#"SELECT id, user_id, activity_id, prize_id, prize_name, created_at FROM lottery_records ORDER BY created_at DESC LIMIT 200"#
     )
     .fetch_all(pool)
     .await
 }
 
 #[derive(Serialize, sqlx::FromRow, Debug, Clone)]
-pub struct GlobalRecordRow { pub id: Uuid, pub user_id: Uuid, pub prize_id: Option<Uuid>, pub prize_name: Option<String>, pub created_at: DateTime<Utc> }
+pub struct GlobalRecordRow { pub id: Uuid, pub user_id: Uuid, pub activity_id: Option<Uuid>, pub prize_id: Option<Uuid>, pub prize_name: Option<String>, pub created_at: DateTime<Utc> }
 
-// Public API used by routes and tests: tries Redis+Lua, falls back to SQL-only if REDIS_URL missing/unavailable
-pub async fn draw(pool: &PgPool, uid: Uuid) -> Result<DrawResult, AppError> {
-    if let Ok(mut mgr) = global_manager_from_env().await {
-        return draw_with_redis(pool, &mut mgr, uid).await;
+// Public API used by routes and tests: Redis+Lua or SQL-only, whichever backend the state was built for
+pub async fn draw(state: &StateData, uid: Uuid, activity_id: Uuid) -> Result<DrawResult, AppError> {
+    draw_with_seed(state, uid, activity_id, None).await
+}
+
+/// Like `draw`, with the user's client seed for provably fair activities (ignored otherwise).
+pub async fn draw_with_seed(state: &StateData, uid: Uuid, activity_id: Uuid, client_seed: Option<&str>) -> Result<DrawResult, AppError> {
+    let started = Instant::now();
+    if let Some(mut mgr) = state.redis.clone() {
+        let res = draw_with_redis(&state.pool, &mut mgr, &state.prize_cache, &state.metrics, uid, activity_id, client_seed).await;
+        state.metrics.observe_draw("redis", started, &res);
+        return res;
     }
-    draw_sql_only(pool, uid).await
+    let res = draw_sql_only(&state.pool, uid, activity_id, client_seed).await;
+    state.metrics.observe_draw("sql", started, &res);
+    if let Ok(res) = &res {
+        announce_committed(state, uid, activity_id, res).await;
+    }
+    res
 }
 
-// Redis path: requires a mutable connection manager
-pub async fn draw_with_redis(pool: &PgPool, redis: &mut RedisManager, uid: Uuid) -> Result<DrawResult, AppError> {
-    // 1) read enabled prizes from in-memory cache (fallback to DB if empty)
-    let mut prizes_lite: Vec<PrizeLite> = prize_snapshot().await;
-    if prizes_lite.is_empty() {
-        let rows = sqlx::query_as::<_, (Uuid, String, i32)>(
-            r#"SELECT id, name, probability FROM prizes WHERE is_enabled=true"#
-        )
-        .fetch_all(pool)
+/// Puts a committed SQL-backend win on the live feed; with Redis the outbox consumer does it once persisted.
+async fn announce_committed(state: &StateData, uid: Uuid, activity_id: Uuid, res: &DrawResult) {
+    if let (Some(prize_id), Some(prize_name)) = (res.prize_id, &res.prize_name) {
+        let win = Win { user_id: uid, activity_id, prize_id, prize_name: prize_name.clone(), at: Utc::now() };
+        state.feed.announce_wins(&state.pool, &[win]).await;
+    }
+}
+
+/// Draw guarded by an Idempotency-Key. Returns the JSON body and whether it is a replay of an earlier draw.
+pub async fn draw_idempotent(state: &StateData, uid: Uuid, activity_id: Uuid, key: &str, client_seed: Option<&str>) -> Result<(String, bool), AppError> {
+    draw_idempotency::validate_key(key)?;
+    let pool = &state.pool;
+    if let Some(mut mgr) = state.redis.clone() {
+        if let Claim::Replay(body) = draw_idempotency::claim_redis(&mut mgr, uid, activity_id, key).await? {
+            return Ok((body, true));
+        }
+        let started = Instant::now();
+        let res = draw_with_redis(pool, &mut mgr, &state.prize_cache, &state.metrics, uid, activity_id, client_seed).await;
+        state.metrics.observe_draw("redis", started, &res);
+        let res = match res {
+            Ok(res) => res,
+            Err(e) => {
+                draw_idempotency::release_redis(&mut mgr, uid, key).await;
+                return Err(e);
+            }
+        };
+        let body = serde_json::to_string(&res).map_err(|_| AppError::Internal("serialize failed"))?;
+        // the draw already happened; failing to remember it only costs replay protection
+        if let Err(e) = draw_idempotency::complete_redis(&mut mgr, uid, activity_id, key, &body).await {
+            tracing::warn!(error = %e, "failed to store idempotent draw result");
+        }
+        return Ok((body, false));
+    }
+
+    let mut tx = pool.begin().await?;
+    sqlx::query("SELECT 1 FROM users WHERE id=$1 FOR UPDATE")
+        .bind(uid)
+        .fetch_one(&mut *tx)
         .await?;
-        prizes_lite = rows.into_iter().map(|(id,name,probability)| PrizeLite{ id, name, probability }).collect();
-    }
-
-    // 2) weighted selection
-    let total_weight: i32 = prizes_lite.iter().map(|p| p.probability.max(0)).sum();
-    let no_win_weight = (100 - total_weight).max(0);
-    let roll = { let mut rng = rand::thread_rng(); rng.gen_range(1..=std::cmp::max(1, total_weight + no_win_weight)) };
-
-    let mut acc = 0;
-    let mut selected: Option<(Uuid, String)> = None;
-    for p in &prizes_lite {
-        acc += p.probability.max(0);
-        if roll <= acc { selected = Some((p.id, p.name.clone())); break; }
-    }
-
-    // 3) if selected, atomically decr stock + set cooldown in Redis; otherwise set cooldown only
-    let ttl = 60i64;
-    let (won, prize_id, prize_name) = if let Some((pid, pname)) = selected {
-        let stock_key = format!("lottery:stock:{}", pid);
-        let sold_key = format!("lottery:sold:{}", pid);
-        let r: i64 = LUA_COOLDOWN_AND_DECR
-            .key(format!("lottery:cooldown:{}", uid))
-            .key(stock_key)
-            .key(sold_key)
-            .arg(ttl)
-            .invoke_async(redis)
-            .await
-            .unwrap_or(-1);
-        if r == 0 { return Err(AppError::BadRequest("抽奖频率过高，请稍后再试")); }
-        if r == 1 { (true, Some(pid), Some(pname)) } else { (false, None, None) }
+    if let Claim::Replay(body) = draw_idempotency::claim_sql(&mut tx, uid, activity_id, key).await? {
+        return Ok((body, true));
+    }
+    let started = Instant::now();
+    let res = draw_in_tx(&mut tx, uid, activity_id, client_seed).await;
+    state.metrics.observe_draw("sql", started, &res);
+    let res = res?;
+    let body = serde_json::to_string(&res).map_err(|_| AppError::Internal("serialize failed"))?;
+    draw_idempotency::complete_sql(&mut tx, uid, activity_id, key, &body).await?;
+    tx.commit().await?;
+    announce_committed(state, uid, activity_id, &res).await;
+    Ok((body, false))
+}
+
+// Only ongoing activities inside their time window accept draws
+fn ensure_drawable(status: ActivityStatus, start_time: DateTime<Utc>, end_time: DateTime<Utc>) -> Result<(), AppError> {
+    let now = Utc::now();
+    match status {
+        ActivityStatus::Planned => return Err(AppError::bad_request(Reason::ActivityNotStarted, "活动尚未开始")),
+        ActivityStatus::Paused => return Err(AppError::bad_request(Reason::ActivityPaused, "活动已暂停")),
+        ActivityStatus::Ended => return Err(AppError::bad_request(Reason::ActivityEnded, "活动已结束")),
+        ActivityStatus::Ongoing => {}
+    }
+    if now < start_time { return Err(AppError::bad_request(Reason::ActivityNotStarted, "活动尚未开始")); }
+    if now > end_time { return Err(AppError::bad_request(Reason::ActivityEnded, "活动已结束")); }
+    Ok(())
+}
+
+fn redis_err(e: redis::RedisError) -> AppError {
+    tracing::error!(error = ?e, "draw script failed");
+    AppError::Internal("redis error")
+}
+
+/// The prizes one draw may end on, as indexes into `prizes` given as (tier, weight), and the policy that
+/// applies when the first one is sold out. Empty means no prize was selected. `policy` is the activity's.
+pub(crate) fn draw_candidates(
+    policy: SoldOutPolicy,
+    selector: &dyn PrizeSelector,
+    prizes: &[(i32, i32)],
+    forced: Option<i32>,
+    roll: impl Fn(RollStep) -> f64,
+) -> (Vec<usize>, SoldOutPolicy) {
+    if let Some(tier) = forced {
+        // a guaranteed draw bypasses the selector; any qualifying prize in stock will do
+        return (pity_service::forced_candidates(prizes, tier, roll(RollStep::Pity)), SoldOutPolicy::Redraw);
+    }
+    let selected = selector.select(roll(RollStep::Select)).filter(|i| *i < prizes.len());
+    let candidates = match selected {
+        Some(i) => {
+            let tiers: Vec<i32> = prizes.iter().map(|p| p.0).collect();
+            std::iter::once(i).chain(prize_selector::fallback_candidates(policy, &tiers, i)).collect()
+        }
+        None => Vec::new(),
+    };
+    (candidates, policy)
+}
+
+/// The candidate a draw ends on given current stock; mirrors the stock handling of `LUA_DRAW`.
+pub(crate) fn resolve_in_stock(
+    candidates: &[usize],
+    policy: SoldOutPolicy,
+    in_stock: impl Fn(usize) -> bool,
+    weight: impl Fn(usize) -> u64,
+    redraw_roll: f64,
+) -> Option<usize> {
+    let (&first, rest) = candidates.split_first()?;
+    if in_stock(first) {
+        return Some(first);
+    }
+    let mut available = rest.iter().copied().filter(|c| in_stock(*c));
+    match policy {
+        SoldOutPolicy::Lose => None,
+        SoldOutPolicy::Downgrade => available.next(),
+        SoldOutPolicy::Redraw => prize_selector::redraw(&available.map(|c| (c, weight(c))).collect::<Vec<_>>(), redraw_roll),
+    }
+}
+
+/// Roll `step` of a draw: derived from the seeds for provably fair activities, random otherwise.
+fn draw_roll(seed: Option<&FairSeed>, fair: Option<&FairInputs>, step: RollStep) -> f64 {
+    match (seed, fair) {
+        (Some(s), Some(f)) => fair_service::roll(&s.server_seed, &f.client_seed, f.nonce, step),
+        _ => rand::random(),
+    }
+}
+
+// Redis path: requires a mutable connection manager
+pub async fn draw_with_redis(
+    pool: &PgPool,
+    redis: &mut RedisManager,
+    cache: &PrizeCache,
+    metrics: &Metrics,
+    uid: Uuid,
+    activity_id: Uuid,
+    client_seed: Option<&str>,
+) -> Result<DrawResult, AppError> {
+    // 1) read activity + its enabled prizes from in-memory cache (fallback to DB if not cached yet)
+    let snap = cache.snapshot().await;
+    let (activity, prizes) = match (snap.activities.get(&activity_id), snap.prizes.get(&activity_id)) {
+        (Some(a), Some(p)) => (a.clone(), p.clone()),
+        _ => {
+            let activity = prize_cache::fetch_activity(&mut *pool.acquire().await?, activity_id).await?.ok_or(AppError::NotFound)?;
+            let prizes = prize_cache::fetch_activity_prizes(pool, &activity).await?;
+            (activity, prizes)
+        }
+    };
+    ensure_drawable(activity.status, activity.start_time, activity.end_time)?;
+    let now = Utc::now();
+    // counters outlive the activity by a day so late profile reads still see them
+    let expire_at = (activity.end_time + chrono::Duration::days(1)).timestamp().max(now.timestamp() + 60);
+
+    // provably fair: the roll comes from the committed seed, the client seed and the user's next nonce
+    let seed = match (activity.provably_fair, &activity.fair_seed) {
+        (false, _) => None,
+        (true, Some(s)) => Some(s.clone()),
+        (true, None) => Some(fair_service::ensure_seed(&mut *pool.acquire().await?, activity_id).await?),
+    };
+    let fair = match &seed {
+        Some(s) => Some(FairInputs {
+            server_seed_hash: s.server_seed_hash.clone(),
+            client_seed: fair_service::client_seed_or_random(client_seed)?,
+            nonce: fair_service::next_nonce(&mut *pool.acquire().await?, activity_id, uid).await?,
+            selection_hash: prizes.fair_selection.clone(),
+        }),
+        None => None,
+    };
+
+    // 2) weighted selection with the activity's selector, unless a pity rule guarantees a tier;
+    //    fallbacks are only used if the first candidate is sold out
+    let forced = if activity.pity_rules.is_empty() {
+        None
     } else {
-        let r: i64 = LUA_COOLDOWN_ONLY
-            .key(format!("lottery:cooldown:{}", uid))
-            .arg(ttl)
-            .invoke_async(redis)
-            .await
-            .unwrap_or(0);
-        if r == 0 { return Err(AppError::BadRequest("抽奖频率过高，请稍后再试")); }
-        (false, None, None)
+        let counters = pity_service::counters_redis(redis, activity_id, uid).await.map_err(redis_err)?;
+        pity_service::forced_tier(&activity.pity_rules, &counters)
     };
+    let weights: Vec<(i32, i32)> = prizes.prizes.iter().map(|p| (p.tier, p.probability)).collect();
+    let roll = |step| draw_roll(seed.as_ref(), fair.as_ref(), step);
+    let (candidates, policy) = draw_candidates(activity.selection.sold_out_policy, &*prizes.selector, &weights, forced, roll);
+    let candidates: Vec<&PrizeLite> = candidates.into_iter().map(|i| &prizes.prizes[i]).collect();
 
-    // 4) persist record asynchronously (fire-and-forget)
-    if won || true {
-        let pool = pool.clone();
-        let prize_id_c = prize_id;
-        let prize_name_c = prize_name.clone();
-        tokio::spawn(async move {
-            let _ = sqlx::query(
-                r#"INSERT INTO lottery_records (id, user_id, prize_id, prize_name, created_at)
-                    VALUES ($1,$2,$3,$4, now())"#
-            )
-            .bind(Uuid::new_v4())
-            .bind(uid)
-            .bind(prize_id_c)
-            .bind(prize_name_c.as_deref())
-            .execute(&pool)
-            .await;
-
-            let _ = sqlx::query("UPDATE users SET last_lottery_at=now(), updated_at=now() WHERE id=$1")
-                .bind(uid)
-                .execute(&pool)
-                .await;
-        });
-    }
-
-    Ok(DrawResult { won, prize_id, prize_name })
+    // 3) atomically check cooldown + quotas and, if a prize was selected, decr the stock of it or a fallback
+    let day = quota_service::day_window(&activity.quota.quota_timezone, now);
+    let qkeys = quota_service::redis_keys(activity_id, uid, &day);
+    let limit = |v: Option<i32>| v.map(i64::from).unwrap_or(-1);
+    let record_id = Uuid::new_v4();
+    let mut invocation = LUA_DRAW.prepare_invoke();
+    invocation
+        .key(format!("lottery:cooldown:{}:{}", activity_id, uid))
+        .key(&qkeys.total)
+        .key(&qkeys.daily)
+        .key(&qkeys.wins)
+        .key(record_outbox::STREAM_KEY)
+        .key(pity_service::redis_key(activity_id, uid));
+    for p in &candidates {
+        invocation.key(format!("lottery:stock:{}", p.id)).key(format!("lottery:sold:{}", p.id));
+    }
+    invocation
+        .arg(activity.draw_cooldown_secs.max(0))
+        .arg(limit(activity.quota.max_draws_total))
+        .arg(limit(activity.quota.max_draws_daily))
+        .arg(limit(activity.quota.max_wins_total))
+        .arg(expire_at)
+        .arg(day.end.timestamp())
+        .arg(now.timestamp())
+        .arg(record_id.to_string())
+        .arg(uid.to_string())
+        .arg(activity_id.to_string())
+        .arg(policy.as_str())
+        .arg(roll(RollStep::Redraw))
+        .arg(now.timestamp_millis())
+        .arg(fair.as_ref().map(FairInputs::encode).unwrap_or_default())
+        .arg(if fair.is_some() { FairSteps::encode_choice(forced, policy) } else { String::new() })
+        .arg(activity.pity_rules.len());
+    for r in &activity.pity_rules {
+        invocation.arg(r.tier);
+    }
+    for p in &candidates {
+        invocation.arg(p.id.to_string()).arg(&p.name).arg(p.probability.max(0)).arg(p.tier);
+    }
+    let timer = metrics.script_timer("draw");
+    let (code, n): (i64, i64) = invocation.invoke_async(redis).await.map_err(redis_err)?;
+    timer.observe_duration();
+    let won_prize = match code {
+        0 => return Err(AppError::TooFrequent(n)),
+        -2 => return Err(AppError::bad_request(Reason::DrawQuotaExhausted, "抽奖次数已用完")),
+        -3 => {
+            return Err(AppError::bad_request(Reason::DailyQuotaExhausted, "今日抽奖次数已用完")
+                .with_details(serde_json::json!({ "resets_in_secs": n })))
+        }
+        1 => usize::try_from(n - 1).ok().and_then(|i| candidates.get(i)),
+        _ => None,
+    };
+    let (won, prize_id, prize_name) = match won_prize {
+        Some(p) => (true, Some(p.id), Some(p.name.clone())),
+        None => (false, None, None),
+    };
+
+    // 4) the record was appended to the outbox stream by the script; record_outbox persists it
+    let fair = fair.map(|inputs| FairProof { record_id, inputs });
+    Ok(DrawResult { won, prize_id, prize_name, fair, sold_out: code == -1 })
 }
 
 // SQL-only fallback (original implementation)
-async fn draw_sql_only(pool: &PgPool, uid: Uuid) -> Result<DrawResult, AppError> {
+async fn draw_sql_only(pool: &PgPool, uid: Uuid, activity_id: Uuid, client_seed: Option<&str>) -> Result<DrawResult, AppError> {
     let mut tx = pool.begin().await?;
+    let res = draw_in_tx(&mut tx, uid, activity_id, client_seed).await?;
+    tx.commit().await?;
+    Ok(res)
+}
+
+async fn draw_in_tx(tx: &mut Transaction<'_, Postgres>, uid: Uuid, activity_id: Uuid, client_seed: Option<&str>) -> Result<DrawResult, AppError> {
+    let activity = prize_cache::fetch_activity(tx, activity_id).await?.ok_or(AppError::NotFound)?;
+    ensure_drawable(activity.status, activity.start_time, activity.end_time)?;
 
-    let last: Option<DateTime<Utc>> = sqlx::query_scalar("SELECT last_lottery_at FROM users WHERE id=$1 FOR UPDATE")
+    // lock the user row to serialize concurrent draws of the same user
+    sqlx::query("SELECT 1 FROM users WHERE id=$1 FOR UPDATE")
         .bind(uid)
-        .fetch_one(&mut *tx)
+        .fetch_one(&mut **tx)
         .await?;
-    if let Some(last) = last {
-        let seconds = (Utc::now() - last).num_seconds();
-        if seconds < 60 { return Err(AppError::BadRequest("抽奖频率过高，请稍后再试")); }
+    let cooldown = i64::from(activity.draw_cooldown_secs.max(0));
+    if cooldown > 0 {
+        let last: Option<DateTime<Utc>> = sqlx::query_scalar(
+            "SELECT max(created_at) FROM lottery_records WHERE user_id=$1 AND activity_id=$2"
+        )
+        .bind(uid)
+        .bind(activity_id)
+        .fetch_one(&mut **tx)
+        .await?;
+        if let Some(last) = last {
+            let elapsed_ms = (Utc::now() - last).num_milliseconds();
+            if elapsed_ms < cooldown * 1000 {
+                let wait = (cooldown * 1000 - elapsed_ms + 999) / 1000;
+                return Err(AppError::TooFrequent(wait));
+            }
+        }
     }
 
-    let prizes = sqlx::query_as::<_, EnabledPrize>(
-        r#"SELECT id, name, remaining_count, probability FROM prizes
-           WHERE is_enabled=true AND remaining_count>0"#
-    )
-    .fetch_all(&mut *tx)
-    .await?;
+    let day = quota_service::day_window(&activity.quota.quota_timezone, Utc::now());
+    let usage = quota_service::usage_sql(&mut **tx, uid, activity_id, &day).await?;
+    let may_win = activity.quota.check(&usage)?;
 
-    let total_weight: i32 = prizes.iter().map(|p| p.probability.max(0)).sum();
-    let no_win_weight = (100 - total_weight).max(0);
-    let roll = { let mut rng = rand::thread_rng(); rng.gen_range(1..=std::cmp::max(1, total_weight + no_win_weight)) };
+    let mut pity = pity_service::counters_sql(&mut **tx, uid, activity_id).await?;
+    let forced = pity_service::forced_tier(&activity.pity_rules, &pity);
 
-    let mut acc = 0;
-    let mut selected: Option<(Uuid, String)> = None;
-    for p in &prizes {
-        acc += p.probability.max(0);
-        if roll <= acc { selected = Some((p.id, p.name.clone())); break; }
-    }
+    let seed = match (activity.provably_fair, &activity.fair_seed) {
+        (false, _) => None,
+        (true, Some(s)) => Some(s.clone()),
+        (true, None) => Some(fair_service::ensure_seed(tx, activity_id).await?),
+    };
+    let mut fair = match &seed {
+        Some(s) => Some(FairInputs {
+            server_seed_hash: s.server_seed_hash.clone(),
+            client_seed: fair_service::client_seed_or_random(client_seed)?,
+            nonce: fair_service::next_nonce(tx, activity_id, uid).await?,
+            selection_hash: None,
+        }),
+        None => None,
+    };
 
-    let (won, prize_id, prize_name) = if let Some((pid, pname)) = selected {
+    // sold-out prizes take part in the selection too, so the sold-out policy behaves as on the Redis path
+    let prizes = sqlx::query_as::<_, (Uuid, String, i32, i32, i64)>(
+        r#"SELECT id, name, probability, tier, remaining_count FROM prizes
+           WHERE is_enabled=true AND activity_id=$1 ORDER BY id"#
+    )
+    .bind(activity_id)
+    .fetch_all(&mut **tx)
+    .await?;
+    if let Some(f) = fair.as_mut() {
+        let snapshot = SelectionSnapshot::new(&activity.selection, prizes.iter().map(|p| (p.0, p.2, p.3)));
+        f.selection_hash = Some(fair_service::save_selection(tx, activity_id, &snapshot).await?);
+    }
+    let weights: Vec<(i32, i32)> = prizes.iter().map(|p| (p.3, p.2)).collect();
+    let selector = activity.selection.build(&prizes.iter().map(|p| p.2).collect::<Vec<_>>());
+    let roll = |step| draw_roll(seed.as_ref(), fair.as_ref(), step);
+    let (candidates, policy) = draw_candidates(activity.selection.sold_out_policy, &*selector, &weights, forced, roll);
+    let selected = may_win && !candidates.is_empty();
+    let mut stock: Vec<i64> = prizes.iter().map(|p| p.4).collect();
+    let chosen = loop {
+        let pick = Some(&candidates)
+            .filter(|_| may_win)
+            .and_then(|c| resolve_in_stock(c, policy, |i| stock[i] > 0, |i| prizes[i].2.max(0) as u64, roll(RollStep::Redraw)));
+        let Some(i) = pick else { break None };
         let row = sqlx::query(
             r#"UPDATE prizes SET remaining_count = remaining_count - 1, updated_at=now()
                 WHERE id=$1 AND remaining_count>0 RETURNING remaining_count"#
         )
-        .bind(pid)
-        .fetch_optional(&mut *tx)
+        .bind(prizes[i].0)
+        .fetch_optional(&mut **tx)
         .await?;
-        if row.is_some() { (true, Some(pid), Some(pname)) } else { (false, None, None) }
-    } else { (false, None, None) };
+        if row.is_some() {
+            break Some(i);
+        }
+        // sold out by a concurrent draw since it was read: resolve again without it, as the replay will
+        stock[i] = 0;
+    };
+    let (won, prize_id, prize_name) = match chosen {
+        Some(i) => (true, Some(prizes[i].0), Some(prizes[i].1.clone())),
+        None => (false, None, None),
+    };
+    let steps = fair.as_ref().map(|_| FairSteps {
+        forced_tier: forced,
+        policy,
+        may_win,
+        sold_out: candidates.iter().filter(|i| stock[**i] <= 0).map(|i| prizes[*i].0).collect(),
+    });
+
+    if !activity.pity_rules.is_empty() {
+        let won_tier = chosen.map(|i| prizes[i].3);
+        pity_service::advance(&activity.pity_rules, &mut pity, won_tier);
+        let now = Utc::now();
+        let entries: Vec<_> = activity.pity_rules.iter().map(|r| (uid, activity_id, r.tier, pity[&r.tier], now)).collect();
+        pity_service::save(tx, &entries).await?;
+    }
 
+    let record_id = Uuid::new_v4();
     sqlx::query(
-        r#"INSERT INTO lottery_records (id, user_id, prize_id, prize_name, created_at)
-            VALUES ($1,$2,$3,$4, now())"#
+        r#"INSERT INTO lottery_records (id, user_id, activity_id, prize_id, prize_name, created_at, server_seed_hash, client_seed, nonce, selection_hash, fair_steps)
+            VALUES ($1,$2,$3,$4,$5, now(), $6,$7,$8,$9,$10)"#
     )
-    .bind(Uuid::new_v4())
+    .bind(record_id)
     .bind(uid)
+    .bind(activity_id)
     .bind(prize_id)
     .bind(prize_name.as_deref())
-    .execute(&mut *tx)
+    .bind(fair.as_ref().map(|f| &f.server_seed_hash))
+    .bind(fair.as_ref().map(|f| &f.client_seed))
+    .bind(fair.as_ref().map(|f| f.nonce))
+    .bind(fair.as_ref().and_then(|f| f.selection_hash.as_ref()))
+    .bind(steps.as_ref().map(FairSteps::to_json))
+    .execute(&mut **tx)
     .await?;
 
     sqlx::query("UPDATE users SET last_lottery_at=now(), updated_at=now() WHERE id=$1")
         .bind(uid)
-        .execute(&mut *tx)
+        .execute(&mut **tx)
         .await?;
 
-    tx.commit().await?;
-    Ok(DrawResult { won, prize_id, prize_name })
+    let fair = fair.map(|inputs| FairProof { record_id, inputs });
+    Ok(DrawResult { won, prize_id, prize_name, fair, sold_out: selected && !won })
 }
@@ -1,10 +1,20 @@
 use sqlx::PgPool;
 
 pub mod user_service;
+pub mod admin_service;
+pub mod token_service;
 pub mod activity_service;
 pub mod prize_service;
 pub mod lottery_service;
 pub mod stock_sync;
 pub mod prize_cache;
+pub mod prize_selector;
+pub mod draw_idempotency;
+pub mod quota_service;
+pub mod pity_service;
+pub mod fair_service;
+pub mod record_outbox;
+pub mod health_service;
+pub mod feed_service;
 
 pub type Db = PgPool;
@@ -0,0 +1,192 @@
+use std::collections::{hash_map::Entry, HashMap};
+
+use chrono::{DateTime, Utc};
+use redis::aio::ConnectionManager as RedisManager;
+use serde::{Deserialize, Serialize};
+use sqlx::{types::Uuid, PgExecutor, PgPool, Postgres, Transaction};
+
+use crate::error::AppError;
+use crate::services::prize_selector;
+
+// Guaranteed wins ("pity"): a rule (tier T, threshold N) counts a user's draws in an activity since the last
+// win of a prize with tier <= T; once N draws in a row missed, the next draw is forced to such a prize.
+// On the Redis path the live counters are the hash `lottery:pity:{activity}:{user}` updated by `LUA_DRAW`,
+// and every record in the outbox carries the new values so `record_outbox` mirrors them to `pity_counters`.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::FromRow)]
+pub struct PityRule {
+    /// prizes of this tier or better (smaller number) qualify
+    pub tier: i32,
+    /// draws without a qualifying win before the next one is guaranteed
+    pub threshold: i32,
+}
+
+/// Draws since a qualifying win, per rule tier.
+pub type PityCounters = HashMap<i32, i32>;
+
+#[derive(Serialize, Debug, Clone)]
+pub struct PityStatus {
+    pub activity_id: Uuid,
+    pub tier: i32,
+    pub threshold: i32,
+    pub draws_since_win: i32,
+    /// the draw (counting from the next one) that is guaranteed to qualify
+    pub guaranteed_in: i32,
+}
+
+pub fn redis_key(activity_id: Uuid, uid: Uuid) -> String {
+    format!("lottery:pity:{}:{}", activity_id, uid)
+}
+
+/// The tier the next draw is forced to reach, if any rule triggered; the best tier wins.
+pub fn forced_tier(rules: &[PityRule], counters: &PityCounters) -> Option<i32> {
+    rules
+        .iter()
+        .filter(|r| counters.get(&r.tier).copied().unwrap_or(0) >= r.threshold)
+        .map(|r| r.tier)
+        .min()
+}
+
+/// Applies one draw to the counters: reset on a qualifying win, +1 otherwise. Mirrors `LUA_DRAW`.
+pub fn advance(rules: &[PityRule], counters: &mut PityCounters, won_tier: Option<i32>) {
+    for r in rules {
+        let c = counters.entry(r.tier).or_insert(0);
+        *c = if won_tier.is_some_and(|t| t <= r.tier) { 0 } else { *c + 1 };
+    }
+}
+
+/// Prizes a forced draw may end on, as indexes into `prizes` given as (tier, weight): the tier <= `tier`
+/// ones, the first picked by weight (equal weights when all are 0), the rest as in-stock fallbacks.
+pub fn forced_candidates(prizes: &[(i32, i32)], tier: i32, roll: f64) -> Vec<usize> {
+    let qualifying: Vec<usize> = (0..prizes.len()).filter(|i| prizes[*i].0 <= tier).collect();
+    let mut weighted: Vec<(usize, u64)> = qualifying.iter().map(|i| (*i, prizes[*i].1.max(0) as u64)).collect();
+    if weighted.iter().all(|(_, w)| *w == 0) {
+        weighted.iter_mut().for_each(|(_, w)| *w = 1);
+    }
+    let Some(first) = prize_selector::redraw(&weighted, roll) else {
+        return Vec::new();
+    };
+    std::iter::once(first).chain(qualifying.into_iter().filter(|i| *i != first)).collect()
+}
+
+/// "tier:count,..." as written to the outbox by `LUA_DRAW`.
+pub fn parse_counters(s: &str) -> Vec<(i32, i32)> {
+    s.split(',')
+        .filter_map(|kv| kv.split_once(':'))
+        .filter_map(|(t, n)| Some((t.parse().ok()?, n.parse().ok()?)))
+        .collect()
+}
+
+pub async fn rules_of<'e, E: PgExecutor<'e>>(ex: E, activity_id: Uuid) -> sqlx::Result<Vec<PityRule>> {
+    sqlx::query_as::<_, PityRule>("SELECT tier, threshold FROM activity_pity_rules WHERE activity_id=$1 ORDER BY tier")
+        .bind(activity_id)
+        .fetch_all(ex)
+        .await
+}
+
+pub async fn all_rules(pool: &PgPool) -> sqlx::Result<HashMap<Uuid, Vec<PityRule>>> {
+    let rows: Vec<(Uuid, i32, i32)> = sqlx::query_as("SELECT activity_id, tier, threshold FROM activity_pity_rules ORDER BY tier")
+        .fetch_all(pool)
+        .await?;
+    let mut out: HashMap<Uuid, Vec<PityRule>> = HashMap::new();
+    for (aid, tier, threshold) in rows {
+        out.entry(aid).or_default().push(PityRule { tier, threshold });
+    }
+    Ok(out)
+}
+
+pub async fn insert_rules<'e, E: PgExecutor<'e>>(ex: E, activity_id: Uuid, rules: &[PityRule]) -> sqlx::Result<()> {
+    sqlx::query(
+        r#"INSERT INTO activity_pity_rules (activity_id, tier, threshold)
+           SELECT $1, * FROM UNNEST($2::int[], $3::int[])"#
+    )
+    .bind(activity_id)
+    .bind(rules.iter().map(|r| r.tier).collect::<Vec<_>>())
+    .bind(rules.iter().map(|r| r.threshold).collect::<Vec<_>>())
+    .execute(ex)
+    .await?;
+    Ok(())
+}
+
+pub async fn counters_sql<'e, E: PgExecutor<'e>>(ex: E, uid: Uuid, activity_id: Uuid) -> sqlx::Result<PityCounters> {
+    let rows: Vec<(i32, i32)> = sqlx::query_as(
+        "SELECT tier, draws_since_win FROM pity_counters WHERE user_id=$1 AND activity_id=$2"
+    )
+    .bind(uid)
+    .bind(activity_id)
+    .fetch_all(ex)
+    .await?;
+    Ok(rows.into_iter().collect())
+}
+
+pub async fn counters_redis(redis: &mut RedisManager, activity_id: Uuid, uid: Uuid) -> redis::RedisResult<PityCounters> {
+    let raw: HashMap<String, i32> = redis::cmd("HGETALL").arg(redis_key(activity_id, uid)).query_async(redis).await?;
+    Ok(raw.into_iter().filter_map(|(t, n)| Some((t.parse().ok()?, n))).collect())
+}
+
+/// Stores counters in `pity_counters`; rows older than `at` are overwritten, newer ones kept.
+/// Each entry is (user, activity, tier, draws since win, as of).
+pub async fn save(tx: &mut Transaction<'_, Postgres>, entries: &[(Uuid, Uuid, i32, i32, DateTime<Utc>)]) -> sqlx::Result<()> {
+    if entries.is_empty() {
+        return Ok(());
+    }
+    // a batch may carry several states of the same counter; only the latest is kept
+    let mut latest: HashMap<(Uuid, Uuid, i32), (i32, DateTime<Utc>)> = HashMap::new();
+    for (uid, aid, tier, n, at) in entries {
+        let e = latest.entry((*uid, *aid, *tier)).or_insert((*n, *at));
+        if *at >= e.1 {
+            *e = (*n, *at);
+        }
+    }
+    let rows: Vec<_> = latest.into_iter().collect();
+    sqlx::query(
+        r#"INSERT INTO pity_counters (user_id, activity_id, tier, draws_since_win, updated_at)
+           SELECT * FROM UNNEST($1::uuid[], $2::uuid[], $3::int[], $4::int[], $5::timestamptz[]) AS x(u, a, t, n, at)
+           WHERE x.a IN (SELECT id FROM activities)
+           ON CONFLICT (user_id, activity_id, tier) DO UPDATE
+           SET draws_since_win = EXCLUDED.draws_since_win, updated_at = EXCLUDED.updated_at
+           WHERE pity_counters.updated_at <= EXCLUDED.updated_at"#
+    )
+    .bind(rows.iter().map(|((u, _, _), _)| *u).collect::<Vec<_>>())
+    .bind(rows.iter().map(|((_, a, _), _)| *a).collect::<Vec<_>>())
+    .bind(rows.iter().map(|((_, _, t), _)| *t).collect::<Vec<_>>())
+    .bind(rows.iter().map(|(_, (n, _))| *n).collect::<Vec<_>>())
+    .bind(rows.iter().map(|(_, (_, at))| *at).collect::<Vec<_>>())
+    .execute(&mut **tx)
+    .await?;
+    Ok(())
+}
+
+/// Pity progress of `uid` in every running activity with rules; read from Redis when the draw backend keeps them there.
+pub async fn status_for_user(pool: &PgPool, redis: Option<&RedisManager>, uid: Uuid) -> Result<Vec<PityStatus>, AppError> {
+    let rows: Vec<(Uuid, i32, i32)> = sqlx::query_as(
+        r#"SELECT r.activity_id, r.tier, r.threshold FROM activity_pity_rules r JOIN activities a ON a.id = r.activity_id
+           WHERE a.status='ongoing' AND a.start_time<=now() AND a.end_time>=now()
+           ORDER BY a.start_time, r.tier"#
+    )
+    .fetch_all(pool)
+    .await?;
+    if rows.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut redis = redis.cloned();
+    let mut counters: HashMap<Uuid, PityCounters> = HashMap::new();
+    let mut out = Vec::with_capacity(rows.len());
+    for (aid, tier, threshold) in rows {
+        if let Entry::Vacant(e) = counters.entry(aid) {
+            e.insert(match redis.as_mut() {
+                Some(r) => counters_redis(r, aid, uid).await.map_err(|e| anyhow::anyhow!(e))?,
+                None => counters_sql(pool, uid, aid).await?,
+            });
+        }
+        let draws_since_win = counters[&aid].get(&tier).copied().unwrap_or(0);
+        out.push(PityStatus {
+            activity_id: aid,
+            tier,
+            threshold,
+            draws_since_win,
+            guaranteed_in: (threshold - draws_since_win + 1).max(1),
+        });
+    }
+    Ok(out)
+}
@@ -1,39 +1,220 @@
+use std::collections::HashMap;
 use std::time::Duration;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use chrono::{DateTime, Utc};
 use tokio::sync::RwLock;
-use tokio::sync::OnceCell;
-use sqlx::{PgPool, types::Uuid};
+use sqlx::{PgConnection, PgPool, types::Uuid};
+
+use crate::config::SharedConfig;
+use crate::metrics::Metrics;
+use crate::state::Jobs;
+use crate::models::ActivityStatus;
+use crate::services::fair_service::{self, FairSeed, SelectionSnapshot};
+use crate::services::pity_service::{self, PityRule};
+use crate::services::prize_selector::{PrizeSelector, SelectionConfig};
+use crate::services::quota_service::QuotaLimits;
 
 #[derive(Clone, Debug)]
-pub struct PrizeLite { pub id: Uuid, pub name: String, pub probability: i32 }
-
-static CACHE: OnceCell<Arc<RwLock<Vec<PrizeLite>>>> = OnceCell::const_new();
-
-pub async fn get_cache() -> Arc<RwLock<Vec<PrizeLite>>> {
-    CACHE.get_or_init(|| async { Arc::new(RwLock::new(Vec::new())) }).await.clone()
-}
-
-pub fn spawn_refresh(pool: PgPool) {
-    let cache_fut = get_cache();
-    tokio::spawn(async move {
-        let cache = cache_fut.await;
-        let mut tick = tokio::time::interval(Duration::from_millis(800));
-        loop {
-            tick.tick().await;
-            let rows: Result<Vec<(Uuid, String, i32)>, _> = sqlx::query_as(
-                "SELECT id, name, probability FROM prizes WHERE is_enabled=true"
-            )
-            .fetch_all(&pool)
-            .await
-            .map(|v: Vec<(Uuid, String, i32)>| v);
-            if let Ok(v) = rows {
-                let list: Vec<PrizeLite> = v.into_iter().map(|(id, name, probability)| PrizeLite{ id, name, probability }).collect();
-                *cache.write().await = list;
+pub struct PrizeLite { pub id: Uuid, pub activity_id: Uuid, pub name: String, pub probability: i32, pub tier: i32 }
+
+#[derive(Clone, Debug, sqlx::FromRow)]
+pub struct ActivityLite {
+    pub id: Uuid,
+    pub status: ActivityStatus,
+    pub start_time: DateTime<Utc>,
+    pub end_time: DateTime<Utc>,
+    pub draw_cooldown_secs: i32,
+    #[sqlx(flatten)]
+    pub quota: QuotaLimits,
+    #[sqlx(flatten)]
+    pub selection: SelectionConfig,
+    pub provably_fair: bool,
+    #[sqlx(skip)]
+    pub pity_rules: Vec<PityRule>,
+    /// set for provably fair activities once their seed exists
+    #[sqlx(skip)]
+    pub fair_seed: Option<FairSeed>,
+}
+
+const ACTIVITY_LITE_SELECT: &str = "SELECT id, status, start_time, end_time, draw_cooldown_secs, \
+    max_draws_total, max_draws_daily, max_wins_total, quota_timezone, \
+    prize_selector, probability_unit, no_win_weight, sold_out_policy, provably_fair FROM activities";
+
+/// Enabled prizes of one activity with the selector built over them (indexes match `prizes`).
+#[derive(Clone, Debug)]
+pub struct ActivityPrizes {
+    pub prizes: Vec<PrizeLite>,
+    pub selector: Arc<dyn PrizeSelector>,
+    /// provably fair activities: hash of the stored snapshot of this table, referenced by their draws
+    pub fair_selection: Option<String>,
+}
+
+impl ActivityPrizes {
+    pub fn new(selection: &SelectionConfig, prizes: Vec<PrizeLite>) -> Self {
+        let probabilities: Vec<i32> = prizes.iter().map(|p| p.probability).collect();
+        Self { selector: selection.build(&probabilities), prizes, fair_selection: None }
+    }
+
+    /// Stores the snapshot of a provably fair activity's table before draws use it; `known` is the hash the
+    /// cache already stored, which skips the write when nothing changed.
+    async fn pin_fair_selection(&mut self, pool: &PgPool, activity: &ActivityLite, known: Option<&str>) -> sqlx::Result<()> {
+        if !activity.provably_fair {
+            return Ok(());
+        }
+        let snapshot = SelectionSnapshot::new(&activity.selection, self.prizes.iter().map(|p| (p.id, p.probability, p.tier)));
+        self.fair_selection = match known {
+            Some(hash) if snapshot.hash() == hash => Some(hash.to_string()),
+            _ => Some(fair_service::save_selection(&mut *pool.acquire().await?, activity.id, &snapshot).await?),
+        };
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct CacheSnapshot {
+    pub activities: HashMap<Uuid, ActivityLite>,
+    /// keyed by activity; selectors (e.g. alias tables) are built once per refresh
+    pub prizes: HashMap<Uuid, ActivityPrizes>,
+}
+
+/// The activities and prizes draws read instead of hitting Postgres. Cheap to clone; clones share the cache.
+#[derive(Clone, Default)]
+pub struct PrizeCache(Arc<CacheInner>);
+
+#[derive(Default)]
+struct CacheInner {
+    snapshot: RwLock<Arc<CacheSnapshot>>,
+    // Bumped by every targeted reload. A full refresh only publishes its result if no reload started while it
+    // was reading the DB, so a slow refresh cannot put back what an admin change just replaced.
+    generation: AtomicU64,
+    // Targeted reloads run one at a time so an older read never overwrites a newer one.
+    reload: tokio::sync::Mutex<()>,
+}
+
+impl PrizeCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reloads everything every `jobs.prize_cache_refresh_ms`, re-read each pass so a config reload applies.
+    pub fn spawn_refresh(&self, jobs: &Jobs, pool: PgPool, cfg: SharedConfig, metrics: Arc<Metrics>) {
+        let inner = self.0.clone();
+        let stop = jobs.stop_token();
+        jobs.spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = tokio::time::sleep(Duration::from_millis(cfg.get().jobs.prize_cache_refresh_ms)) => {}
+                    _ = stop.cancelled() => break,
+                }
+                let generation = inner.generation.load(Ordering::SeqCst);
+                let previous = inner.snapshot.read().await.clone();
+                if let Ok(snap) = load(&pool, &previous).await {
+                    let mut guard = inner.snapshot.write().await;
+                    // a skipped result is older than the reload that replaced it, so the cache is fresh either way
+                    if inner.generation.load(Ordering::SeqCst) == generation {
+                        *guard = Arc::new(snap);
+                    }
+                    metrics.prize_cache_refreshed();
+                }
+            }
+        });
+    }
+
+    /// Re-reads one activity and its prizes right after an admin change; a deleted activity leaves the cache.
+    /// Call after the change is committed.
+    pub async fn reload_activity(&self, pool: &PgPool, id: Uuid) -> sqlx::Result<()> {
+        let _serial = self.0.reload.lock().await;
+        self.0.generation.fetch_add(1, Ordering::SeqCst);
+        let activity = fetch_activity(&mut *pool.acquire().await?, id).await?;
+        let prizes = match &activity {
+            Some(a) => Some(fetch_activity_prizes(pool, a).await?),
+            None => None,
+        };
+        let mut guard = self.0.snapshot.write().await;
+        let mut snap = CacheSnapshot::clone(&guard);
+        match (activity, prizes) {
+            (Some(a), Some(p)) => {
+                snap.activities.insert(id, a);
+                snap.prizes.insert(id, p);
+            }
+            _ => {
+                snap.activities.remove(&id);
+                snap.prizes.remove(&id);
             }
         }
-    });
+        *guard = Arc::new(snap);
+        Ok(())
+    }
+
+    pub async fn snapshot(&self) -> Arc<CacheSnapshot> {
+        self.0.snapshot.read().await.clone()
+    }
+}
+
+async fn load(pool: &PgPool, previous: &CacheSnapshot) -> sqlx::Result<CacheSnapshot> {
+    let mut activities: Vec<ActivityLite> = sqlx::query_as(ACTIVITY_LITE_SELECT)
+        .fetch_all(pool)
+        .await?;
+    let mut rules = pity_service::all_rules(pool).await?;
+    let mut seeds: HashMap<Uuid, FairSeed> = sqlx::query_as::<_, (Uuid, String, String)>(
+        "SELECT activity_id, server_seed, server_seed_hash FROM activity_fair_seeds"
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|(aid, server_seed, server_seed_hash)| (aid, FairSeed { server_seed, server_seed_hash }))
+    .collect();
+    for a in &mut activities {
+        a.pity_rules = rules.remove(&a.id).unwrap_or_default();
+        a.fair_seed = seeds.remove(&a.id).filter(|_| a.provably_fair);
+    }
+    let rows: Vec<(Uuid, Uuid, String, i32, i32)> = sqlx::query_as(
+        "SELECT id, activity_id, name, probability, tier FROM prizes WHERE is_enabled=true ORDER BY activity_id, id"
+    )
+    .fetch_all(pool)
+    .await?;
+    let mut grouped: HashMap<Uuid, Vec<PrizeLite>> = HashMap::new();
+    for (id, activity_id, name, probability, tier) in rows {
+        grouped.entry(activity_id).or_default().push(PrizeLite { id, activity_id, name, probability, tier });
+    }
+    let mut prizes = HashMap::with_capacity(activities.len());
+    for a in &activities {
+        let mut p = ActivityPrizes::new(&a.selection, grouped.remove(&a.id).unwrap_or_default());
+        let known = previous.prizes.get(&a.id).and_then(|p| p.fair_selection.as_deref());
+        p.pin_fair_selection(pool, a, known).await?;
+        prizes.insert(a.id, p);
+    }
+    Ok(CacheSnapshot { activities: activities.into_iter().map(|a| (a.id, a)).collect(), prizes })
+}
+
+/// Reads the enabled prizes of one activity straight from the DB (cache miss).
+pub async fn fetch_activity_prizes(pool: &PgPool, activity: &ActivityLite) -> sqlx::Result<ActivityPrizes> {
+    let rows: Vec<(Uuid, String, i32, i32)> = sqlx::query_as(
+        "SELECT id, name, probability, tier FROM prizes WHERE is_enabled=true AND activity_id=$1 ORDER BY id"
+    )
+    .bind(activity.id)
+    .fetch_all(pool)
+    .await?;
+    let prizes = rows
+        .into_iter()
+        .map(|(id, name, probability, tier)| PrizeLite { id, activity_id: activity.id, name, probability, tier })
+        .collect();
+    let mut prizes = ActivityPrizes::new(&activity.selection, prizes);
+    prizes.pin_fair_selection(pool, activity, None).await?;
+    Ok(prizes)
 }
 
-pub async fn snapshot() -> Vec<PrizeLite> {
-    get_cache().await.read().await.clone()
+/// Reads one activity straight from the DB (cache miss or inside a transaction).
+pub async fn fetch_activity(conn: &mut PgConnection, id: Uuid) -> sqlx::Result<Option<ActivityLite>> {
+    let activity = sqlx::query_as::<_, ActivityLite>(&format!("{} WHERE id=$1", ACTIVITY_LITE_SELECT))
+        .bind(id)
+        .fetch_optional(&mut *conn)
+        .await?;
+    let Some(mut activity) = activity else { return Ok(None) };
+    activity.pity_rules = pity_service::rules_of(&mut *conn, id).await?;
+    if activity.provably_fair {
+        activity.fair_seed = fair_service::seed_of(&mut *conn, id).await?;
+    }
+    Ok(Some(activity))
 }
@@ -0,0 +1,153 @@
+use std::sync::Arc;
+
+use crate::models::{PrizeSelectorKind, ProbabilityUnit, SoldOutPolicy};
+
+/// Selection settings of an activity (`activities.prize_selector`, `probability_unit`, `no_win_weight`,
+/// `sold_out_policy`).
+#[derive(Debug, Clone, Copy, Default, sqlx::FromRow)]
+pub struct SelectionConfig {
+    pub prize_selector: PrizeSelectorKind,
+    pub probability_unit: ProbabilityUnit,
+    pub no_win_weight: i32,
+    pub sold_out_policy: SoldOutPolicy,
+}
+
+impl SelectionConfig {
+    /// Weight of drawing nothing, given the prize weights in this unit.
+    pub fn no_win_weight(&self, weights: &[u64]) -> u64 {
+        match self.probability_unit.scale() {
+            Some(scale) => scale.saturating_sub(weights.iter().sum()),
+            None => self.no_win_weight.max(0) as u64,
+        }
+    }
+
+    /// Builds the configured selector over prizes with the given `probability` values (negatives count as 0).
+    pub fn build(&self, probabilities: &[i32]) -> Arc<dyn PrizeSelector> {
+        let weights: Vec<u64> = probabilities.iter().map(|p| (*p).max(0) as u64).collect();
+        let no_win = self.no_win_weight(&weights);
+        match self.prize_selector {
+            PrizeSelectorKind::Linear => Arc::new(LinearSelector::new(weights, no_win)),
+            PrizeSelectorKind::Alias => Arc::new(AliasSelector::new(&weights, no_win)),
+        }
+    }
+}
+
+/// Picks one prize out of a fixed weighted list.
+pub trait PrizeSelector: Send + Sync + std::fmt::Debug {
+    /// Index into the prize list the selector was built from for a uniform `roll` in [0, 1);
+    /// None means no prize. The same roll always selects the same prize (provably fair draws rely on it).
+    fn select(&self, roll: f64) -> Option<usize>;
+}
+
+/// Cumulative-weight scan, O(n) per draw and cheap to build.
+#[derive(Debug, Clone)]
+pub struct LinearSelector {
+    weights: Vec<u64>,
+    total: u64,
+}
+
+impl LinearSelector {
+    pub fn new(weights: Vec<u64>, no_win_weight: u64) -> Self {
+        let total = weights.iter().sum::<u64>() + no_win_weight;
+        Self { weights, total }
+    }
+}
+
+impl PrizeSelector for LinearSelector {
+    fn select(&self, roll: f64) -> Option<usize> {
+        if self.total == 0 {
+            return None;
+        }
+        let mut target = ((roll * self.total as f64) as u64).min(self.total - 1);
+        for (i, w) in self.weights.iter().enumerate() {
+            if target < *w {
+                return Some(i);
+            }
+            target -= w;
+        }
+        None
+    }
+}
+
+/// Vose's alias method: O(n) to build, O(1) per draw. The last slot stands for "no prize".
+#[derive(Debug, Clone)]
+pub struct AliasSelector {
+    prob: Vec<f64>,
+    alias: Vec<usize>,
+}
+
+impl AliasSelector {
+    pub fn new(weights: &[u64], no_win_weight: u64) -> Self {
+        let n = weights.len() + 1;
+        let total = weights.iter().sum::<u64>() + no_win_weight;
+        if total == 0 {
+            // nothing can be won: every slot aliases to "no prize"
+            return Self { prob: vec![0.0; n], alias: vec![n - 1; n] };
+        }
+        let mut scaled: Vec<f64> = weights
+            .iter()
+            .chain(std::iter::once(&no_win_weight))
+            .map(|w| *w as f64 * n as f64 / total as f64)
+            .collect();
+        let mut prob = vec![1.0; n];
+        let mut alias: Vec<usize> = (0..n).collect();
+        let (mut small, mut large): (Vec<usize>, Vec<usize>) = (0..n).partition(|i| scaled[*i] < 1.0);
+        while let (Some(s), Some(&l)) = (small.pop(), large.last()) {
+            prob[s] = scaled[s];
+            alias[s] = l;
+            scaled[l] -= 1.0 - scaled[s];
+            if scaled[l] < 1.0 {
+                large.pop();
+                small.push(l);
+            }
+        }
+        // leftovers are 1.0 up to rounding error and keep their own slot
+        Self { prob, alias }
+    }
+}
+
+impl PrizeSelector for AliasSelector {
+    fn select(&self, roll: f64) -> Option<usize> {
+        // integer part picks the slot, the fraction decides between the slot and its alias
+        let x = roll * self.prob.len() as f64;
+        let slot = (x as usize).min(self.prob.len() - 1);
+        let i = if x - (slot as f64) < self.prob[slot] { slot } else { self.alias[slot] };
+        (i + 1 < self.prob.len()).then_some(i)
+    }
+}
+
+
+/// Prizes to try, in order, when prize `selected` is sold out; `tiers[i]` is the tier of prize i.
+/// Redraw returns every other prize (the pick among them is weighted, see `redraw`);
+/// downgrade returns the strictly lower tiers, nearest first.
+pub fn fallback_candidates(policy: SoldOutPolicy, tiers: &[i32], selected: usize) -> Vec<usize> {
+    let others = (0..tiers.len()).filter(|i| *i != selected);
+    match policy {
+        SoldOutPolicy::Lose => Vec::new(),
+        SoldOutPolicy::Redraw => others.collect(),
+        SoldOutPolicy::Downgrade => {
+            let mut lower: Vec<usize> = others.filter(|i| tiers[*i] > tiers[selected]).collect();
+            lower.sort_by_key(|i| (tiers[*i], *i));
+            lower
+        }
+    }
+}
+
+/// Weighted pick among in-stock candidates given as (candidate, weight); `roll` is uniform in [0, 1).
+/// Mirrors the redraw branch of `LUA_DRAW`.
+pub fn redraw(candidates: &[(usize, u64)], roll: f64) -> Option<usize> {
+    let total: u64 = candidates.iter().map(|(_, w)| w).sum();
+    if total == 0 {
+        return None;
+    }
+    let target = roll * total as f64;
+    let mut acc = 0u64;
+    for (i, w) in candidates {
+        acc += w;
+        if target < acc as f64 {
+            return Some(*i);
+        }
+    }
+    // rounding with roll close to 1
+    candidates.iter().rev().find(|(_, w)| *w > 0).map(|(i, _)| *i)
+}
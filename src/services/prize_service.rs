@@ -1,7 +1,10 @@
-use chrono::{DateTime, Utc};
 use serde::Serialize;
-use sqlx::{types::Uuid, PgPool, Postgres, Transaction, Row};
-use crate::models::Prize;
+use sqlx::{types::Uuid, PgConnection, PgPool, Postgres, Transaction, Row};
+use crate::error::{AppError, Reason};
+use crate::models::{Prize, ProbabilityUnit};
+use crate::services::stock_sync;
+use crate::validation::Validator;
+use redis::aio::ConnectionManager as RedisManager;
 
 #[derive(Serialize, sqlx::FromRow, Debug, Clone)]
 pub struct EnabledPrize { pub id: Uuid, pub name: String, pub remaining_count: i64, pub probability: i32 }
@@ -14,37 +17,113 @@ pub async fn list_enabled_prizes(pool: &PgPool) -> sqlx::Result<Vec<EnabledPrize
     .await
 }
 
+const PRIZE_COLUMNS: &str = "id, activity_id, name, description, total_count, remaining_count, probability, tier, is_enabled, created_at, updated_at";
+
 pub async fn list_prizes(pool: &PgPool) -> sqlx::Result<Vec<Prize>> {
-    sqlx::query_as::<_, Prize>(
-        r#"SELECT id, activity_id, name, description, total_count, remaining_count, probability, is_enabled, created_at, updated_at FROM prizes ORDER BY created_at DESC"#
+    sqlx::query_as::<_, Prize>(&format!("SELECT {} FROM prizes ORDER BY created_at DESC", PRIZE_COLUMNS))
+        .fetch_all(pool)
+        .await
+}
+
+pub async fn get_prize(pool: &PgPool, id: Uuid) -> sqlx::Result<Option<Prize>> {
+    sqlx::query_as::<_, Prize>(&format!("SELECT {} FROM prizes WHERE id=$1", PRIZE_COLUMNS))
+        .bind(id)
+        .fetch_optional(pool)
+        .await
+}
+
+pub const DEFAULT_PRIZE_TIER: i32 = 1;
+
+/// How much probability the enabled prizes of an activity already take.
+#[derive(Debug, Clone, Copy)]
+pub struct ProbabilityBudget {
+    pub unit: ProbabilityUnit,
+    pub used: i64,
+}
+
+impl ProbabilityBudget {
+    /// Whether an enabled prize with `probability` still fits; weights always do.
+    pub fn fits(&self, probability: i32) -> bool {
+        self.unit.scale().is_none_or(|scale| self.used + i64::from(probability.max(0)) <= scale as i64)
+    }
+
+    /// Validation code for a budget that does not fit.
+    pub fn over_code(&self) -> &'static str {
+        match self.unit {
+            ProbabilityUnit::BasisPoints => "probability_over_basis_points",
+            _ => "probability_over_percent",
+        }
+    }
+}
+
+/// Budget of an activity not counting prize `exclude`; None if the activity does not exist.
+pub async fn probability_budget<'e>(ex: impl sqlx::PgExecutor<'e>, activity_id: Uuid, exclude: Option<Uuid>) -> sqlx::Result<Option<ProbabilityBudget>> {
+    let row: Option<(ProbabilityUnit, i64)> = sqlx::query_as(
+        r#"SELECT a.probability_unit,
+                  COALESCE((SELECT sum(GREATEST(p.probability, 0)) FROM prizes p
+                            WHERE p.activity_id = a.id AND p.is_enabled AND p.id IS DISTINCT FROM $2), 0)::int8
+           FROM activities a WHERE a.id=$1"#
     )
-    .fetch_all(pool)
-    .await
+    .bind(activity_id)
+    .bind(exclude)
+    .fetch_optional(ex)
+    .await?;
+    Ok(row.map(|(unit, used)| ProbabilityBudget { unit, used }))
 }
 
-pub async fn create_prize(
-    pool: &PgPool,
-    id: Uuid,
-    activity_id: Uuid,
-    name: String,
-    description: Option<String>,
-    total_count: i64,
-    probability: i32,
-    is_enabled: bool,
-) -> sqlx::Result<()> {
+/// Like `probability_budget`, after locking the activity row so concurrent prize changes of one activity check
+/// their budget one after another.
+async fn lock_budget(conn: &mut PgConnection, activity_id: Uuid, exclude: Option<Uuid>) -> sqlx::Result<Option<ProbabilityBudget>> {
+    let found: Option<i32> = sqlx::query_scalar("SELECT 1 FROM activities WHERE id=$1 FOR UPDATE")
+        .bind(activity_id)
+        .fetch_optional(&mut *conn)
+        .await?;
+    match found {
+        Some(_) => probability_budget(&mut *conn, activity_id, exclude).await,
+        None => Ok(None),
+    }
+}
+
+/// Percent and basis-point probabilities of the enabled prizes must not add up to more than the whole.
+fn check_budget(budget: Option<ProbabilityBudget>, probability: i32) -> Result<(), AppError> {
+    let mut v = Validator::new();
+    match budget {
+        None => v.check(false, "activity_id", "activity_not_found"),
+        Some(budget) => v.check(budget.fits(probability), "probability", budget.over_code()),
+    };
+    v.finish()
+}
+
+pub struct NewPrize {
+    pub activity_id: Uuid,
+    pub name: String,
+    pub description: Option<String>,
+    pub total_count: i64,
+    pub probability: i32,
+    pub tier: i32,
+    pub is_enabled: bool,
+}
+
+/// Inserts a prize; an enabled one must fit the probability budget of its activity.
+pub async fn create_prize(pool: &PgPool, id: Uuid, p: NewPrize) -> Result<(), AppError> {
+    let mut tx = pool.begin().await?;
+    let budget = lock_budget(&mut tx, p.activity_id, None).await?;
+    check_budget(budget, if p.is_enabled { p.probability } else { 0 })?;
     sqlx::query(
-        r#"INSERT INTO prizes (id, activity_id, name, description, total_count, remaining_count, probability, is_enabled, created_at, updated_at)
-           VALUES ($1,$2,$3,$4,$5,$5,$6,$7, now(), now())"#
+        r#"INSERT INTO prizes (id, activity_id, name, description, total_count, remaining_count, probability, tier, is_enabled, created_at, updated_at)
+           VALUES ($1,$2,$3,$4,$5,$5,$6,$7,$8, now(), now())"#
     )
     .bind(id)
-    .bind(activity_id)
-    .bind(name)
-    .bind(description)
-    .bind(total_count)
-    .bind(probability)
-    .bind(is_enabled)
-    .execute(pool)
+    .bind(p.activity_id)
+    .bind(p.name)
+    .bind(p.description)
+    .bind(p.total_count)
+    .bind(p.probability)
+    .bind(p.tier)
+    .bind(p.is_enabled)
+    .execute(&mut *tx)
     .await?;
+    tx.commit().await?;
     Ok(())
 }
 
@@ -58,3 +137,86 @@ pub async fn decrement_stock(tx: &mut Transaction<'_, Postgres>, prize_id: Uuid)
     .await?;
     Ok(row.map(|r| r.get::<i64, _>("remaining_count")))
 }
+
+/// Fields to change on a prize; None keeps the current value.
+#[derive(Debug, Default)]
+pub struct PrizePatch {
+    pub name: Option<String>,
+    pub description: Option<Option<String>>,
+    pub probability: Option<i32>,
+    pub tier: Option<i32>,
+    pub is_enabled: Option<bool>,
+    /// added to both `total_count` and `remaining_count`; negative to take stock back
+    pub stock_delta: Option<i64>,
+}
+
+/// Applies `patch` and returns the updated prize; None if it does not exist. A change that adds probability
+/// to the enabled prizes must fit the budget; with the Redis backend stock taken back must also be left after
+/// the sales not flushed yet.
+pub async fn update_prize(pool: &PgPool, redis: Option<&RedisManager>, id: Uuid, patch: PrizePatch) -> Result<Option<Prize>, AppError> {
+    let delta = patch.stock_delta.unwrap_or(0);
+    let insufficient = || AppError::bad_request(Reason::InsufficientStock, "库存不足，无法扣减");
+    let mut tx = pool.begin().await?;
+    // activity first, then the prize: the order create_prize and delete_activity lock in
+    let activity_id: Option<Uuid> = sqlx::query_scalar("SELECT activity_id FROM prizes WHERE id=$1")
+        .bind(id)
+        .fetch_optional(&mut *tx)
+        .await?;
+    let Some(activity_id) = activity_id else { return Ok(None) };
+    let budget = lock_budget(&mut tx, activity_id, Some(id)).await?;
+    // the row lock also holds off the flusher's commit for this prize
+    let current = sqlx::query_as::<_, Prize>(&format!("SELECT {} FROM prizes WHERE id=$1 FOR UPDATE", PRIZE_COLUMNS))
+        .bind(id)
+        .fetch_optional(&mut *tx)
+        .await?;
+    let Some(current) = current else { return Ok(None) };
+    let probability = patch.probability.unwrap_or(current.probability);
+    // only a change that adds probability to the enabled prizes can break the budget
+    if patch.is_enabled.unwrap_or(current.is_enabled) && (!current.is_enabled || probability > current.probability) {
+        check_budget(budget, probability)?;
+    }
+    if current.remaining_count + delta < 0 {
+        return Err(insufficient());
+    }
+    let mut taken = None;
+    if let (Some(redis), true) = (redis, delta < 0) {
+        let mut redis = redis.clone();
+        match stock_sync::take_back_stock(&mut tx, &mut redis, id, -delta, current.remaining_count).await? {
+            None => return Err(insufficient()),
+            Some(lowered) => taken = lowered.then_some(redis),
+        }
+    }
+    let updated = sqlx::query_as::<_, Prize>(&format!(
+        r#"UPDATE prizes SET name=$2, description=$3, probability=$4, tier=$5, is_enabled=$6,
+                  total_count = total_count + $7, remaining_count = remaining_count + $7, updated_at=now()
+           WHERE id=$1 RETURNING {}"#,
+        PRIZE_COLUMNS
+    ))
+    .bind(id)
+    .bind(patch.name.unwrap_or(current.name))
+    .bind(patch.description.unwrap_or(current.description))
+    .bind(patch.probability.unwrap_or(current.probability))
+    .bind(patch.tier.unwrap_or(current.tier))
+    .bind(patch.is_enabled.unwrap_or(current.is_enabled))
+    .bind(delta)
+    .fetch_one(&mut *tx)
+    .await;
+    let updated = match updated {
+        Ok(p) => tx.commit().await.map(|_| p),
+        Err(e) => Err(e),
+    };
+    if let (Err(_), Some(mut redis)) = (&updated, taken) {
+        if let Err(e) = stock_sync::give_back_stock(&mut redis, id, -delta).await {
+            tracing::error!(error = ?e, prize_id = %id, "failed to give back redis stock, reconcile the prize");
+        }
+    }
+    Ok(Some(updated?))
+}
+
+/// Deletes a prize; draw records keep its name. Returns the activity it belonged to, None if it does not exist.
+pub async fn delete_prize(pool: &PgPool, id: Uuid) -> sqlx::Result<Option<Uuid>> {
+    sqlx::query_scalar("DELETE FROM prizes WHERE id=$1 RETURNING activity_id")
+        .bind(id)
+        .fetch_optional(pool)
+        .await
+}
@@ -0,0 +1,148 @@
+use chrono::{DateTime, NaiveDate, NaiveTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use redis::aio::ConnectionManager as RedisManager;
+use serde::Serialize;
+use sqlx::{types::Uuid, PgExecutor, PgPool};
+
+use crate::error::{AppError, Reason};
+
+/// Per-user limits of an activity; `None` means unlimited.
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct QuotaLimits {
+    pub max_draws_total: Option<i32>,
+    pub max_draws_daily: Option<i32>,
+    pub max_wins_total: Option<i32>,
+    pub quota_timezone: String,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct QuotaUsage { pub draws_total: i64, pub draws_today: i64, pub wins_total: i64 }
+
+/// The calendar day containing `now` in the quota timezone.
+#[derive(Debug, Clone)]
+pub struct DayWindow { pub key: String, pub start: DateTime<Utc>, pub end: DateTime<Utc> }
+
+pub struct QuotaKeys { pub total: String, pub daily: String, pub wins: String }
+
+#[derive(Serialize, Debug, Clone)]
+pub struct QuotaRemaining {
+    pub activity_id: Uuid,
+    pub activity_name: String,
+    pub draws_total_remaining: Option<i64>,
+    pub draws_today_remaining: Option<i64>,
+    pub wins_remaining: Option<i64>,
+}
+
+fn local_midnight(tz: Tz, date: NaiveDate) -> DateTime<Utc> {
+    // midnight can fall into a DST gap in a few zones; the day then starts at the first valid hour
+    (0..3)
+        .find_map(|h| tz.from_local_datetime(&date.and_time(NaiveTime::from_hms_opt(h, 0, 0)?)).earliest())
+        .map(|t| t.with_timezone(&Utc))
+        .unwrap_or_else(|| Utc.from_utc_datetime(&date.and_time(NaiveTime::MIN)))
+}
+
+pub fn day_window(timezone: &str, now: DateTime<Utc>) -> DayWindow {
+    let tz: Tz = timezone.parse().unwrap_or(Tz::UTC);
+    let today = now.with_timezone(&tz).date_naive();
+    let tomorrow = today.succ_opt().unwrap_or(today);
+    DayWindow {
+        key: today.format("%Y%m%d").to_string(),
+        start: local_midnight(tz, today),
+        end: local_midnight(tz, tomorrow),
+    }
+}
+
+pub fn redis_keys(activity_id: Uuid, uid: Uuid, day: &DayWindow) -> QuotaKeys {
+    let prefix = format!("lottery:quota:{}:{}", activity_id, uid);
+    QuotaKeys {
+        total: format!("{}:total", prefix),
+        daily: format!("{}:daily:{}", prefix, day.key),
+        wins: format!("{}:wins", prefix),
+    }
+}
+
+fn left(limit: Option<i32>, used: i64) -> Option<i64> {
+    limit.map(|l| (i64::from(l) - used).max(0))
+}
+
+impl QuotaLimits {
+    /// Rejects the draw when the total or daily quota is used up.
+    /// Returns whether the user may still win (false once the wins quota is reached).
+    pub fn check(&self, usage: &QuotaUsage) -> Result<bool, AppError> {
+        if left(self.max_draws_total, usage.draws_total) == Some(0) {
+            return Err(AppError::bad_request(Reason::DrawQuotaExhausted, "抽奖次数已用完"));
+        }
+        if left(self.max_draws_daily, usage.draws_today) == Some(0) {
+            return Err(AppError::bad_request(Reason::DailyQuotaExhausted, "今日抽奖次数已用完"));
+        }
+        Ok(left(self.max_wins_total, usage.wins_total) != Some(0))
+    }
+
+    pub fn is_unlimited(&self) -> bool {
+        self.max_draws_total.is_none() && self.max_draws_daily.is_none() && self.max_wins_total.is_none()
+    }
+}
+
+pub async fn usage_sql<'e, E: PgExecutor<'e>>(ex: E, uid: Uuid, activity_id: Uuid, day: &DayWindow) -> sqlx::Result<QuotaUsage> {
+    let (draws_total, draws_today, wins_total): (i64, i64, i64) = sqlx::query_as(
+        r#"SELECT COUNT(*), COUNT(*) FILTER (WHERE created_at >= $3), COUNT(prize_name)
+           FROM lottery_records WHERE user_id=$1 AND activity_id=$2"#
+    )
+    .bind(uid)
+    .bind(activity_id)
+    .bind(day.start)
+    .fetch_one(ex)
+    .await?;
+    Ok(QuotaUsage { draws_total, draws_today, wins_total })
+}
+
+pub async fn usage_redis(redis: &mut RedisManager, keys: &QuotaKeys) -> redis::RedisResult<QuotaUsage> {
+    let (total, today, wins): (Option<i64>, Option<i64>, Option<i64>) = redis::cmd("MGET")
+        .arg(&keys.total)
+        .arg(&keys.daily)
+        .arg(&keys.wins)
+        .query_async(redis)
+        .await?;
+    Ok(QuotaUsage { draws_total: total.unwrap_or(0), draws_today: today.unwrap_or(0), wins_total: wins.unwrap_or(0) })
+}
+
+#[derive(sqlx::FromRow)]
+struct ActivityQuotaRow {
+    id: Uuid,
+    name: String,
+    #[sqlx(flatten)]
+    limits: QuotaLimits,
+}
+
+/// Remaining quota of `uid` in every running activity; read from Redis when the draw backend keeps them there.
+pub async fn remaining_for_user(pool: &PgPool, redis: Option<&RedisManager>, uid: Uuid) -> Result<Vec<QuotaRemaining>, AppError> {
+    let rows = sqlx::query_as::<_, ActivityQuotaRow>(
+        r#"SELECT id, name, max_draws_total, max_draws_daily, max_wins_total, quota_timezone
+           FROM activities WHERE status='ongoing' AND start_time<=now() AND end_time>=now()
+           ORDER BY start_time"#
+    )
+    .fetch_all(pool)
+    .await?;
+    let mut redis = redis.cloned();
+    let now = Utc::now();
+    let mut out = Vec::with_capacity(rows.len());
+    for row in rows {
+        let usage = if row.limits.is_unlimited() {
+            QuotaUsage::default()
+        } else {
+            let day = day_window(&row.limits.quota_timezone, now);
+            match redis.as_mut() {
+                Some(r) => usage_redis(r, &redis_keys(row.id, uid, &day)).await.map_err(|e| anyhow::anyhow!(e))?,
+                None => usage_sql(pool, uid, row.id, &day).await?,
+            }
+        };
+        out.push(QuotaRemaining {
+            activity_id: row.id,
+            activity_name: row.name,
+            draws_total_remaining: left(row.limits.max_draws_total, usage.draws_total),
+            draws_today_remaining: left(row.limits.max_draws_daily, usage.draws_today),
+            wins_remaining: left(row.limits.max_wins_total, usage.wins_total),
+        });
+    }
+    Ok(out)
+}
@@ -0,0 +1,323 @@
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, TimeZone, Utc};
+use redis::aio::ConnectionManager as RedisManager;
+use redis::streams::{StreamId, StreamReadReply};
+use serde::Serialize;
+use sqlx::{types::Uuid, PgPool};
+
+use crate::metrics::Metrics;
+use crate::state::Jobs;
+use crate::services::fair_service::{FairInputs, FairSteps};
+use crate::services::feed_service::{LiveFeed, Win};
+use crate::services::pity_service;
+
+// Durable outbox of draw records: `LUA_DRAW` appends one entry per accepted draw to this stream in the
+// same script that consumes stock, and the consumer below batch-inserts them into `lottery_records`.
+// Entries are only acked (and deleted) after the Postgres transaction committed, so a crash or DB outage
+// just leaves them pending until a later retry; record ids come from the draw, inserts are idempotent.
+pub const STREAM_KEY: &str = "lottery:records";
+pub const DEAD_LETTER_KEY: &str = "lottery:records:dead";
+const GROUP: &str = "persist";
+const BATCH: usize = 500;
+const BLOCK_MS: usize = 1000;
+// entries left pending this long by another (dead) consumer are taken over
+const CLAIM_IDLE_MS: usize = 60_000;
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone)]
+pub struct OutboxRecord {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub activity_id: Uuid,
+    pub prize_id: Option<Uuid>,
+    pub prize_name: Option<String>,
+    pub created_at: DateTime<Utc>,
+    /// pity counters (tier, draws since a qualifying win) right after this draw
+    pub pity: Vec<(i32, i32)>,
+    /// roll inputs of a provably fair draw
+    pub fair: Option<FairInputs>,
+    /// what decided a provably fair draw after its roll
+    pub fair_steps: Option<FairSteps>,
+}
+
+#[derive(Default)]
+pub struct OutboxStats {
+    backlog: AtomicI64,
+    pending: AtomicI64,
+    persisted_total: AtomicU64,
+    failed_batches_total: AtomicU64,
+    dead_lettered_total: AtomicU64,
+}
+
+#[derive(Serialize, Debug, Clone, Copy)]
+pub struct OutboxStatsSnapshot {
+    /// entries written by draws but not yet committed to Postgres
+    pub backlog: i64,
+    /// entries delivered to a consumer and awaiting ack
+    pub pending: i64,
+    pub persisted_total: u64,
+    pub failed_batches_total: u64,
+    pub dead_lettered_total: u64,
+}
+
+impl OutboxStats {
+    pub fn snapshot(&self) -> OutboxStatsSnapshot {
+        OutboxStatsSnapshot {
+            backlog: self.backlog.load(Ordering::Relaxed),
+            pending: self.pending.load(Ordering::Relaxed),
+            persisted_total: self.persisted_total.load(Ordering::Relaxed),
+            failed_batches_total: self.failed_batches_total.load(Ordering::Relaxed),
+            dead_lettered_total: self.dead_lettered_total.load(Ordering::Relaxed),
+        }
+    }
+}
+
+fn parse(entry: &StreamId) -> Option<OutboxRecord> {
+    let uuid = |field: &str| entry.get::<String>(field).and_then(|s| Uuid::parse_str(&s).ok());
+    let ts: i64 = entry.get::<String>("ts")?.parse().ok()?;
+    Some(OutboxRecord {
+        id: uuid("id")?,
+        user_id: uuid("user_id")?,
+        activity_id: uuid("activity_id")?,
+        prize_id: uuid("prize_id"),
+        prize_name: entry.get::<String>("prize_name").filter(|s| !s.is_empty()),
+        created_at: Utc.timestamp_millis_opt(ts).single()?,
+        pity: entry.get::<String>("pity").map(|s| pity_service::parse_counters(&s)).unwrap_or_default(),
+        fair: entry.get::<String>("fair").and_then(|s| FairInputs::decode(&s)),
+        fair_steps: entry.get::<String>("steps").and_then(|s| FairSteps::decode(&s)),
+    })
+}
+
+/// Inserts a batch of records, bumps `users.last_lottery_at` and mirrors pity counters in one transaction;
+/// replays are no-ops.
+pub async fn persist_batch(pool: &PgPool, records: &[OutboxRecord]) -> sqlx::Result<()> {
+    let mut tx = pool.begin().await?;
+    sqlx::query(
+        // an activity or prize deleted while its records were still in the stream is stored as NULL
+        r#"INSERT INTO lottery_records (id, user_id, activity_id, prize_id, prize_name, created_at, server_seed_hash, client_seed, nonce, selection_hash, fair_steps)
+           SELECT r.id, r.user_id, a.id, p.id, r.prize_name, r.created_at, r.server_seed_hash, r.client_seed, r.nonce, r.selection_hash, r.fair_steps
+           FROM UNNEST($1::uuid[], $2::uuid[], $3::uuid[], $4::uuid[], $5::text[], $6::timestamptz[],
+                       $7::text[], $8::text[], $9::int8[], $10::text[], $11::text[])
+                AS r(id, user_id, activity_id, prize_id, prize_name, created_at, server_seed_hash, client_seed, nonce, selection_hash, fair_steps)
+           LEFT JOIN activities a ON a.id = r.activity_id
+           LEFT JOIN prizes p ON p.id = r.prize_id
+           ON CONFLICT (id) DO NOTHING"#
+    )
+    .bind(records.iter().map(|r| r.id).collect::<Vec<_>>())
+    .bind(records.iter().map(|r| r.user_id).collect::<Vec<_>>())
+    .bind(records.iter().map(|r| r.activity_id).collect::<Vec<_>>())
+    .bind(records.iter().map(|r| r.prize_id).collect::<Vec<_>>())
+    .bind(records.iter().map(|r| r.prize_name.clone()).collect::<Vec<_>>())
+    .bind(records.iter().map(|r| r.created_at).collect::<Vec<_>>())
+    .bind(records.iter().map(|r| r.fair.as_ref().map(|f| f.server_seed_hash.clone())).collect::<Vec<_>>())
+    .bind(records.iter().map(|r| r.fair.as_ref().map(|f| f.client_seed.clone())).collect::<Vec<_>>())
+    .bind(records.iter().map(|r| r.fair.as_ref().map(|f| f.nonce)).collect::<Vec<_>>())
+    .bind(records.iter().map(|r| r.fair.as_ref().and_then(|f| f.selection_hash.clone())).collect::<Vec<_>>())
+    .bind(records.iter().map(|r| r.fair_steps.as_ref().map(FairSteps::to_json)).collect::<Vec<_>>())
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query(
+        r#"UPDATE users u SET last_lottery_at = GREATEST(u.last_lottery_at, t.ts), updated_at = now()
+           FROM (SELECT user_id, max(ts) AS ts FROM UNNEST($1::uuid[], $2::timestamptz[]) AS x(user_id, ts) GROUP BY user_id) t
+           WHERE u.id = t.user_id"#
+    )
+    .bind(records.iter().map(|r| r.user_id).collect::<Vec<_>>())
+    .bind(records.iter().map(|r| r.created_at).collect::<Vec<_>>())
+    .execute(&mut *tx)
+    .await?;
+    let pity: Vec<_> = records
+        .iter()
+        .flat_map(|r| r.pity.iter().map(move |(tier, n)| (r.user_id, r.activity_id, *tier, *n, r.created_at)))
+        .collect();
+    pity_service::save(&mut tx, &pity).await?;
+    tx.commit().await
+}
+
+// constraint violations (e.g. the user was deleted meanwhile) will never succeed on retry
+fn is_permanent(e: &sqlx::Error) -> bool {
+    matches!(e, sqlx::Error::Database(db) if db.code().is_some_and(|c| c.starts_with("23")))
+}
+
+async fn ensure_group(conn: &mut RedisManager) -> redis::RedisResult<()> {
+    let r: redis::RedisResult<()> = redis::cmd("XGROUP")
+        .arg("CREATE").arg(STREAM_KEY).arg(GROUP).arg("0").arg("MKSTREAM")
+        .query_async(conn)
+        .await;
+    match r {
+        Err(e) if e.code() == Some("BUSYGROUP") => Ok(()),
+        other => other,
+    }
+}
+
+async fn read(conn: &mut RedisManager, consumer: &str, from: &str, block: bool) -> redis::RedisResult<Vec<StreamId>> {
+    let mut cmd = redis::cmd("XREADGROUP");
+    cmd.arg("GROUP").arg(GROUP).arg(consumer).arg("COUNT").arg(BATCH);
+    if block {
+        cmd.arg("BLOCK").arg(BLOCK_MS);
+    }
+    cmd.arg("STREAMS").arg(STREAM_KEY).arg(from);
+    let reply: Option<StreamReadReply> = cmd.query_async(conn).await?;
+    Ok(reply.map(|r| r.keys.into_iter().flat_map(|k| k.ids).collect()).unwrap_or_default())
+}
+
+async fn ack(conn: &mut RedisManager, ids: &[String]) -> redis::RedisResult<()> {
+    if ids.is_empty() {
+        return Ok(());
+    }
+    redis::pipe()
+        .cmd("XACK").arg(STREAM_KEY).arg(GROUP).arg(ids).ignore()
+        .cmd("XDEL").arg(STREAM_KEY).arg(ids).ignore()
+        .query_async(conn)
+        .await
+}
+
+async fn dead_letter(conn: &mut RedisManager, stats: &OutboxStats, entry: &StreamId, reason: &str) -> redis::RedisResult<()> {
+    let mut cmd = redis::cmd("XADD");
+    cmd.arg(DEAD_LETTER_KEY).arg("*").arg("source_id").arg(&entry.id).arg("reason").arg(reason);
+    for (k, v) in &entry.map {
+        if let Ok(v) = redis::from_redis_value::<String>(v) {
+            cmd.arg(k).arg(v);
+        }
+    }
+    cmd.query_async::<_, ()>(conn).await?;
+    stats.dead_lettered_total.fetch_add(1, Ordering::Relaxed);
+    ack(conn, std::slice::from_ref(&entry.id)).await
+}
+
+async fn refresh_stats(conn: &mut RedisManager, stats: &OutboxStats) -> redis::RedisResult<()> {
+    let (len, pending): (i64, redis::Value) = redis::pipe()
+        .cmd("XLEN").arg(STREAM_KEY)
+        .cmd("XPENDING").arg(STREAM_KEY).arg(GROUP)
+        .query_async(conn)
+        .await?;
+    stats.backlog.store(len, Ordering::Relaxed);
+    if let redis::Value::Bulk(items) = pending {
+        if let Some(v) = items.first() {
+            stats.pending.store(redis::from_redis_value(v).unwrap_or(0), Ordering::Relaxed);
+        }
+    }
+    Ok(())
+}
+
+/// Wins among persisted records, for the live feed.
+fn wins<'a>(records: impl IntoIterator<Item = &'a OutboxRecord>) -> Vec<Win> {
+    records
+        .into_iter()
+        .filter_map(|r| {
+            Some(Win { user_id: r.user_id, activity_id: r.activity_id, prize_id: r.prize_id?, prize_name: r.prize_name.clone()?, at: r.created_at })
+        })
+        .collect()
+}
+
+/// Persists one delivered batch and announces its wins. Returns false when a transient error left entries
+/// pending.
+async fn process(pool: &PgPool, conn: &mut RedisManager, stats: &OutboxStats, feed: &LiveFeed, entries: Vec<StreamId>) -> redis::RedisResult<bool> {
+    let mut parsed = Vec::with_capacity(entries.len());
+    for entry in &entries {
+        match parse(entry) {
+            Some(r) => parsed.push((entry, r)),
+            None => dead_letter(conn, stats, entry, "malformed").await?,
+        }
+    }
+    let records: Vec<OutboxRecord> = parsed.iter().map(|(_, r)| r.clone()).collect();
+    if records.is_empty() {
+        return Ok(true);
+    }
+    match persist_batch(pool, &records).await {
+        Ok(()) => {
+            let ids: Vec<String> = parsed.iter().map(|(e, _)| e.id.clone()).collect();
+            ack(conn, &ids).await?;
+            stats.persisted_total.fetch_add(records.len() as u64, Ordering::Relaxed);
+            feed.announce_wins(pool, &wins(&records)).await;
+            return Ok(true);
+        }
+        Err(e) => {
+            stats.failed_batches_total.fetch_add(1, Ordering::Relaxed);
+            tracing::warn!(error = ?e, size = records.len(), "outbox batch insert failed, retrying per record");
+        }
+    }
+    // isolate the bad record(s) so one poison entry cannot block the whole stream
+    let mut persisted = Vec::new();
+    let mut done = true;
+    for (entry, record) in parsed {
+        match persist_batch(pool, std::slice::from_ref(&record)).await {
+            Ok(()) => {
+                ack(conn, std::slice::from_ref(&entry.id)).await?;
+                stats.persisted_total.fetch_add(1, Ordering::Relaxed);
+                persisted.push(record);
+            }
+            Err(e) if is_permanent(&e) => {
+                tracing::error!(error = ?e, record_id = %record.id, "outbox record rejected by DB, dead-lettered");
+                dead_letter(conn, stats, entry, &e.to_string()).await?;
+            }
+            Err(e) => {
+                tracing::warn!(error = ?e, "outbox persistence failed, will retry");
+                done = false;
+                break;
+            }
+        }
+    }
+    feed.announce_wins(pool, &wins(&persisted)).await;
+    Ok(done)
+}
+
+/// Runs the outbox consumer. `redis` must be a dedicated connection: XREADGROUP blocks it. On shutdown it
+/// stops blocking for new entries and returns once the stream is drained or persisting fails.
+pub fn spawn_outbox_consumer(jobs: &Jobs, pool: PgPool, mut redis: RedisManager, metrics: Arc<Metrics>, feed: LiveFeed) {
+    let stop = jobs.stop_token();
+    jobs.spawn(async move {
+        let consumer = format!("consumer-{}", Uuid::new_v4().simple());
+        let mut backoff = Duration::from_millis(200);
+        let mut last_claim: Option<Instant> = None;
+        loop {
+            let stopping = stop.is_cancelled();
+            let mut drained = false;
+            let step: redis::RedisResult<bool> = async {
+                if last_claim.is_none_or(|t| t.elapsed() > Duration::from_secs(30)) {
+                    ensure_group(&mut redis).await?;
+                    // take over entries a crashed instance left unacked; they are then re-read as our own pending
+                    redis::cmd("XAUTOCLAIM")
+                        .arg(STREAM_KEY).arg(GROUP).arg(&consumer).arg(CLAIM_IDLE_MS).arg("0-0")
+                        .arg("COUNT").arg(BATCH).arg("JUSTID")
+                        .query_async::<_, redis::Value>(&mut redis)
+                        .await?;
+                    last_claim = Some(Instant::now());
+                }
+                refresh_stats(&mut redis, &metrics.outbox).await?;
+                // own pending entries (earlier failures) first, then new ones
+                let mut entries = read(&mut redis, &consumer, "0", false).await?;
+                if entries.is_empty() {
+                    entries = read(&mut redis, &consumer, ">", !stopping).await?;
+                }
+                if entries.is_empty() {
+                    drained = true;
+                    return Ok(true);
+                }
+                process(&pool, &mut redis, &metrics.outbox, &feed, entries).await
+            }
+            .await;
+            if stopping && (drained || !matches!(step, Ok(true))) {
+                // whatever is left stays in the stream for the next consumer
+                tracing::info!(drained, "outbox consumer stopped");
+                break;
+            }
+            match step {
+                Ok(true) => {
+                    backoff = Duration::from_millis(200);
+                    continue;
+                }
+                Ok(false) => {}
+                Err(e) => tracing::warn!(error = ?e, "outbox consumer redis error"),
+            }
+            // shutdown cuts the backoff short for one last attempt
+            tokio::select! {
+                _ = tokio::time::sleep(backoff) => {}
+                _ = stop.cancelled() => {}
+            }
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    });
+}
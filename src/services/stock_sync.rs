@@ -1,38 +1,365 @@
+use std::future::Future;
+use std::sync::Arc;
 use std::time::Duration;
-use sqlx::{PgPool, types::Uuid};
+use serde::Serialize;
+use sqlx::{PgConnection, PgPool, types::Uuid};
 use redis::aio::ConnectionManager as RedisManager;
 
-pub fn spawn_redis_delta_flusher(pool: PgPool, redis: std::sync::Arc<RedisManager>) {
-    tokio::spawn(async move {
-        let mut tick = tokio::time::interval(Duration::from_secs(5));
+use crate::config::SharedConfig;
+use crate::metrics::Metrics;
+use crate::state::Jobs;
+use crate::redis_scripts::{LUA_ACK_SOLD, LUA_CLAIM_SOLD, LUA_TAKE_BACK_STOCK};
+
+// Sold deltas travel Redis -> Postgres in three steps so none is lost or applied twice:
+// 1) claim: `lottery:sold:{id}` is moved into the batch hash `lottery:sold:pending:{id}` with a fresh batch id;
+// 2) commit: the batch id is recorded in `stock_flush_batches` and `remaining_count` is lowered in one transaction;
+// 3) ack: the pending hash is deleted.
+// A failure before 3) leaves the batch pending and the next run replays it; the batch id makes the replay of an
+// already committed batch a no-op.
+
+pub fn sold_key(prize_id: Uuid) -> String {
+    format!("lottery:sold:{}", prize_id)
+}
+
+pub fn pending_key(prize_id: Uuid) -> String {
+    format!("lottery:sold:pending:{}", prize_id)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PendingDelta {
+    pub batch_id: Uuid,
+    pub delta: i64,
+}
+
+/// Where sold deltas accumulate between flushes; Redis in production.
+pub trait SoldDeltaStore {
+    /// Returns the pending batch of a prize, creating one from the live delta if none is pending.
+    fn claim(&mut self, prize_id: Uuid) -> impl Future<Output = anyhow::Result<Option<PendingDelta>>> + Send;
+    /// Forgets a batch after it was committed to Postgres.
+    fn ack(&mut self, prize_id: Uuid, batch_id: Uuid) -> impl Future<Output = anyhow::Result<()>> + Send;
+}
+
+pub struct RedisDeltaStore {
+    conn: RedisManager,
+    metrics: Arc<Metrics>,
+}
+
+impl RedisDeltaStore {
+    pub fn new(conn: RedisManager, metrics: Arc<Metrics>) -> Self {
+        Self { conn, metrics }
+    }
+}
+
+impl SoldDeltaStore for RedisDeltaStore {
+    async fn claim(&mut self, prize_id: Uuid) -> anyhow::Result<Option<PendingDelta>> {
+        let _timer = self.metrics.script_timer("claim_sold");
+        let r: Vec<String> = LUA_CLAIM_SOLD
+            .key(sold_key(prize_id))
+            .key(pending_key(prize_id))
+            .arg(Uuid::new_v4().to_string())
+            .invoke_async(&mut self.conn)
+            .await?;
+        match r.as_slice() {
+            [batch, delta] => Ok(Some(PendingDelta { batch_id: Uuid::parse_str(batch)?, delta: delta.parse()? })),
+            _ => Ok(None),
+        }
+    }
+
+    async fn ack(&mut self, prize_id: Uuid, batch_id: Uuid) -> anyhow::Result<()> {
+        let _timer = self.metrics.script_timer("ack_sold");
+        LUA_ACK_SOLD
+            .key(pending_key(prize_id))
+            .arg(batch_id.to_string())
+            .invoke_async::<_, i64>(&mut self.conn)
+            .await?;
+        Ok(())
+    }
+}
+
+/// Applies one batch to Postgres. Returns None if this batch had already been applied or its prize is gone
+/// (the batch is then acked and dropped), else the units that exceeded `remaining_count` (oversold) and were
+/// dropped to keep it at 0.
+async fn commit_batch(pool: &PgPool, prize_id: Uuid, batch: PendingDelta) -> sqlx::Result<Option<i64>> {
+    let mut tx = pool.begin().await?;
+    // checked before the insert: the batch row references the prize, so a deleted one would fail it forever
+    let Some(remaining) = sqlx::query_scalar::<_, i64>("SELECT remaining_count FROM prizes WHERE id=$1 FOR UPDATE")
+        .bind(prize_id)
+        .fetch_optional(&mut *tx)
+        .await?
+    else {
+        tracing::info!(prize_id = %prize_id, delta = batch.delta, "prize deleted, dropping its sold delta");
+        return Ok(None);
+    };
+    let inserted = sqlx::query(
+        "INSERT INTO stock_flush_batches (batch_id, prize_id, delta, applied_at) VALUES ($1,$2,$3, now()) ON CONFLICT (batch_id) DO NOTHING"
+    )
+    .bind(batch.batch_id)
+    .bind(prize_id)
+    .bind(batch.delta)
+    .execute(&mut *tx)
+    .await?
+    .rows_affected();
+    if inserted == 0 {
+        return Ok(None);
+    }
+    sqlx::query(
+        "UPDATE prizes SET remaining_count = GREATEST(0, remaining_count - $1), updated_at=now() WHERE id=$2"
+    )
+    .bind(batch.delta)
+    .bind(prize_id)
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    let clamped = (batch.delta - remaining).max(0);
+    if clamped > 0 {
+        tracing::error!(prize_id = %prize_id, delta = batch.delta, remaining, clamped, "sold delta exceeds remaining_count, prize oversold");
+    }
+    Ok(Some(clamped))
+}
+
+/// Flushes the sold delta of one prize. Returns the delta newly applied to Postgres and the oversold units
+/// dropped on the way.
+pub async fn flush_prize<S: SoldDeltaStore>(pool: &PgPool, store: &mut S, prize_id: Uuid) -> anyhow::Result<(i64, i64)> {
+    let Some(batch) = store.claim(prize_id).await? else {
+        return Ok((0, 0));
+    };
+    let applied = commit_batch(pool, prize_id, batch).await?;
+    store.ack(prize_id, batch.batch_id).await?;
+    Ok(applied.map_or((0, 0), |clamped| (batch.delta, clamped)))
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FlushReport {
+    pub flushed: i64,
+    /// sold units that exceeded `remaining_count`
+    pub oversold: i64,
+    pub failed_prizes: usize,
+}
+
+/// One flusher pass over every prize; a failing prize keeps its batch pending and does not stop the others.
+pub async fn flush_once<S: SoldDeltaStore>(pool: &PgPool, store: &mut S) -> sqlx::Result<FlushReport> {
+    // all prizes, not only enabled ones: a prize disabled after it sold still owes its delta
+    let ids: Vec<Uuid> = sqlx::query_scalar("SELECT id FROM prizes").fetch_all(pool).await?;
+    let mut report = FlushReport::default();
+    for pid in ids {
+        match flush_prize(pool, store, pid).await {
+            Ok((flushed, oversold)) => {
+                report.flushed += flushed;
+                report.oversold += oversold;
+            }
+            Err(e) => {
+                report.failed_prizes += 1;
+                tracing::warn!(error = ?e, prize_id = %pid, "stock delta flush failed; batch stays pending");
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// Whether Postgres already applied a sold-delta batch.
+async fn batch_applied(conn: &mut PgConnection, batch_id: Uuid) -> sqlx::Result<bool> {
+    let applied: Option<i32> = sqlx::query_scalar("SELECT 1 FROM stock_flush_batches WHERE batch_id=$1")
+        .bind(batch_id)
+        .fetch_optional(conn)
+        .await?;
+    Ok(applied.is_some())
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct StockDrift {
+    pub prize_id: Uuid,
+    pub db_remaining: i64,
+    /// sold in Redis but not yet flushed to Postgres
+    pub unflushed_sold: i64,
+    /// Redis stock before reconciling; None if the key was missing
+    pub redis_before: Option<i64>,
+    /// db_remaining - unflushed_sold, what Redis stock should be
+    pub expected: i64,
+    /// redis_before - expected (a missing key counts as 0); positive means Redis oversells
+    pub drift: i64,
+}
+
+/// The Redis keys of one prize, read together when reconciling.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RedisStock {
+    /// `lottery:stock:{id}`; None if the key is missing
+    pub stock: Option<i64>,
+    /// `lottery:sold:{id}`
+    pub sold: i64,
+    /// `lottery:sold:pending:{id}`
+    pub pending: Option<PendingDelta>,
+}
+
+impl StockDrift {
+    /// Compares the Redis stock of a prize with what Postgres and the sales not flushed yet leave of it. The
+    /// pending batch counts as not flushed unless it is `applied_batch`.
+    pub fn measure(prize_id: Uuid, db_remaining: i64, redis: RedisStock, applied_batch: Option<Uuid>) -> StockDrift {
+        let pending = redis.pending.filter(|p| Some(p.batch_id) != applied_batch).map_or(0, |p| p.delta);
+        let unflushed_sold = redis.sold + pending;
+        let expected = (db_remaining - unflushed_sold).max(0);
+        StockDrift {
+            prize_id,
+            db_remaining,
+            unflushed_sold,
+            redis_before: redis.stock,
+            expected,
+            drift: redis.stock.unwrap_or(0) - expected,
+        }
+    }
+}
+
+/// Recomputes `lottery:stock:{id}` as Postgres `remaining_count` minus the unflushed `lottery:sold:{id}` delta
+/// for the given enabled prizes (all enabled prizes when `prize_ids` is None) and reports the drift.
+/// With `apply == false` only the report is produced.
+pub async fn reconcile_stock(
+    pool: &PgPool,
+    redis: &mut RedisManager,
+    metrics: &Metrics,
+    prize_ids: Option<&[Uuid]>,
+    apply: bool,
+) -> anyhow::Result<Vec<StockDrift>> {
+    let mut tx = pool.begin().await?;
+    // row locks keep the delta flusher from committing between our read and the Redis write
+    let rows: Vec<(Uuid, i64)> = sqlx::query_as(
+        r#"SELECT id, remaining_count FROM prizes
+           WHERE is_enabled=true AND ($1::uuid[] IS NULL OR id = ANY($1))
+           ORDER BY id FOR UPDATE"#
+    )
+    .bind(prize_ids.map(|ids| ids.to_vec()))
+    .fetch_all(&mut *tx)
+    .await?;
+    let mut report = Vec::with_capacity(rows.len());
+    for (pid, remaining) in rows {
+        let stock_key = format!("lottery:stock:{}", pid);
+        let _timer = metrics.script_timer("reconcile_stock");
+        // read in one transaction: a draw moves a unit from stock to sold, never just one of them
+        let (stock, sold, batch, delta): (Option<i64>, Option<i64>, Option<String>, Option<i64>) = redis::pipe()
+            .atomic()
+            .get(&stock_key)
+            .get(sold_key(pid))
+            .hget(pending_key(pid), "batch")
+            .hget(pending_key(pid), "delta")
+            .query_async(redis)
+            .await?;
+        let pending = batch.and_then(|b| Uuid::parse_str(&b).ok()).map(|batch_id| PendingDelta { batch_id, delta: delta.unwrap_or(0) });
+        // a pending batch committed before we took the lock is already part of remaining_count
+        let mut applied_batch = None;
+        if let Some(p) = pending {
+            applied_batch = batch_applied(&mut tx, p.batch_id).await?.then_some(p.batch_id);
+        }
+        let drift = StockDrift::measure(pid, remaining, RedisStock { stock, sold: sold.unwrap_or(0), pending }, applied_batch);
+        // draws since the read took from stock and added to sold alike, so shifting by the drift keeps them
+        if apply && (drift.drift != 0 || drift.redis_before.is_none()) {
+            redis::cmd("DECRBY").arg(&stock_key).arg(drift.drift).query_async::<_, i64>(redis).await?;
+        }
+        report.push(drift);
+    }
+    tx.commit().await?;
+    for d in report.iter().filter(|d| d.drift != 0 || d.redis_before.is_none()) {
+        tracing::warn!(prize_id = %d.prize_id, redis_before = ?d.redis_before, expected = d.expected, drift = d.drift, apply, "redis stock drift");
+    }
+    Ok(report)
+}
+
+/// Takes `units` of stock back from a prize ahead of lowering it in Postgres, counting sales not flushed yet.
+/// `conn` must hold the prize row lock, so the pending batch cannot be applied meanwhile. None when fewer
+/// units are left; otherwise whether the Redis stock was lowered, which `give_back_stock` undoes if the
+/// Postgres update fails.
+pub async fn take_back_stock(conn: &mut PgConnection, redis: &mut RedisManager, prize_id: Uuid, units: i64, db_remaining: i64) -> anyhow::Result<Option<bool>> {
+    let pending: Option<String> = redis::cmd("HGET").arg(pending_key(prize_id)).arg("batch").query_async(redis).await?;
+    let mut applied_batch = String::new();
+    if let Some(batch) = pending.and_then(|b| Uuid::parse_str(&b).ok()) {
+        if batch_applied(&mut *conn, batch).await? {
+            applied_batch = batch.to_string();
+        }
+    }
+    let (ok, lowered): (i64, i64) = LUA_TAKE_BACK_STOCK
+        .key(format!("lottery:stock:{}", prize_id))
+        .key(sold_key(prize_id))
+        .key(pending_key(prize_id))
+        .arg(units)
+        .arg(db_remaining)
+        .arg(applied_batch)
+        .invoke_async(redis)
+        .await?;
+    Ok((ok == 1).then_some(lowered == 1))
+}
+
+pub async fn give_back_stock(redis: &mut RedisManager, prize_id: Uuid, units: i64) -> redis::RedisResult<()> {
+    redis::cmd("INCRBY").arg(format!("lottery:stock:{}", prize_id)).arg(units).query_async::<_, i64>(redis).await?;
+    Ok(())
+}
+
+/// Drops the Redis stock, sold-delta and pending batch keys of a deleted prize.
+pub async fn forget_prizes(redis: &mut RedisManager, prize_ids: &[Uuid]) -> redis::RedisResult<()> {
+    if prize_ids.is_empty() {
+        return Ok(());
+    }
+    let mut del = redis::cmd("DEL");
+    for pid in prize_ids {
+        del.arg(format!("lottery:stock:{}", pid)).arg(sold_key(*pid)).arg(pending_key(*pid));
+    }
+    del.query_async::<_, i64>(redis).await?;
+    Ok(())
+}
+
+/// Stock and unflushed sold delta of every enabled prize, for the metrics.
+async fn sample_stock(pool: &PgPool, redis: &mut RedisManager, metrics: &Metrics) -> anyhow::Result<()> {
+    let ids: Vec<Uuid> = sqlx::query_scalar("SELECT id FROM prizes WHERE is_enabled=true").fetch_all(pool).await?;
+    let mut stock = Vec::with_capacity(ids.len());
+    let mut backlog = 0;
+    for pid in ids {
+        let (n, sold, pending): (Option<i64>, Option<i64>, Option<i64>) = redis::pipe()
+            .get(format!("lottery:stock:{}", pid))
+            .get(sold_key(pid))
+            .hget(pending_key(pid), "delta")
+            .query_async(redis)
+            .await?;
+        backlog += sold.unwrap_or(0) + pending.unwrap_or(0);
+        if let Some(n) = n {
+            stock.push((pid.to_string(), n));
+        }
+    }
+    metrics.set_prize_stock(&stock, backlog);
+    Ok(())
+}
+
+/// Flushes sold deltas every `jobs.stock_flush_interval_secs`; on shutdown one last pass runs after the
+/// draws have drained, so nothing sold stays behind in Redis.
+pub fn spawn_redis_delta_flusher(jobs: &Jobs, pool: PgPool, redis: Arc<RedisManager>, cfg: SharedConfig, metrics: Arc<Metrics>) {
+    let stop = jobs.stop_token();
+    jobs.spawn(async move {
+        let mut store = RedisDeltaStore::new((*redis).clone(), metrics.clone());
+        let mut conn = (*redis).clone();
+        let mut last_prune = std::time::Instant::now();
         loop {
-            tick.tick().await;
-            // read enabled prize ids
-            let ids: Vec<Uuid> = match sqlx::query_scalar(
-                "SELECT id FROM prizes WHERE is_enabled=true"
-            ).fetch_all(&pool).await {
-                Ok(v) => v,
-                Err(_) => continue,
-            };
-            let mut conn = (*redis).clone();
-            for pid in ids {
-                let key = format!("lottery:sold:{}", pid);
-                // atomic get and delete counter; if not supported, fall back to GET then DEL best-effort
-                let delta_opt: redis::RedisResult<Option<i64>> = redis::cmd("GETDEL")
-                    .arg(&key)
-                    .query_async(&mut conn)
-                    .await;
-                let delta = delta_opt.unwrap_or(None).unwrap_or(0);
-                if delta > 0 {
-                    let _ = sqlx::query(
-                        "UPDATE prizes SET remaining_count = GREATEST(0, remaining_count - $1), updated_at=now() WHERE id=$2"
-                    )
-                    .bind(delta)
-                    .bind(pid)
+            tokio::select! {
+                _ = tokio::time::sleep(Duration::from_secs(cfg.get().jobs.stock_flush_interval_secs)) => {}
+                _ = stop.cancelled() => break,
+            }
+            match flush_once(&pool, &mut store).await {
+                Ok(report) => {
+                    metrics.stock_oversold(report.oversold);
+                    if report.failed_prizes == 0 {
+                        metrics.stock_flushed();
+                    }
+                }
+                Err(e) => tracing::warn!(error = ?e, "stock delta flush pass failed"),
+            }
+            if let Err(e) = sample_stock(&pool, &mut conn, &metrics).await {
+                tracing::warn!(error = ?e, "failed to sample redis stock for metrics");
+            }
+            // applied batch ids only need to outlive any pending batch; prune hourly
+            if last_prune.elapsed() > Duration::from_secs(3600) {
+                last_prune = std::time::Instant::now();
+                let _ = sqlx::query("DELETE FROM stock_flush_batches WHERE applied_at < now() - interval '7 days'")
                     .execute(&pool)
                     .await;
-                }
             }
         }
+        match flush_once(&pool, &mut store).await {
+            Ok(report) => tracing::info!(oversold = report.oversold, flushed = report.flushed, failed_prizes = report.failed_prizes, "final stock delta flush done"),
+            Err(e) => tracing::error!(error = ?e, "final stock delta flush failed; deltas stay in redis for the next instance"),
+        }
     });
 }
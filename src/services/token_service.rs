@@ -0,0 +1,194 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use chrono::{Duration, Utc};
+use rand::RngCore;
+use redis::aio::ConnectionManager as RedisManager;
+use redis::AsyncCommands;
+use sha2::{Digest, Sha256};
+use sqlx::{types::Uuid, PgPool};
+
+use crate::auth::now_ts;
+use crate::error::AppError;
+
+// Token revocation. Access tokens are short-lived JWTs carrying a `jti`; logging out puts the jti on a denylist
+// until the token would have expired anyway. The denylist lives in Redis so every instance sees it, or in the
+// `revoked_tokens` table with the SQL draw backend, which runs without Redis; an in-process copy answers for
+// the tokens this instance revoked without a round trip. When the shared store cannot answer a token counts as
+// revoked: an outage must not bring back tokens revoked elsewhere. Refresh tokens are opaque random strings stored as
+// sha256 in `refresh_tokens`; each use rotates them, and reusing a rotated token revokes its whole family
+// since one of the two holders stole it.
+
+fn deny_key(jti: &str) -> String {
+    format!("auth:deny:{}", jti)
+}
+
+/// Revoked access tokens, owned by the app state.
+pub struct Denylist {
+    /// jti -> exp of the tokens this instance revoked
+    local: Mutex<HashMap<String, usize>>,
+    pool: PgPool,
+    redis: Option<RedisManager>,
+}
+
+impl Denylist {
+    pub fn new(pool: PgPool, redis: Option<RedisManager>) -> Self {
+        Denylist { local: Mutex::new(HashMap::new()), pool, redis }
+    }
+
+    /// Revokes the access token `jti` until `exp` (unix secs).
+    pub async fn deny(&self, jti: &str, exp: usize) {
+        let now = now_ts();
+        if exp <= now {
+            return;
+        }
+        {
+            let mut local = self.local.lock().expect("denylist lock");
+            local.retain(|_, e| *e > now);
+            local.insert(jti.to_string(), exp);
+        }
+        if let Some(mut redis) = self.redis.clone() {
+            let res: redis::RedisResult<()> = redis.set_ex(deny_key(jti), 1, (exp - now) as u64).await;
+            if let Err(e) = res {
+                tracing::warn!(error = ?e, "failed to write revoked token to redis denylist, revoked on this instance only");
+            }
+        } else if let Err(e) = self.deny_sql(jti, exp).await {
+            tracing::warn!(error = ?e, "failed to write revoked token to the database, revoked on this instance only");
+        }
+    }
+
+    async fn deny_sql(&self, jti: &str, exp: usize) -> sqlx::Result<()> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("DELETE FROM revoked_tokens WHERE expires_at <= now()").execute(&mut *tx).await?;
+        sqlx::query("INSERT INTO revoked_tokens (jti, expires_at) VALUES ($1, to_timestamp($2)) ON CONFLICT (jti) DO NOTHING")
+            .bind(jti)
+            .bind(exp as f64)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await
+    }
+
+    /// Whether the access token `jti` was revoked; one lookup unless this instance revoked it itself.
+    pub async fn is_denied(&self, jti: &str) -> bool {
+        if self.local.lock().expect("denylist lock").get(jti).is_some_and(|e| *e > now_ts()) {
+            return true;
+        }
+        let Some(mut redis) = self.redis.clone() else {
+            let revoked = sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM revoked_tokens WHERE jti=$1 AND expires_at > now())")
+                .bind(jti)
+                .fetch_one(&self.pool)
+                .await;
+            return revoked.unwrap_or_else(|e| {
+                tracing::warn!(error = ?e, "database denylist unavailable, rejecting the token");
+                true
+            });
+        };
+        match redis.exists(deny_key(jti)).await {
+            Ok(denied) => denied,
+            Err(e) => {
+                tracing::warn!(error = ?e, "redis denylist unavailable, rejecting the token");
+                true
+            }
+        }
+    }
+}
+
+fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+fn new_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+async fn insert_refresh<'e, E: sqlx::PgExecutor<'e>>(ex: E, id: Uuid, uid: Uuid, family_id: Uuid, ttl_secs: u64) -> sqlx::Result<String> {
+    let token = new_token();
+    sqlx::query(
+        r#"INSERT INTO refresh_tokens (id, user_id, family_id, token_hash, expires_at, created_at)
+           VALUES ($1,$2,$3,$4,$5, now())"#
+    )
+    .bind(id)
+    .bind(uid)
+    .bind(family_id)
+    .bind(hash_token(&token))
+    .bind(Utc::now() + Duration::seconds(ttl_secs as i64))
+    .execute(ex)
+    .await?;
+    Ok(token)
+}
+
+/// Starts a new refresh token family for a login.
+pub async fn issue_refresh(pool: &PgPool, uid: Uuid, ttl_secs: u64) -> sqlx::Result<String> {
+    insert_refresh(pool, Uuid::new_v4(), uid, Uuid::new_v4(), ttl_secs).await
+}
+
+#[derive(sqlx::FromRow)]
+struct RefreshRow {
+    id: Uuid,
+    user_id: Uuid,
+    family_id: Uuid,
+    expires_at: chrono::DateTime<Utc>,
+    revoked_at: Option<chrono::DateTime<Utc>>,
+}
+
+/// Exchanges a refresh token for its successor; returns the user and the new token.
+pub async fn rotate_refresh(pool: &PgPool, token: &str, ttl_secs: u64) -> Result<(Uuid, String), AppError> {
+    let mut tx = pool.begin().await?;
+    let row = sqlx::query_as::<_, RefreshRow>(
+        "SELECT id, user_id, family_id, expires_at, revoked_at FROM refresh_tokens WHERE token_hash=$1 FOR UPDATE"
+    )
+    .bind(hash_token(token))
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or(AppError::Unauthorized)?;
+    if row.revoked_at.is_some() {
+        tracing::warn!(user_id = %row.user_id, family_id = %row.family_id, "rotated refresh token reused, revoking its family");
+        revoke_family(&mut *tx, row.family_id).await?;
+        tx.commit().await?;
+        return Err(AppError::Unauthorized);
+    }
+    if row.expires_at <= Utc::now() {
+        return Err(AppError::Unauthorized);
+    }
+    let next_id = Uuid::new_v4();
+    sqlx::query("UPDATE refresh_tokens SET revoked_at = now(), replaced_by = $2 WHERE id=$1")
+        .bind(row.id)
+        .bind(next_id)
+        .execute(&mut *tx)
+        .await?;
+    let next = insert_refresh(&mut *tx, next_id, row.user_id, row.family_id, ttl_secs).await?;
+    tx.commit().await?;
+    Ok((row.user_id, next))
+}
+
+async fn revoke_family<'e, E: sqlx::PgExecutor<'e>>(ex: E, family_id: Uuid) -> sqlx::Result<()> {
+    sqlx::query("UPDATE refresh_tokens SET revoked_at = now() WHERE family_id=$1 AND revoked_at IS NULL")
+        .bind(family_id)
+        .execute(ex)
+        .await?;
+    Ok(())
+}
+
+/// Revokes the family of a refresh token of `uid`; unknown tokens and tokens of other users are ignored.
+pub async fn revoke_refresh(pool: &PgPool, uid: Uuid, token: &str) -> sqlx::Result<()> {
+    sqlx::query(
+        r#"UPDATE refresh_tokens SET revoked_at = now() WHERE revoked_at IS NULL AND family_id =
+           (SELECT family_id FROM refresh_tokens WHERE token_hash=$1 AND user_id=$2)"#
+    )
+    .bind(hash_token(token))
+    .bind(uid)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Revokes every refresh token of a user (logout everywhere).
+pub async fn revoke_all_refresh(pool: &PgPool, uid: Uuid) -> sqlx::Result<()> {
+    sqlx::query("UPDATE refresh_tokens SET revoked_at = now() WHERE user_id=$1 AND revoked_at IS NULL")
+        .bind(uid)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
@@ -38,7 +38,7 @@ pub async fn find_user_credentials(pool: &PgPool, username: &str) -> sqlx::Resul
 }
 
 pub async fn is_username_taken(pool: &PgPool, username: &str) -> sqlx::Result<bool> {
-    let exists: Option<(i64,)> = sqlx::query_as("SELECT 1 FROM users WHERE username=$1")
+    let exists: Option<(i32,)> = sqlx::query_as("SELECT 1 FROM users WHERE username=$1")
         .bind(username)
         .fetch_optional(pool)
         .await?;
@@ -55,11 +55,11 @@ pub async fn get_profile(pool: &PgPool, uid: Uuid) -> sqlx::Result<UserProfileRo
 }
 
 #[derive(Serialize, sqlx::FromRow, Debug, Clone)]
-pub struct UserHistoryRow { pub id: Uuid, pub prize_id: Option<Uuid>, pub prize_name: Option<String>, pub created_at: DateTime<Utc> }
+pub struct UserHistoryRow { pub id: Uuid, pub activity_id: Option<Uuid>, pub prize_id: Option<Uuid>, pub prize_name: Option<String>, pub created_at: DateTime<Utc> }
 
 pub async fn get_history(pool: &PgPool, uid: Uuid) -> sqlx::Result<Vec<UserHistoryRow>> {
     sqlx::query_as::<_, UserHistoryRow>(
-        r#"SELECT id, prize_id, prize_name, created_at FROM lottery_records WHERE user_id=$1 ORDER BY created_at DESC LIMIT 100"#
+        r#"SELECT id, activity_id, prize_id, prize_name, created_at FROM lottery_records WHERE user_id=$1 ORDER BY created_at DESC LIMIT 100"#
     )
     .bind(uid)
     .fetch_all(pool)
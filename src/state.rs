@@ -0,0 +1,85 @@
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context;
+use redis::aio::ConnectionManager as RedisManager;
+use sqlx::PgPool;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+
+use crate::config::{Config, DrawBackend, SharedConfig};
+use crate::metrics::Metrics;
+use crate::redis_client::connect_manager;
+use crate::services::feed_service::LiveFeed;
+use crate::services::prize_cache::PrizeCache;
+use crate::services::token_service::Denylist;
+
+pub type AppState = Arc<StateData>;
+
+/// Everything handlers and background tasks share, built once at startup and handed to every router.
+pub struct StateData {
+    pub pool: PgPool,
+    /// reloaded on SIGHUP; take a snapshot with `cfg.get()`
+    pub cfg: SharedConfig,
+    /// set exactly when `cfg.draw.backend` is Redis
+    pub redis: Option<RedisManager>,
+    pub prize_cache: PrizeCache,
+    pub metrics: Arc<Metrics>,
+    pub jobs: Jobs,
+    /// live wins and stock changes for SSE and WebSocket clients
+    pub feed: LiveFeed,
+    /// revoked access tokens
+    pub denylist: Denylist,
+}
+
+/// Background jobs of the instance. They are spawned through here so shutdown can stop them and wait until
+/// each one finished its last pass.
+#[derive(Clone, Default)]
+pub struct Jobs {
+    tracker: TaskTracker,
+    stop: CancellationToken,
+}
+
+impl Jobs {
+    pub fn spawn<F: Future<Output = ()> + Send + 'static>(&self, job: F) {
+        self.tracker.spawn(job);
+    }
+
+    /// Cancelled once shutdown begins; jobs finish their current pass, run a final one if they owe it, and return.
+    pub fn stop_token(&self) -> CancellationToken {
+        self.stop.clone()
+    }
+
+    /// Stops every job and waits for them; false if some were still running after `timeout`.
+    pub async fn shutdown(&self, timeout: Duration) -> bool {
+        self.stop.cancel();
+        self.tracker.close();
+        tokio::time::timeout(timeout, self.tracker.wait()).await.is_ok()
+    }
+}
+
+impl StateData {
+    pub fn new(pool: PgPool, cfg: Config, redis: Option<RedisManager>) -> AppState {
+        Arc::new(StateData {
+            denylist: Denylist::new(pool.clone(), redis.clone()),
+            pool,
+            cfg: SharedConfig::new(cfg),
+            feed: LiveFeed::new(redis.clone()),
+            redis,
+            prize_cache: PrizeCache::new(),
+            metrics: Arc::new(Metrics::default()),
+            jobs: Jobs::default(),
+        })
+    }
+
+    /// Connects to Redis if the configured draw backend needs it; an unreachable Redis is an error, not a
+    /// reason to draw from Postgres instead.
+    pub async fn connect(pool: PgPool, cfg: Config) -> anyhow::Result<AppState> {
+        let redis = match cfg.draw.backend {
+            DrawBackend::Redis => Some(connect_manager(&cfg.redis.url).await.with_context(|| format!("connecting to {}", cfg.redis.url))?),
+            DrawBackend::Sql => None,
+        };
+        Ok(Self::new(pool, cfg, redis))
+    }
+}
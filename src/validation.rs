@@ -0,0 +1,51 @@
+use serde::{ser::SerializeStruct, Serialize, Serializer};
+
+use crate::error::AppError;
+use crate::i18n;
+
+/// One rejected request field, listed in `ErrorBody.data.fields` as {field, code, message}; the message is
+/// the catalog entry "field.{code}" in the request's locale.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FieldError {
+    pub field: &'static str,
+    pub code: &'static str,
+}
+
+impl Serialize for FieldError {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let message = i18n::lookup(i18n::current(), &format!("field.{}", self.code)).unwrap_or(self.code);
+        let mut s = serializer.serialize_struct("FieldError", 3)?;
+        s.serialize_field("field", self.field)?;
+        s.serialize_field("code", self.code)?;
+        s.serialize_field("message", message)?;
+        s.end()
+    }
+}
+
+/// Collects every failed check of a request so the client gets all of them in one response.
+#[derive(Debug, Default)]
+pub struct Validator {
+    errors: Vec<FieldError>,
+}
+
+impl Validator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records error `code` against `field` unless `ok`.
+    pub fn check(&mut self, ok: bool, field: &'static str, code: &'static str) -> &mut Self {
+        if !ok {
+            self.errors.push(FieldError { field, code });
+        }
+        self
+    }
+
+    pub fn has_error(&self, field: &str) -> bool {
+        self.errors.iter().any(|e| e.field == field)
+    }
+
+    pub fn finish(self) -> Result<(), AppError> {
+        if self.errors.is_empty() { Ok(()) } else { Err(AppError::Validation(self.errors)) }
+    }
+}
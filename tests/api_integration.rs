@@ -5,21 +5,25 @@ use http_body_util::BodyExt; // collect()
 use serde_json::json;
 use tower::util::ServiceExt; // oneshot
 
-use fast_lottery_engine::{config::Config, routes::{admin_routes, auth_routes, lottery_routes, user_routes}};
+use fast_lottery_engine::{config::{Config, DrawBackend}, jwt_keys::JwtKeys, models::AdminRole, routes::{admin_routes, auth_routes, lottery_routes, ops_routes, user_routes}, services::admin_service, state::StateData};
 use sqlx_db_tester::TestPg;
 
 fn base_url() -> String {
     std::env::var("TEST_PG_URL").expect("set TEST_PG_URL for tests")
 }
 
+/// Creates an admin whose password is its username.
+async fn seed_admin(pool: &sqlx::PgPool, username: &str, role: AdminRole) {
+    let hash = fast_lottery_engine::auth::hash_password(username).unwrap();
+    admin_service::create_admin(pool, uuid::Uuid::new_v4(), username, &hash, role).await.unwrap();
+}
+
 fn test_cfg(db_url: String) -> Config {
-    Config {
-        database_url: db_url,
-        jwt_secret: "test_secret".to_string(),
-        server_addr: "127.0.0.1:0".to_string(),
-        admin_username: "admin".to_string(),
-        admin_password: "admin".to_string(),
-    }
+    let mut cfg = Config::new(db_url, JwtKeys::hs256("test_secret"));
+    cfg.server.addr = "127.0.0.1:0".to_string();
+    cfg.draw.backend = DrawBackend::Sql;
+    cfg.auth.refresh_token_ttl_secs = 3600;
+    cfg
 }
 
 #[tokio::test]
@@ -28,12 +32,13 @@ async fn full_flow_register_login_draw() {
     let tdb = TestPg::new(base_url(), Path::new("./migrations"));
     let pool = tdb.get_pool().await;
     let cfg = test_cfg("unused".to_string());
+    let state = StateData::new(pool.clone(), cfg, None);
 
     let app: Router = Router::new()
-        .merge(auth_routes(&pool, &cfg))
-        .merge(user_routes(&pool, &cfg))
-        .merge(lottery_routes(&pool, &cfg))
-        .merge(admin_routes(&pool, &cfg));
+        .merge(auth_routes(&state))
+        .merge(user_routes(&state))
+        .merge(lottery_routes(&state))
+        .merge(admin_routes(&state));
 
     // register
     let body = json!({"username":"user1","password":"secret123"}).to_string();
@@ -54,13 +59,660 @@ async fn full_flow_register_login_draw() {
     let resp = app.clone().oneshot(req).await.unwrap();
     assert_eq!(resp.status(), StatusCode::OK);
 
-    // draw
+    // draw in the seeded demo activity
+    let body = json!({"activity_id":"11111111-1111-1111-1111-111111111111"}).to_string();
     let req = Request::builder()
         .method("POST")
         .uri("/api/lottery/draw")
         .header("authorization", format!("Bearer {}", token))
-        .body(Body::empty())
+        .header("content-type","application/json")
+        .body(Body::from(body))
+        .unwrap();
+    let resp = app.clone().oneshot(req).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn draw_retry_with_idempotency_key_replays_first_result() {
+    let _ = dotenvy::dotenv();
+    let tdb = TestPg::new(base_url(), Path::new("./migrations"));
+    let pool = tdb.get_pool().await;
+    let cfg = test_cfg("unused".to_string());
+    let state = StateData::new(pool.clone(), cfg, None);
+
+    let app: Router = Router::new()
+        .merge(auth_routes(&state))
+        .merge(lottery_routes(&state))
+        .merge(ops_routes(&state));
+
+    let body = json!({"username":"user2","password":"secret123"}).to_string();
+    let req = Request::builder()
+        .method("POST")
+        .uri("/api/auth/register")
+        .header("content-type","application/json")
+        .body(Body::from(body))
         .unwrap();
     let resp = app.clone().oneshot(req).await.unwrap();
+    let bytes = resp.into_body().collect().await.unwrap().to_bytes();
+    let v: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+    let token = v.get("token").and_then(|x| x.as_str()).unwrap().to_string();
+
+    let draw = |key: &str| {
+        Request::builder()
+            .method("POST")
+            .uri("/api/lottery/draw")
+            .header("authorization", format!("Bearer {}", token))
+            .header("content-type","application/json")
+            .header("idempotency-key", key)
+            .body(Body::from(json!({"activity_id":"11111111-1111-1111-1111-111111111111"}).to_string()))
+            .unwrap()
+    };
+
+    let first = app.clone().oneshot(draw("retry-1")).await.unwrap();
+    assert_eq!(first.status(), StatusCode::OK);
+    let first_body = first.into_body().collect().await.unwrap().to_bytes();
+
+    // the retry is inside the cooldown, yet gets the original result instead of an error
+    let second = app.clone().oneshot(draw("retry-1")).await.unwrap();
+    assert_eq!(second.status(), StatusCode::OK);
+    assert_eq!(second.headers().get("idempotent-replayed").unwrap(), "true");
+    let second_body = second.into_body().collect().await.unwrap().to_bytes();
+    assert_eq!(first_body, second_body);
+
+    // a new key is a new draw and hits the cooldown
+    let third = app.clone().oneshot(draw("retry-2")).await.unwrap();
+    assert_eq!(third.status(), StatusCode::BAD_REQUEST);
+
+    let cnt: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM lottery_records").fetch_one(&pool).await.unwrap();
+    assert_eq!(cnt, 1);
+
+    // the replay is not a draw; the rejected one counts as cooldown
+    let resp = app.clone().oneshot(Request::builder().uri("/metrics").body(Body::empty()).unwrap()).await.unwrap();
     assert_eq!(resp.status(), StatusCode::OK);
+    let text = String::from_utf8(resp.into_body().collect().await.unwrap().to_bytes().to_vec()).unwrap();
+    let value = |series: &str| text.lines().find_map(|l| l.strip_prefix(series)).map(|v| v.trim().parse::<f64>().unwrap());
+    assert_eq!(value(r#"lottery_draws_total{backend="sql",outcome="cooldown"}"#), Some(1.0));
+    let first = value(r#"lottery_draws_total{backend="sql",outcome="won"}"#).unwrap_or(0.0)
+        + value(r#"lottery_draws_total{backend="sql",outcome="lost"}"#).unwrap_or(0.0);
+    assert_eq!(first, 1.0);
+    assert_eq!(value(r#"lottery_draw_duration_seconds_count{backend="sql"}"#), Some(2.0));
+    assert!(value("lottery_db_pool_max_connections").is_some());
+}
+
+#[tokio::test]
+async fn admin_updates_reach_the_prize_cache_immediately() {
+
+    let _ = dotenvy::dotenv();
+    let tdb = TestPg::new(base_url(), Path::new("./migrations"));
+    let pool = tdb.get_pool().await;
+    let cfg = test_cfg("unused".to_string());
+    let state = StateData::new(pool.clone(), cfg, None);
+    let app: Router = Router::new().merge(admin_routes(&state));
+
+    let call = |method: &str, uri: String, token: Option<&str>, body: serde_json::Value| {
+        let mut req = Request::builder().method(method).uri(uri).header("content-type", "application/json");
+        if let Some(t) = token {
+            req = req.header("authorization", format!("Bearer {}", t));
+        }
+        let app = app.clone();
+        let req = req.body(Body::from(body.to_string())).unwrap();
+        async move {
+            let resp = app.oneshot(req).await.unwrap();
+            let status = resp.status();
+            let bytes = resp.into_body().collect().await.unwrap().to_bytes();
+            (status, serde_json::from_slice::<serde_json::Value>(&bytes).unwrap_or_default())
+        }
+    };
+
+    seed_admin(&pool, "admin", AdminRole::Operator).await;
+    let (_, v) = call("POST", "/admin/api/login".into(), None, json!({"username":"admin","password":"admin"})).await;
+    let token = v["token"].as_str().unwrap().to_string();
+    let token = Some(token.as_str());
+
+    let (status, v) = call("POST", "/admin/api/activities".into(), token, json!({
+        "name": "crud", "start_time": "2024-01-01T00:00:00Z", "end_time": "2099-01-01T00:00:00Z", "status": "ongoing"
+    })).await;
+    assert_eq!(status, StatusCode::OK);
+    let aid = v["id"].as_str().unwrap().to_string();
+    let (_, v) = call("POST", "/admin/api/prizes".into(), token, json!({
+        "activity_id": aid, "name": "p", "total_count": 10, "probability": 5, "is_enabled": true
+    })).await;
+    let pid = v["id"].as_str().unwrap().to_string();
+
+    // top up and change the probability
+    let (status, v) = call("PATCH", format!("/admin/api/prizes/{}", pid), token, json!({"probability": 40, "stock_delta": 5})).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!((v["total_count"].as_i64(), v["remaining_count"].as_i64()), (Some(15), Some(15)));
+    let (status, _) = call("PATCH", format!("/admin/api/prizes/{}", pid), token, json!({"stock_delta": -16})).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    let aid_uuid = aid.parse().unwrap();
+    let snap = state.prize_cache.snapshot().await;
+    assert_eq!(snap.prizes[&aid_uuid].prizes.iter().map(|p| p.probability).collect::<Vec<_>>(), vec![40]);
+
+    // pause, clear a quota, then disable the prize
+    let (status, v) = call("PATCH", format!("/admin/api/activities/{}", aid), token, json!({"status": "paused", "max_draws_total": null})).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(v["status"], "paused");
+    let (_, _) = call("PATCH", format!("/admin/api/prizes/{}", pid), token, json!({"is_enabled": false})).await;
+    let snap = state.prize_cache.snapshot().await;
+    assert_eq!(snap.activities[&aid_uuid].status, fast_lottery_engine::models::ActivityStatus::Paused);
+    assert!(snap.prizes[&aid_uuid].prizes.is_empty());
+
+    // ongoing activities cannot be deleted
+    call("PATCH", format!("/admin/api/activities/{}", aid), token, json!({"status": "ongoing"})).await;
+    let (status, _) = call("DELETE", format!("/admin/api/activities/{}", aid), token, json!({})).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    call("PATCH", format!("/admin/api/activities/{}", aid), token, json!({"status": "ended"})).await;
+    let (status, v) = call("DELETE", format!("/admin/api/activities/{}", aid), token, json!({})).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(v["deleted_prizes"], json!([pid]));
+    assert!(!state.prize_cache.snapshot().await.activities.contains_key(&aid_uuid));
+    let (status, _) = call("DELETE", format!("/admin/api/prizes/{}", pid), token, json!({})).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn admin_create_rejects_invalid_fields_with_details() {
+    let _ = dotenvy::dotenv();
+    let tdb = TestPg::new(base_url(), Path::new("./migrations"));
+    let pool = tdb.get_pool().await;
+    let cfg = test_cfg("unused".to_string());
+    let state = StateData::new(pool.clone(), cfg, None);
+    let app: Router = Router::new().merge(admin_routes(&state));
+
+    seed_admin(&pool, "admin", AdminRole::Operator).await;
+    let req = Request::builder().method("POST").uri("/admin/api/login").header("content-type", "application/json")
+        .body(Body::from(json!({"username":"admin","password":"admin"}).to_string())).unwrap();
+    let bytes = app.clone().oneshot(req).await.unwrap().into_body().collect().await.unwrap().to_bytes();
+    let token = serde_json::from_slice::<serde_json::Value>(&bytes).unwrap()["token"].as_str().unwrap().to_string();
+
+    // returns the status and the rejected field names
+    let post = |uri: &'static str, body: serde_json::Value| {
+        let req = Request::builder().method("POST").uri(uri).header("content-type", "application/json")
+            .header("authorization", format!("Bearer {}", token)).body(Body::from(body.to_string())).unwrap();
+        let app = app.clone();
+        async move {
+            let resp = app.oneshot(req).await.unwrap();
+            let status = resp.status();
+            let v: serde_json::Value = serde_json::from_slice(&resp.into_body().collect().await.unwrap().to_bytes()).unwrap();
+            let fields: Vec<String> = v["data"]["fields"].as_array().into_iter().flatten()
+                .map(|f| f["field"].as_str().unwrap().to_string()).collect();
+            (status, fields)
+        }
+    };
+
+    let (status, fields) = post("/admin/api/prizes", json!({
+        "activity_id": uuid::Uuid::new_v4(), "name": "x", "total_count": -1, "probability": -5, "is_enabled": true
+    })).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(fields, ["probability", "total_count", "activity_id"]);
+
+    // the seeded activity already hands out 30%
+    let (status, fields) = post("/admin/api/prizes", json!({
+        "activity_id": "11111111-1111-1111-1111-111111111111", "name": "x", "total_count": 1, "probability": 71, "is_enabled": true
+    })).await;
+    assert_eq!((status, fields), (StatusCode::BAD_REQUEST, vec!["probability".to_string()]));
+    let (status, _) = post("/admin/api/prizes", json!({
+        "activity_id": "11111111-1111-1111-1111-111111111111", "name": "x", "total_count": 1, "probability": 70, "is_enabled": true
+    })).await;
+    assert_eq!(status, StatusCode::OK);
+
+    // 10000 basis points are a full budget, but 10000% are not
+    sqlx::query("UPDATE activities SET probability_unit='basis_points' WHERE id='11111111-1111-1111-1111-111111111111'")
+        .execute(&pool).await.unwrap();
+    sqlx::query("UPDATE prizes SET probability=probability*100 WHERE activity_id='11111111-1111-1111-1111-111111111111'")
+        .execute(&pool).await.unwrap();
+    let req = Request::builder().method("PATCH").uri("/admin/api/activities/11111111-1111-1111-1111-111111111111")
+        .header("content-type", "application/json").header("authorization", format!("Bearer {}", token))
+        .body(Body::from(json!({"probability_unit": "percent"}).to_string())).unwrap();
+    let resp = app.clone().oneshot(req).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    let v: serde_json::Value = serde_json::from_slice(&resp.into_body().collect().await.unwrap().to_bytes()).unwrap();
+    assert_eq!(v["data"]["fields"][0]["field"], "probability_unit");
+    let unit: String = sqlx::query_scalar("SELECT probability_unit::text FROM activities WHERE id='11111111-1111-1111-1111-111111111111'")
+        .fetch_one(&pool).await.unwrap();
+    assert_eq!(unit, "basis_points");
+
+    let (status, fields) = post("/admin/api/activities", json!({
+        "name": " ", "start_time": "2030-01-01T00:00:00Z", "end_time": "2029-01-01T00:00:00Z", "status": "planned",
+        "draw_cooldown_secs": -1
+    })).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(fields, ["name", "end_time", "draw_cooldown_secs"]);
+
+    // the database enforces the same invariants
+    let res = sqlx::query("UPDATE prizes SET probability=-1 WHERE activity_id='11111111-1111-1111-1111-111111111111'")
+        .execute(&pool).await;
+    assert!(res.is_err());
+}
+
+#[test]
+fn reason_names_match_their_serialized_form() {
+    use fast_lottery_engine::error::Reason;
+
+    for reason in Reason::ALL {
+        assert_eq!(serde_json::to_value(reason).unwrap(), reason.as_str(), "{:?}", reason);
+    }
+}
+
+#[tokio::test]
+async fn errors_carry_reason_status_and_retry_after() {
+    use fast_lottery_engine::error::{AppError, Reason};
+
+    let _ = dotenvy::dotenv();
+    let tdb = TestPg::new(base_url(), Path::new("./migrations"));
+    let pool = tdb.get_pool().await;
+    let cfg = test_cfg("unused".to_string());
+    let state = StateData::new(pool.clone(), cfg, None);
+    let app: Router = Router::new()
+        .merge(auth_routes(&state))
+        .merge(user_routes(&state))
+        .merge(lottery_routes(&state));
+
+    let send = |method: &str, uri: &str, token: Option<&str>, body: serde_json::Value| {
+        let mut req = Request::builder().method(method).uri(uri).header("content-type", "application/json");
+        if let Some(t) = token {
+            req = req.header("authorization", format!("Bearer {}", t));
+        }
+        app.clone().oneshot(req.body(Body::from(body.to_string())).unwrap())
+    };
+    let json_of = |bytes: &[u8]| serde_json::from_slice::<serde_json::Value>(bytes).unwrap();
+
+    let resp = send("POST", "/api/auth/register", None, json!({"username":"dup","password":"secret123"})).await.unwrap();
+    let token = json_of(&resp.into_body().collect().await.unwrap().to_bytes())["token"].as_str().unwrap().to_string();
+    let resp = send("POST", "/api/auth/register", None, json!({"username":"dup","password":"secret123"})).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::CONFLICT);
+    assert_eq!(json_of(&resp.into_body().collect().await.unwrap().to_bytes())["reason"], "username_taken");
+    // users need as long a password as admins
+    let resp = send("POST", "/api/auth/register", None, json!({"username":"short","password":"secret1"})).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+    // cooldown: the seeded activity waits 60s between draws
+    let draw = json!({"activity_id":"11111111-1111-1111-1111-111111111111"});
+    send("POST", "/api/lottery/draw", Some(&token), draw.clone()).await.unwrap();
+    let resp = send("POST", "/api/lottery/draw", Some(&token), draw).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    let retry_after: i64 = resp.headers()["retry-after"].to_str().unwrap().parse().unwrap();
+    assert!((1..=60).contains(&retry_after));
+    assert_eq!(json_of(&resp.into_body().collect().await.unwrap().to_bytes())["reason"], "cooldown");
+
+    // a deleted user's token no longer finds a profile
+    sqlx::query("DELETE FROM users WHERE username='dup'").execute(&pool).await.unwrap();
+    let resp = send("GET", "/api/user/profile", Some(&token), json!(null)).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+    // foreign key violations are 422
+    let err = sqlx::query("INSERT INTO prizes (id, activity_id, name, total_count, remaining_count, probability, created_at, updated_at) VALUES ($1,$2,'x',1,1,1, now(), now())")
+        .bind(uuid::Uuid::new_v4()).bind(uuid::Uuid::new_v4()).execute(&pool).await.unwrap_err();
+    let err = AppError::from(err);
+    assert_eq!(err.reason(), Reason::ReferenceMissing);
+    assert_eq!(axum::response::IntoResponse::into_response(err).status(), StatusCode::UNPROCESSABLE_ENTITY);
+}
+
+#[tokio::test]
+async fn error_messages_follow_accept_language() {
+    let _ = dotenvy::dotenv();
+    let tdb = TestPg::new(base_url(), Path::new("./migrations"));
+    let pool = tdb.get_pool().await;
+    let cfg = test_cfg("unused".to_string());
+    let state = StateData::new(pool.clone(), cfg, None);
+    let app: Router = Router::new().merge(auth_routes(&state));
+
+    let register = |lang: Option<&'static str>| {
+        let mut req = Request::builder().method("POST").uri("/api/auth/register").header("content-type", "application/json");
+        if let Some(l) = lang {
+            req = req.header("accept-language", l);
+        }
+        let req = req.body(Body::from(json!({"username":"u","password":"123"}).to_string())).unwrap();
+        let app = app.clone();
+        async move {
+            let resp = app.oneshot(req).await.unwrap();
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+            let lang = resp.headers()["content-language"].to_str().unwrap().to_string();
+            let v: serde_json::Value = serde_json::from_slice(&resp.into_body().collect().await.unwrap().to_bytes()).unwrap();
+            assert_eq!(v["error_code"], "00-01-00");
+            (lang, v["message"].as_str().unwrap().to_string())
+        }
+    };
+
+    assert_eq!(register(None).await, ("zh-CN".to_string(), "用户名或密码不合法".to_string()));
+    assert_eq!(register(Some("en-US")).await, ("en-US".to_string(), "Invalid username or password".to_string()));
+    assert_eq!(register(Some("fr-FR, en;q=0.8, zh;q=0.5")).await.1, "Invalid username or password");
+    assert_eq!(register(Some("fr-FR")).await.1, "用户名或密码不合法");
+
+    // the configured default applies when nothing matches
+    let mut cfg = test_cfg("unused".to_string());
+    cfg.server.default_locale = "en-US".parse().unwrap();
+    let state = StateData::new(pool.clone(), cfg, None);
+    let app: Router = Router::new().merge(auth_routes(&state));
+    let req = Request::builder().method("POST").uri("/api/auth/register").header("content-type", "application/json")
+        .header("accept-language", "de").body(Body::from(json!({"username":"u","password":"123"}).to_string())).unwrap();
+    let resp = app.oneshot(req).await.unwrap();
+    let v: serde_json::Value = serde_json::from_slice(&resp.into_body().collect().await.unwrap().to_bytes()).unwrap();
+    assert_eq!(v["message"], "Invalid username or password");
+}
+
+#[tokio::test]
+async fn admin_routes_enforce_roles_from_the_database() {
+    let _ = dotenvy::dotenv();
+    let tdb = TestPg::new(base_url(), Path::new("./migrations"));
+    let pool = tdb.get_pool().await;
+    let cfg = test_cfg("unused".to_string());
+    let state = StateData::new(pool.clone(), cfg, None);
+    let app: Router = Router::new().merge(admin_routes(&state)).merge(user_routes(&state)).merge(lottery_routes(&state));
+    seed_admin(&pool, "root", AdminRole::Superadmin).await;
+
+    let call = |method: &str, uri: String, token: Option<String>, body: serde_json::Value| {
+        let mut req = Request::builder().method(method).uri(uri).header("content-type", "application/json");
+        if let Some(t) = token {
+            req = req.header("authorization", format!("Bearer {}", t));
+        }
+        let app = app.clone();
+        let req = req.body(Body::from(body.to_string())).unwrap();
+        async move {
+            let resp = app.oneshot(req).await.unwrap();
+            let status = resp.status();
+            let bytes = resp.into_body().collect().await.unwrap().to_bytes();
+            (status, serde_json::from_slice::<serde_json::Value>(&bytes).unwrap_or_default())
+        }
+    };
+    let login = |username: &'static str, password: &'static str| {
+        let call = &call;
+        async move {
+            let (status, v) = call("POST", "/admin/api/login".into(), None, json!({"username": username, "password": password})).await;
+            (status, v["token"].as_str().map(str::to_string))
+        }
+    };
+
+    // the router guards reject missing tokens before any handler runs
+    assert_eq!(call("GET", "/admin/api/outbox".into(), None, json!({})).await.0, StatusCode::UNAUTHORIZED);
+    assert_eq!(call("GET", "/api/user/lottery-history".into(), None, json!({})).await.0, StatusCode::UNAUTHORIZED);
+    assert_eq!(call("POST", "/api/lottery/draw".into(), None, json!({})).await.0, StatusCode::UNAUTHORIZED);
+    assert_eq!(call("GET", "/api/lottery/prizes".into(), None, json!({})).await.0, StatusCode::OK);
+    assert_eq!(call("GET", "/api/lottery/global-history".into(), None, json!({})).await.0, StatusCode::OK);
+    assert_eq!(login("root", "wrong").await.0, StatusCode::UNAUTHORIZED);
+    let root = login("root", "root").await.1;
+
+    let (status, fields) = call("POST", "/admin/api/admins".into(), root.clone(), json!({"username": "", "password": "short", "role": "viewer"})).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(fields["data"]["fields"].as_array().unwrap().len(), 2);
+    let (status, v) = call("POST", "/admin/api/admins".into(), root.clone(), json!({"username": "eve", "password": "viewer-pass", "role": "viewer"})).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(v["role"], "viewer");
+    let eve_id = v["id"].as_str().unwrap().to_string();
+    let (status, _) = call("POST", "/admin/api/admins".into(), root.clone(), json!({"username": "eve", "password": "viewer-pass", "role": "viewer"})).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+
+    // viewers read but cannot change anything, nor use admin tokens as user tokens
+    let eve = login("eve", "viewer-pass").await.1;
+    assert_eq!(call("GET", "/admin/api/activities".into(), eve.clone(), json!({})).await.0, StatusCode::OK);
+    let activity = json!({"name": "a", "start_time": "2024-01-01T00:00:00Z", "end_time": "2099-01-01T00:00:00Z", "status": "ongoing"});
+    assert_eq!(call("POST", "/admin/api/activities".into(), eve.clone(), activity.clone()).await.0, StatusCode::FORBIDDEN);
+    assert_eq!(call("GET", "/admin/api/admins".into(), eve.clone(), json!({})).await.0, StatusCode::FORBIDDEN);
+    assert_eq!(call("GET", "/api/user/profile".into(), eve.clone(), json!({})).await.0, StatusCode::UNAUTHORIZED);
+
+    // a promotion applies to the token already issued; disabling revokes it
+    call("PATCH", format!("/admin/api/admins/{}", eve_id), root.clone(), json!({"role": "operator"})).await;
+    assert_eq!(call("POST", "/admin/api/activities".into(), eve.clone(), activity).await.0, StatusCode::OK);
+    call("PATCH", format!("/admin/api/admins/{}", eve_id), root.clone(), json!({"is_active": false})).await;
+    assert_eq!(call("GET", "/admin/api/activities".into(), eve, json!({})).await.0, StatusCode::UNAUTHORIZED);
+    assert_eq!(login("eve", "viewer-pass").await.0, StatusCode::UNAUTHORIZED);
+
+    // the last superadmin cannot demote itself
+    let (_, v) = call("GET", "/admin/api/admins".into(), root.clone(), json!({})).await;
+    let root_id = v["admins"].as_array().unwrap().iter().find(|a| a["username"] == "root").unwrap()["id"].as_str().unwrap().to_string();
+    let (status, v) = call("PATCH", format!("/admin/api/admins/{}", root_id), root.clone(), json!({"role": "viewer"})).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert_eq!(v["reason"], "last_superadmin");
+
+    // logging out revokes the admin token, on other instances as well
+    assert_eq!(call("POST", "/admin/api/logout".into(), root.clone(), json!({})).await.0, StatusCode::NO_CONTENT);
+    assert_eq!(call("GET", "/admin/api/outbox".into(), root.clone(), json!({})).await.0, StatusCode::UNAUTHORIZED);
+    let other = StateData::new(pool.clone(), test_cfg("unused".to_string()), None);
+    assert!(fast_lottery_engine::auth::verify_jwt(&other, &root.unwrap()).await.is_err());
+
+    // two superadmins demoting each other at once leave one of them
+    seed_admin(&pool, "root2", AdminRole::Superadmin).await;
+    let ids: Vec<uuid::Uuid> = sqlx::query_scalar("SELECT id FROM admin_users WHERE role='superadmin' ORDER BY username")
+        .fetch_all(&pool).await.unwrap();
+    let demote = |id| admin_service::update_admin(&pool, id, Some(AdminRole::Viewer), None);
+    let (a, b) = tokio::join!(demote(ids[0]), demote(ids[1]));
+    assert_eq!([a.is_ok(), b.is_ok()].iter().filter(|ok| **ok).count(), 1);
+    let left: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM admin_users WHERE role='superadmin' AND is_active").fetch_one(&pool).await.unwrap();
+    assert_eq!(left, 1);
+}
+
+#[tokio::test]
+async fn refresh_tokens_rotate_and_logout_revokes_the_access_token() {
+    let _ = dotenvy::dotenv();
+    let tdb = TestPg::new(base_url(), Path::new("./migrations"));
+    let pool = tdb.get_pool().await;
+    let cfg = test_cfg("unused".to_string());
+    let state = StateData::new(pool.clone(), cfg, None);
+    let app: Router = Router::new().merge(auth_routes(&state)).merge(user_routes(&state));
+
+    let call = |method: &str, uri: &str, token: Option<&str>, body: serde_json::Value| {
+        let mut req = Request::builder().method(method).uri(uri).header("content-type", "application/json");
+        if let Some(t) = token {
+            req = req.header("authorization", format!("Bearer {}", t));
+        }
+        let app = app.clone();
+        let req = req.body(Body::from(body.to_string())).unwrap();
+        async move {
+            let resp = app.oneshot(req).await.unwrap();
+            let status = resp.status();
+            let bytes = resp.into_body().collect().await.unwrap().to_bytes();
+            (status, serde_json::from_slice::<serde_json::Value>(&bytes).unwrap_or_default())
+        }
+    };
+
+    let (status, v) = call("POST", "/api/auth/register", None, json!({"username":"rot","password":"secret123"})).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(v["expires_in"], 900);
+    let first = v["refresh_token"].as_str().unwrap().to_string();
+
+    let (status, v) = call("POST", "/api/auth/refresh", None, json!({"refresh_token": first})).await;
+    assert_eq!(status, StatusCode::OK);
+    let second = v["refresh_token"].as_str().unwrap().to_string();
+    assert_ne!(first, second);
+    assert_eq!(call("GET", "/api/user/profile", v["token"].as_str(), json!({})).await.0, StatusCode::OK);
+
+    // replaying the rotated token revokes the whole family, including its successor
+    assert_eq!(call("POST", "/api/auth/refresh", None, json!({"refresh_token": first})).await.0, StatusCode::UNAUTHORIZED);
+    assert_eq!(call("POST", "/api/auth/refresh", None, json!({"refresh_token": second})).await.0, StatusCode::UNAUTHORIZED);
+
+    // logout denies the access token and revokes the refresh token passed along
+    let (_, v) = call("POST", "/api/auth/login", None, json!({"username":"rot","password":"secret123"})).await;
+    let access = v["token"].as_str().unwrap().to_string();
+    let refresh = v["refresh_token"].as_str().unwrap().to_string();
+    assert_eq!(call("GET", "/api/user/profile", Some(&access), json!({})).await.0, StatusCode::OK);
+    assert_eq!(call("POST", "/api/auth/logout", Some(&access), json!({"refresh_token": refresh})).await.0, StatusCode::NO_CONTENT);
+    assert_eq!(call("GET", "/api/user/profile", Some(&access), json!({})).await.0, StatusCode::UNAUTHORIZED);
+    assert_eq!(call("POST", "/api/auth/refresh", None, json!({"refresh_token": refresh})).await.0, StatusCode::UNAUTHORIZED);
+    // without Redis the revocation is kept in Postgres, so another instance rejects the token too
+    let other = StateData::new(pool.clone(), test_cfg("unused".to_string()), None);
+    assert!(fast_lottery_engine::auth::verify_jwt(&other, &access).await.is_err());
+}
+
+#[tokio::test]
+async fn asymmetric_tokens_rotate_keys_and_verify_from_jwks() {
+    let _ = dotenvy::dotenv();
+    let tdb = TestPg::new(base_url(), Path::new("./migrations"));
+    let pool = tdb.get_pool().await;
+    let pem = |name: &str| std::fs::read(format!("tests/fixtures/jwt/{}.pem", name)).unwrap();
+    let keys = |kid: &str, private: &str, public: &[(&str, &str)], legacy: Option<&str>| {
+        let public: Vec<_> = public.iter().map(|(kid, name)| (kid.to_string(), pem(name))).collect();
+        Config { jwt: JwtKeys::asymmetric(kid, &pem(private), &public, legacy).unwrap(), ..test_cfg("unused".to_string()) }
+    };
+    let call = |cfg: &Config, method: &str, uri: &str, token: Option<&str>, body: serde_json::Value| {
+        let state = StateData::new(pool.clone(), cfg.clone(), None);
+        let app: Router = Router::new().merge(auth_routes(&state)).merge(user_routes(&state));
+        let mut req = Request::builder().method(method).uri(uri).header("content-type", "application/json");
+        if let Some(t) = token {
+            req = req.header("authorization", format!("Bearer {}", t));
+        }
+        let req = req.body(Body::from(body.to_string())).unwrap();
+        async move {
+            let resp = app.oneshot(req).await.unwrap();
+            let status = resp.status();
+            let bytes = resp.into_body().collect().await.unwrap().to_bytes();
+            (status, serde_json::from_slice::<serde_json::Value>(&bytes).unwrap_or_default())
+        }
+    };
+    let login = json!({"username":"keys","password":"secret123"});
+
+    let hs = test_cfg("unused".to_string());
+    let (_, v) = call(&hs, "POST", "/api/auth/register", None, login.clone()).await;
+    let hs_token = v["token"].as_str().unwrap().to_string();
+
+    let rsa = keys("rsa-1", "rsa_private", &[("rsa-1", "rsa_public")], None);
+    let (_, v) = call(&rsa, "POST", "/api/auth/login", None, login.clone()).await;
+    let rsa_token = v["token"].as_str().unwrap().to_string();
+    let header = jsonwebtoken::decode_header(&rsa_token).unwrap();
+    assert_eq!((header.alg, header.kid.as_deref()), (jsonwebtoken::Algorithm::RS256, Some("rsa-1")));
+    assert_eq!(call(&rsa, "GET", "/api/user/profile", Some(&rsa_token), json!({})).await.0, StatusCode::OK);
+    assert_eq!(call(&rsa, "GET", "/api/user/profile", Some(&hs_token), json!({})).await.0, StatusCode::UNAUTHORIZED);
+
+    // rotation: sign with the new key, keep verifying the old one and (during the migration) HS256 tokens
+    let ed = keys("ed-1", "ed25519_private", &[("rsa-1", "rsa_public"), ("ed-1", "ed25519_public")], Some("test_secret"));
+    let (_, v) = call(&ed, "POST", "/api/auth/login", None, login.clone()).await;
+    let ed_token = v["token"].as_str().unwrap().to_string();
+    assert_eq!(jsonwebtoken::decode_header(&ed_token).unwrap().kid.as_deref(), Some("ed-1"));
+    for token in [&ed_token, &rsa_token, &hs_token] {
+        assert_eq!(call(&ed, "GET", "/api/user/profile", Some(token), json!({})).await.0, StatusCode::OK);
+    }
+
+    // a gateway verifies with nothing but the JWKS
+    let (status, jwks) = call(&ed, "GET", "/.well-known/jwks.json", None, json!({})).await;
+    assert_eq!(status, StatusCode::OK);
+    let set: jsonwebtoken::jwk::JwkSet = serde_json::from_value(jwks).unwrap();
+    assert_eq!(set.keys.len(), 2);
+    for (token, kid, alg) in [(&ed_token, "ed-1", jsonwebtoken::Algorithm::EdDSA), (&rsa_token, "rsa-1", jsonwebtoken::Algorithm::RS256)] {
+        let key = jsonwebtoken::DecodingKey::from_jwk(set.find(kid).unwrap()).unwrap();
+        let claims = jsonwebtoken::decode::<serde_json::Value>(token, &key, &jsonwebtoken::Validation::new(alg)).unwrap().claims;
+        assert!(claims["jti"].is_string());
+    }
+    assert_eq!(call(&hs, "GET", "/.well-known/jwks.json", None, json!({})).await.1, json!({"keys": []}));
+
+    // once the old key is dropped its tokens stop working
+    let retired = keys("ed-1", "ed25519_private", &[("ed-1", "ed25519_public")], None);
+    assert_eq!(call(&retired, "GET", "/api/user/profile", Some(&rsa_token), json!({})).await.0, StatusCode::UNAUTHORIZED);
+    assert_eq!(call(&retired, "GET", "/api/user/profile", Some(&ed_token), json!({})).await.0, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn readiness_reports_each_check_and_fails_with_503() {
+    let _ = dotenvy::dotenv();
+    let tdb = TestPg::new(base_url(), Path::new("./migrations"));
+    let pool = tdb.get_pool().await;
+    let mut cfg = test_cfg("unused".to_string());
+    cfg.jobs.prize_cache_refresh_ms = 100;
+    let state = StateData::new(pool.clone(), cfg, None);
+    let app: Router = Router::new().merge(ops_routes(&state));
+    let get = |uri: &'static str| {
+        let app = app.clone();
+        async move {
+            let resp = app.oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap()).await.unwrap();
+            let status = resp.status();
+            let bytes = resp.into_body().collect().await.unwrap().to_bytes();
+            (status, serde_json::from_slice::<serde_json::Value>(&bytes).unwrap_or_default())
+        }
+    };
+
+    assert_eq!(get("/livez").await.0, StatusCode::OK);
+    // the prize cache has not been loaded yet
+    let (status, v) = get("/readyz").await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!((v["checks"]["db"]["ok"].as_bool(), v["checks"]["migrations"]["ok"].as_bool()), (Some(true), Some(true)));
+    assert_eq!(v["checks"]["prize_cache"]["ok"], false);
+    assert!(v["checks"].get("redis").is_none());
+
+    state.prize_cache.spawn_refresh(&state.jobs, pool.clone(), state.cfg.clone(), state.metrics.clone());
+    tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+    let (status, v) = get("/readyz").await;
+    assert_eq!(status, StatusCode::OK, "{}", v);
+    assert_eq!(v["ready"], true);
+
+    // a schema behind the binary takes the instance out of rotation
+    sqlx::query("DELETE FROM _sqlx_migrations WHERE version = (SELECT max(version) FROM _sqlx_migrations)").execute(&pool).await.unwrap();
+    let (status, v) = get("/readyz").await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(v["checks"]["migrations"]["ok"], false);
+
+    // background jobs stop on shutdown instead of running until the runtime is dropped
+    assert!(state.jobs.shutdown(std::time::Duration::from_secs(2)).await);
+}
+
+#[tokio::test]
+async fn live_feed_streams_masked_wins_of_one_activity() {
+    use fast_lottery_engine::services::feed_service::{mask_username, FeedEvent};
+    use futures_util::StreamExt;
+
+    let _ = dotenvy::dotenv();
+    let tdb = TestPg::new(base_url(), Path::new("./migrations"));
+    let pool = tdb.get_pool().await;
+    let state = StateData::new(pool.clone(), test_cfg("unused".to_string()), None);
+    let app: Router = Router::new().merge(auth_routes(&state)).merge(lottery_routes(&state)).merge(admin_routes(&state));
+    assert_eq!((mask_username("alice"), mask_username("张三"), mask_username("x")), ("a***e".to_string(), "张*".to_string(), "*".to_string()));
+    // every draw of the demo activity wins
+    sqlx::query("UPDATE activities SET probability_unit='weight', no_win_weight=0").execute(&pool).await.unwrap();
+
+    let activity = "11111111-1111-1111-1111-111111111111";
+    let req = Request::builder().uri(format!("/api/lottery/stream?activity_id={}", activity)).body(Body::empty()).unwrap();
+    let resp = app.clone().oneshot(req).await.unwrap();
+    assert_eq!(resp.headers()["content-type"], "text/event-stream");
+    let mut sse = resp.into_body();
+    let mut other = Box::pin(state.feed.subscribe(Some(uuid::Uuid::new_v4())));
+
+    let register = json!({"username":"feeduser","password":"secret123"}).to_string();
+    let req = Request::builder().method("POST").uri("/api/auth/register").header("content-type", "application/json").body(Body::from(register)).unwrap();
+    let bytes = app.clone().oneshot(req).await.unwrap().into_body().collect().await.unwrap().to_bytes();
+    let token = serde_json::from_slice::<serde_json::Value>(&bytes).unwrap()["token"].as_str().unwrap().to_string();
+    let req = Request::builder()
+        .method("POST")
+        .uri("/api/lottery/draw")
+        .header("authorization", format!("Bearer {}", token))
+        .header("content-type", "application/json")
+        .body(Body::from(json!({"activity_id": activity}).to_string()))
+        .unwrap();
+    let bytes = app.clone().oneshot(req).await.unwrap().into_body().collect().await.unwrap().to_bytes();
+    let drawn: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+    assert_eq!(drawn["won"], true, "{}", drawn);
+
+    // the win and the prize's remaining stock, with the username masked
+    let mut text = String::new();
+    while !(text.contains("event: win") && text.contains("event: stock")) {
+        let frame = tokio::time::timeout(std::time::Duration::from_secs(2), sse.frame()).await.expect("feed event").unwrap().unwrap();
+        text.push_str(std::str::from_utf8(frame.data_ref().unwrap()).unwrap());
+    }
+    assert!(text.contains(r#""user":"f***r""#) && !text.contains("feeduser"), "{}", text);
+    assert!(text.contains(&format!(r#""prize_id":"{}""#, drawn["prize_id"].as_str().unwrap())), "{}", text);
+    // subscribers of another activity see nothing
+    assert!(tokio::time::timeout(std::time::Duration::from_millis(100), other.next()).await.is_err());
+
+    // admin top-ups and deletes announce the stock too
+    seed_admin(&pool, "admin", AdminRole::Operator).await;
+    let req = Request::builder().method("POST").uri("/admin/api/login").header("content-type", "application/json")
+        .body(Body::from(json!({"username":"admin","password":"admin"}).to_string())).unwrap();
+    let bytes = app.clone().oneshot(req).await.unwrap().into_body().collect().await.unwrap().to_bytes();
+    let admin = serde_json::from_slice::<serde_json::Value>(&bytes).unwrap()["token"].as_str().unwrap().to_string();
+    let prize_id: uuid::Uuid = drawn["prize_id"].as_str().unwrap().parse().unwrap();
+    let mut events = Box::pin(state.feed.subscribe(Some(activity.parse().unwrap())));
+    let admin_call = |method: &str, body: serde_json::Value| {
+        Request::builder().method(method).uri(format!("/admin/api/prizes/{}", prize_id)).header("content-type", "application/json")
+            .header("authorization", format!("Bearer {}", admin)).body(Body::from(body.to_string())).unwrap()
+    };
+    let before: i64 = sqlx::query_scalar("SELECT remaining_count FROM prizes WHERE id=$1").bind(prize_id).fetch_one(&pool).await.unwrap();
+    assert_eq!(app.clone().oneshot(admin_call("PATCH", json!({"stock_delta": 5}))).await.unwrap().status(), StatusCode::OK);
+    let stock = |remaining| FeedEvent::Stock { activity_id: activity.parse().unwrap(), prize_id, remaining };
+    let event = tokio::time::timeout(std::time::Duration::from_secs(2), events.next()).await.unwrap().unwrap();
+    assert_eq!(*event, stock(before + 5));
+    assert_eq!(app.clone().oneshot(admin_call("DELETE", json!({}))).await.unwrap().status(), StatusCode::OK);
+    let event = tokio::time::timeout(std::time::Duration::from_secs(2), events.next()).await.unwrap().unwrap();
+    assert_eq!(*event, stock(0));
+
+    // closing the feed ends open streams so shutdown does not wait on them
+    state.feed.close();
+    assert!(tokio::time::timeout(std::time::Duration::from_secs(2), sse.frame()).await.unwrap().is_none());
 }
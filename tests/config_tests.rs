@@ -0,0 +1,51 @@
+use std::path::PathBuf;
+
+use fast_lottery_engine::config::{Config, DrawBackend, SharedConfig};
+
+fn write_config(name: &str, text: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("lottery-config-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join(name);
+    std::fs::write(&path, text).unwrap();
+    path
+}
+
+#[test]
+fn file_is_layered_under_env_validated_and_hot_reloaded() {
+    for name in ["DATABASE_URL", "DB_MAX_CONNECTIONS", "JWT_SECRET", "JWT_PRIVATE_KEY_FILE", "ACCESS_TOKEN_TTL_SECS", "DRAW_BACKEND"] {
+        std::env::remove_var(name);
+    }
+    let toml = |ttl: u64, url: &str, backend: &str| {
+        format!(
+            "[db]\nurl = \"{}\"\nmax_connections = 20\n\n[auth]\njwt_secret = \"file-secret\"\naccess_token_ttl_secs = {}\n\n\
+             [draw]\nbackend = \"{}\"\n\n[jobs]\nprize_cache_refresh_ms = 500\n",
+            url, ttl, backend
+        )
+    };
+    let path = write_config("lottery.toml", &toml(600, "postgres://file/lottery", "sql"));
+    std::env::set_var("DB_MAX_CONNECTIONS", "4");
+    let cfg = Config::load_from(Some(&path)).unwrap();
+    assert_eq!((cfg.db.url.as_str(), cfg.db.max_connections), ("postgres://file/lottery", 4));
+    assert_eq!((cfg.draw.backend, cfg.draw.default_cooldown_secs), (DrawBackend::Sql, 60));
+    assert_eq!((cfg.jobs.prize_cache_refresh_ms, cfg.jobs.stock_flush_interval_secs), (500, 5));
+    assert_eq!(cfg.auth.access_token_ttl_secs, 600);
+
+    // every problem is reported at once; unknown keys are typos, not silently ignored
+    let yaml = write_config("bad.yaml", "auth:\n  jwt_secret: s\n  access_token_ttl_secs: 0\njobs:\n  stock_flush_interval_secs: 0\n");
+    let err = format!("{:#}", Config::load_from(Some(&yaml)).err().unwrap());
+    for problem in ["db.url", "access_token_ttl_secs", "stock_flush_interval_secs"] {
+        assert!(err.contains(problem), "{}", err);
+    }
+    let typo = write_config("typo.toml", "[draw]\ncooldown = 5\n");
+    assert!(Config::load_from(Some(&typo)).is_err());
+
+    // a reload takes the safe knobs and leaves the ones that need a restart
+    let shared = SharedConfig::new(cfg);
+    std::fs::write(&path, toml(300, "postgres://other/lottery", "redis")).unwrap();
+    let next = Config::load_from(Some(&path)).unwrap();
+    assert_eq!(shared.reload(&next), vec!["db", "draw.backend"]);
+    let live = shared.get();
+    assert_eq!(live.auth.access_token_ttl_secs, 300);
+    assert_eq!((live.db.url.as_str(), live.draw.backend), ("postgres://file/lottery", DrawBackend::Sql));
+    std::env::remove_var("DB_MAX_CONNECTIONS");
+}
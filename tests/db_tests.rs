@@ -28,6 +28,27 @@ async fn migrations_and_seed_exist() {
     assert!(ongoing_cnt >= 1, "expected at least one ongoing activity");
 }
 
+#[tokio::test]
+async fn check_constraints_backfill_adds_each_missing_constraint() {
+    let _ = dotenvy::dotenv();
+    let tdb = TestPg::new(base_url(), Path::new("./migrations"));
+    let pool = tdb.get_pool().await;
+    let count = || async {
+        sqlx::query_scalar::<_, i64>(
+            "SELECT COUNT(*) FROM pg_constraint WHERE conrelid IN ('activities'::regclass, 'prizes'::regclass) AND conname LIKE '%\\_check'"
+        ).fetch_one(&pool).await.unwrap()
+    };
+    let all = count().await;
+
+    // as if 0012 stopped after the first constraint of each table
+    sqlx::query("ALTER TABLE activities DROP CONSTRAINT activities_cooldown_check, DROP CONSTRAINT activities_no_win_weight_check")
+        .execute(&pool).await.unwrap();
+    sqlx::query("ALTER TABLE prizes DROP CONSTRAINT prizes_tier_check").execute(&pool).await.unwrap();
+    assert_eq!(count().await, all - 3);
+    sqlx::raw_sql(include_str!("../migrations/0016_check_constraints_backfill.sql")).execute(&pool).await.unwrap();
+    assert_eq!(count().await, all);
+}
+
 #[tokio::test]
 async fn prize_inventory_decrements_atomically() {
     let _ = dotenvy::dotenv();
@@ -55,3 +76,33 @@ async fn prize_inventory_decrements_atomically() {
     assert_eq!(new_remaining, prize.remaining_count - 1);
     tx.commit().await.unwrap();
 }
+
+#[tokio::test]
+async fn outbox_batch_replay_is_idempotent() {
+    use fast_lottery_engine::services::{record_outbox::{persist_batch, OutboxRecord}, user_service};
+
+    let _ = dotenvy::dotenv();
+    let tdb = TestPg::new(base_url(), Path::new("./migrations"));
+    let pool = tdb.get_pool().await;
+
+    let uid = Uuid::new_v4();
+    user_service::create_user(&pool, uid, "outbox_user", "HASH", &None).await.unwrap();
+    let aid = Uuid::parse_str("11111111-1111-1111-1111-111111111111").unwrap();
+    let pid = Uuid::parse_str("22222222-2222-2222-2222-222222222222").unwrap();
+    let ts = chrono::Utc::now() - chrono::Duration::minutes(5);
+    let batch = vec![
+        OutboxRecord { id: Uuid::new_v4(), user_id: uid, activity_id: aid, prize_id: Some(pid), prize_name: Some("一等奖".into()), created_at: ts, pity: vec![], fair: None, fair_steps: None },
+        OutboxRecord { id: Uuid::new_v4(), user_id: uid, activity_id: aid, prize_id: None, prize_name: None, created_at: ts, pity: vec![], fair: None, fair_steps: None },
+    ];
+
+    // a retried delivery of the same entries must not duplicate records
+    persist_batch(&pool, &batch).await.unwrap();
+    persist_batch(&pool, &batch).await.unwrap();
+
+    let cnt: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM lottery_records WHERE user_id=$1")
+        .bind(uid).fetch_one(&pool).await.unwrap();
+    assert_eq!(cnt, 2);
+    let last: Option<chrono::DateTime<chrono::Utc>> = sqlx::query_scalar("SELECT last_lottery_at FROM users WHERE id=$1")
+        .bind(uid).fetch_one(&pool).await.unwrap();
+    assert_eq!(last.map(|t| t.timestamp_micros()), Some(ts.timestamp_micros()));
+}
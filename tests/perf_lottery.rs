@@ -1,6 +1,6 @@
 use std::{path::Path, time::Instant};
 
-use fast_lottery_engine::services::{lottery_service, user_service};
+use fast_lottery_engine::{config::{Config, DrawBackend, DrawConfig}, jwt_keys::JwtKeys, services::{lottery_service, user_service}, state::{AppState, StateData}};
 use sqlx::types::Uuid;
 use sqlx_db_tester::TestPg;
 use tokio::task::JoinSet;
@@ -9,6 +9,14 @@ fn base_url() -> String {
     std::env::var("TEST_PG_URL").expect("set TEST_PG_URL for tests")
 }
 
+fn sql_state(pool: &sqlx::PgPool) -> AppState {
+    let cfg = Config {
+        draw: DrawConfig { backend: DrawBackend::Sql, ..Default::default() },
+        ..Config::new("unused".to_string(), JwtKeys::hs256("test_secret"))
+    };
+    StateData::new(pool.clone(), cfg, None)
+}
+
 fn env_usize(name: &str, default_: usize) -> usize {
     std::env::var(name).ok().and_then(|s| s.parse().ok()).unwrap_or(default_)
 }
@@ -23,9 +31,10 @@ async fn perf_draw_concurrency() {
 
     let tdb = TestPg::new(base_url(), Path::new("./migrations"));
     let pool = tdb.get_pool().await;
+    let state = sql_state(&pool);
 
     // Ensure there is plenty of stock for performance run: insert a big prize under any activity
-    let act_id: Uuid = sqlx::query_scalar("SELECT id FROM activities LIMIT 1").fetch_one(&pool).await.unwrap();
+    let act_id: Uuid = sqlx::query_scalar("SELECT id FROM activities WHERE status='ongoing' LIMIT 1").fetch_one(&pool).await.unwrap();
     let prize_id = Uuid::new_v4();
     let total = (ops as i64) + 100;
     sqlx::query(
@@ -62,10 +71,10 @@ async fn perf_draw_concurrency() {
         while js.len() < conc {
             match it.next() {
                 Some(uid) => {
-                    let pool_cloned = pool.clone();
+                    let state = state.clone();
                     js.spawn(async move {
                         let t0 = Instant::now();
-                        let _ = lottery_service::draw(&pool_cloned, uid).await;
+                        let _ = lottery_service::draw(&state, uid, act_id).await;
                         t0.elapsed().as_micros()
                     });
                 }
@@ -73,7 +82,7 @@ async fn perf_draw_concurrency() {
             }
         }
         if js.is_empty() { break; }
-        if let Some(res) = js.join_next().await { match res { Ok(us) => { completed += 1; durations.push(us); }, Err(_) => {} } }
+        if let Some(Ok(us)) = js.join_next().await { completed += 1; durations.push(us); }
     }
     let total_elapsed = start.elapsed();
 
@@ -0,0 +1,65 @@
+use fast_lottery_engine::models::{PrizeSelectorKind, ProbabilityUnit};
+use fast_lottery_engine::services::prize_selector::SelectionConfig;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+const DRAWS: usize = 200_000;
+
+// frequency of each prize index plus "no prize" in the last slot
+fn frequencies(cfg: SelectionConfig, probabilities: &[i32]) -> Vec<f64> {
+    let selector = cfg.build(probabilities);
+    let mut rng = StdRng::seed_from_u64(7);
+    let mut hits = vec![0usize; probabilities.len() + 1];
+    for _ in 0..DRAWS {
+        hits[selector.select(rng.gen()).unwrap_or(probabilities.len())] += 1;
+    }
+    hits.into_iter().map(|h| h as f64 / DRAWS as f64).collect()
+}
+
+fn assert_close(got: &[f64], want: &[f64]) {
+    for (g, w) in got.iter().zip(want) {
+        assert!((g - w).abs() < 0.005, "got {:?}, want {:?}", got, want);
+    }
+}
+
+#[test]
+fn linear_and_alias_agree_on_every_unit() {
+    for kind in [PrizeSelectorKind::Linear, PrizeSelectorKind::Alias] {
+        let cfg = |probability_unit, no_win_weight| SelectionConfig { prize_selector: kind, probability_unit, no_win_weight, ..Default::default() };
+        // percent: the remaining 60% lose
+        assert_close(&frequencies(cfg(ProbabilityUnit::Percent, 0), &[10, 30, 0]), &[0.10, 0.30, 0.0, 0.60]);
+        // basis points: 2.5% and 0.5%
+        assert_close(&frequencies(cfg(ProbabilityUnit::BasisPoints, 0), &[250, 50]), &[0.025, 0.005, 0.97]);
+        // weights do not need to add up to anything; losing weighs no_win_weight
+        assert_close(&frequencies(cfg(ProbabilityUnit::Weight, 6), &[1, 3]), &[0.1, 0.3, 0.6]);
+        assert_close(&frequencies(cfg(ProbabilityUnit::Weight, 0), &[1, 1]), &[0.5, 0.5, 0.0]);
+    }
+}
+
+#[test]
+fn selectors_without_weight_never_select() {
+    for kind in [PrizeSelectorKind::Linear, PrizeSelectorKind::Alias] {
+        let cfg = SelectionConfig { prize_selector: kind, probability_unit: ProbabilityUnit::Weight, no_win_weight: 0, ..Default::default() };
+        let mut rng = StdRng::seed_from_u64(1);
+        assert_eq!(cfg.build(&[0, -5]).select(rng.gen()), None);
+        assert_eq!(cfg.build(&[]).select(rng.gen()), None);
+    }
+}
+
+#[test]
+fn fallback_candidates_follow_policy() {
+    use fast_lottery_engine::models::SoldOutPolicy;
+    use fast_lottery_engine::services::prize_selector::{fallback_candidates, redraw};
+
+    let tiers = [2, 1, 3, 2, 3];
+    assert!(fallback_candidates(SoldOutPolicy::Lose, &tiers, 1).is_empty());
+    // strictly lower tiers, nearest first
+    assert_eq!(fallback_candidates(SoldOutPolicy::Downgrade, &tiers, 1), vec![0, 3, 2, 4]);
+    assert_eq!(fallback_candidates(SoldOutPolicy::Downgrade, &tiers, 0), vec![2, 4]);
+    assert!(fallback_candidates(SoldOutPolicy::Downgrade, &tiers, 4).is_empty());
+    assert_eq!(fallback_candidates(SoldOutPolicy::Redraw, &tiers, 2), vec![0, 1, 3, 4]);
+
+    // weights renormalised over the in-stock candidates
+    assert_eq!(redraw(&[(3, 10), (4, 30)], 0.2), Some(3));
+    assert_eq!(redraw(&[(3, 10), (4, 30)], 0.3), Some(4));
+    assert_eq!(redraw(&[(3, 0), (4, 0)], 0.5), None);
+}
@@ -1,6 +1,6 @@
 use std::path::Path;
 
-use fast_lottery_engine::services::{lottery_service, user_service};
+use fast_lottery_engine::{config::{Config, DrawBackend, DrawConfig}, error::{AppError, Reason}, jwt_keys::JwtKeys, services::{lottery_service, quota_service, user_service}, state::{AppState, StateData}};
 use sqlx::types::Uuid;
 use sqlx_db_tester::TestPg;
 
@@ -8,17 +8,494 @@ fn base_url() -> String {
     std::env::var("TEST_PG_URL").expect("set TEST_PG_URL for tests")
 }
 
+fn sql_state(pool: &sqlx::PgPool) -> AppState {
+    let cfg = Config {
+        draw: DrawConfig { backend: DrawBackend::Sql, ..Default::default() },
+        ..Config::new("unused".to_string(), JwtKeys::hs256("test_secret"))
+    };
+    StateData::new(pool.clone(), cfg, None)
+}
+
+/// Redis-backend state for the Lua draw path; None (the test returns early) when TEST_REDIS_URL is unset.
+async fn redis_state(pool: &sqlx::PgPool) -> Option<AppState> {
+    let url = std::env::var("TEST_REDIS_URL").ok()?;
+    let redis = fast_lottery_engine::redis_client::connect_manager(&url).await.expect("TEST_REDIS_URL is not reachable");
+    let mut cfg = Config {
+        draw: DrawConfig { backend: DrawBackend::Redis, ..Default::default() },
+        ..Config::new("unused".to_string(), JwtKeys::hs256("test_secret"))
+    };
+    cfg.redis.url = url;
+    Some(StateData::new(pool.clone(), cfg, Some(redis)))
+}
+
+// ongoing demo activity from seed 0002
+const SEED_ACTIVITY: &str = "11111111-1111-1111-1111-111111111111";
+
 #[tokio::test]
 async fn draw_flow_with_new_user() {
     let _ = dotenvy::dotenv();
     let tdb = TestPg::new(base_url(), Path::new("./migrations"));
     let pool = tdb.get_pool().await;
+    let state = sql_state(&pool);
 
     // create a user
     let uid = Uuid::new_v4();
     user_service::create_user(&pool, uid, "tester", "HASH", &None).await.unwrap();
 
     // draw once; result should be either won or not, but no error
-    let res = lottery_service::draw(&pool, uid).await.unwrap();
-    assert!(res.won || (!res.won && res.prize_id.is_none()));
+    let aid = Uuid::parse_str(SEED_ACTIVITY).unwrap();
+    let res = lottery_service::draw(&state, uid, aid).await.unwrap();
+    assert!(res.won || res.prize_id.is_none());
+}
+
+#[tokio::test]
+async fn draw_rejected_when_activity_not_ongoing() {
+    let _ = dotenvy::dotenv();
+    let tdb = TestPg::new(base_url(), Path::new("./migrations"));
+    let pool = tdb.get_pool().await;
+    let state = sql_state(&pool);
+
+    let uid = Uuid::new_v4();
+    user_service::create_user(&pool, uid, "tester", "HASH", &None).await.unwrap();
+
+    // paused activity
+    let aid = Uuid::parse_str(SEED_ACTIVITY).unwrap();
+    sqlx::query("UPDATE activities SET status='paused' WHERE id=$1").bind(aid).execute(&pool).await.unwrap();
+    assert!(lottery_service::draw(&state, uid, aid).await.is_err());
+
+    // ongoing but window already closed
+    sqlx::query("UPDATE activities SET status='ongoing', end_time=now() - interval '1 hour' WHERE id=$1")
+        .bind(aid).execute(&pool).await.unwrap();
+    assert!(lottery_service::draw(&state, uid, aid).await.is_err());
+
+    // unknown activity
+    assert!(lottery_service::draw(&state, uid, Uuid::new_v4()).await.is_err());
+
+    // nothing was recorded for rejected draws
+    let cnt: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM lottery_records WHERE user_id=$1")
+        .bind(uid).fetch_one(&pool).await.unwrap();
+    assert_eq!(cnt, 0);
+}
+
+#[tokio::test]
+async fn second_draw_within_cooldown_reports_wait() {
+    let _ = dotenvy::dotenv();
+    let tdb = TestPg::new(base_url(), Path::new("./migrations"));
+    let pool = tdb.get_pool().await;
+    let state = sql_state(&pool);
+
+    let uid = Uuid::new_v4();
+    user_service::create_user(&pool, uid, "tester", "HASH", &None).await.unwrap();
+
+    let aid = Uuid::parse_str(SEED_ACTIVITY).unwrap();
+    sqlx::query("UPDATE activities SET draw_cooldown_secs=30 WHERE id=$1").bind(aid).execute(&pool).await.unwrap();
+
+    lottery_service::draw(&state, uid, aid).await.unwrap();
+    match lottery_service::draw(&state, uid, aid).await {
+        Err(AppError::TooFrequent(wait)) => assert!((1..=30).contains(&wait), "unexpected wait {}", wait),
+        other => panic!("expected cooldown rejection, got {:?}", other),
+    }
+
+    // cooldown disabled: draws back to back are allowed
+    sqlx::query("UPDATE activities SET draw_cooldown_secs=0 WHERE id=$1").bind(aid).execute(&pool).await.unwrap();
+    lottery_service::draw(&state, uid, aid).await.unwrap();
+}
+
+#[tokio::test]
+async fn daily_quota_limits_draws_and_shows_remaining() {
+    let _ = dotenvy::dotenv();
+    let tdb = TestPg::new(base_url(), Path::new("./migrations"));
+    let pool = tdb.get_pool().await;
+    let state = sql_state(&pool);
+
+    let uid = Uuid::new_v4();
+    user_service::create_user(&pool, uid, "tester", "HASH", &None).await.unwrap();
+
+    let aid = Uuid::parse_str(SEED_ACTIVITY).unwrap();
+    sqlx::query("UPDATE activities SET draw_cooldown_secs=0, max_draws_daily=2, max_draws_total=10, quota_timezone='Asia/Shanghai' WHERE id=$1")
+        .bind(aid).execute(&pool).await.unwrap();
+
+    lottery_service::draw(&state, uid, aid).await.unwrap();
+    lottery_service::draw(&state, uid, aid).await.unwrap();
+    assert!(matches!(lottery_service::draw(&state, uid, aid).await, Err(AppError::BadRequest { reason: Reason::DailyQuotaExhausted, .. })));
+
+    let quotas = quota_service::remaining_for_user(&pool, None, uid).await.unwrap();
+    let q = quotas.iter().find(|q| q.activity_id == aid).unwrap();
+    assert_eq!(q.draws_today_remaining, Some(0));
+    assert_eq!(q.draws_total_remaining, Some(8));
+    assert_eq!(q.wins_remaining, None);
+}
+
+#[tokio::test]
+async fn wins_of_deleted_prizes_still_count_toward_the_quota() {
+    let _ = dotenvy::dotenv();
+    let tdb = TestPg::new(base_url(), Path::new("./migrations"));
+    let pool = tdb.get_pool().await;
+    let state = sql_state(&pool);
+
+    let uid = Uuid::new_v4();
+    user_service::create_user(&pool, uid, "tester", "HASH", &None).await.unwrap();
+
+    // every draw wins until the single allowed win is used up
+    let aid = Uuid::parse_str(SEED_ACTIVITY).unwrap();
+    sqlx::query("UPDATE activities SET draw_cooldown_secs=0, probability_unit='weight', no_win_weight=0, max_wins_total=1 WHERE id=$1")
+        .bind(aid).execute(&pool).await.unwrap();
+    sqlx::query("UPDATE prizes SET probability=100 WHERE activity_id=$1").bind(aid).execute(&pool).await.unwrap();
+
+    let won = lottery_service::draw(&state, uid, aid).await.unwrap();
+    assert!(won.won);
+    // the record keeps the prize name when the prize itself is deleted
+    sqlx::query("DELETE FROM prizes WHERE id=$1").bind(won.prize_id).execute(&pool).await.unwrap();
+
+    assert!(!lottery_service::draw(&state, uid, aid).await.unwrap().won);
+    let quotas = quota_service::remaining_for_user(&pool, None, uid).await.unwrap();
+    assert_eq!(quotas.iter().find(|q| q.activity_id == aid).unwrap().wins_remaining, Some(0));
+}
+
+#[tokio::test]
+async fn sold_out_prize_follows_activity_policy() {
+    let _ = dotenvy::dotenv();
+    let tdb = TestPg::new(base_url(), Path::new("./migrations"));
+    let pool = tdb.get_pool().await;
+    let state = sql_state(&pool);
+
+    let uid = Uuid::new_v4();
+    user_service::create_user(&pool, uid, "tester", "HASH", &None).await.unwrap();
+
+    // only the sold-out top prize (一等奖, tier 1) can be selected
+    let aid = Uuid::parse_str(SEED_ACTIVITY).unwrap();
+    sqlx::query("UPDATE activities SET draw_cooldown_secs=0, probability_unit='weight', no_win_weight=0 WHERE id=$1")
+        .bind(aid).execute(&pool).await.unwrap();
+    sqlx::query("UPDATE prizes SET probability = CASE WHEN tier=1 THEN 100 ELSE 0 END, remaining_count = CASE WHEN tier=1 THEN 0 ELSE remaining_count END WHERE activity_id=$1")
+        .bind(aid).execute(&pool).await.unwrap();
+
+    let res = lottery_service::draw(&state, uid, aid).await.unwrap();
+    assert!(!res.won, "policy 'lose' must not hand out another prize");
+
+    sqlx::query("UPDATE activities SET sold_out_policy='downgrade' WHERE id=$1").bind(aid).execute(&pool).await.unwrap();
+    let res = lottery_service::draw(&state, uid, aid).await.unwrap();
+    assert_eq!(res.prize_name.as_deref(), Some("二等奖"));
+
+    // tier 2 gone as well: falls through to tier 3
+    sqlx::query("UPDATE prizes SET remaining_count=0 WHERE activity_id=$1 AND tier=2").bind(aid).execute(&pool).await.unwrap();
+    let res = lottery_service::draw(&state, uid, aid).await.unwrap();
+    assert_eq!(res.prize_name.as_deref(), Some("三等奖"));
+}
+
+#[tokio::test]
+async fn pity_rules_guarantee_prizes_and_reset_on_win() {
+    use fast_lottery_engine::services::pity_service::{self, PityRule};
+
+    let _ = dotenvy::dotenv();
+    let tdb = TestPg::new(base_url(), Path::new("./migrations"));
+    let pool = tdb.get_pool().await;
+    let state = sql_state(&pool);
+
+    let uid = Uuid::new_v4();
+    user_service::create_user(&pool, uid, "tester", "HASH", &None).await.unwrap();
+
+    // practically never wins on its own; only 三等奖 (tier 3) carries weight
+    let aid = Uuid::parse_str(SEED_ACTIVITY).unwrap();
+    sqlx::query("UPDATE activities SET draw_cooldown_secs=0, probability_unit='weight', no_win_weight=100000000 WHERE id=$1")
+        .bind(aid).execute(&pool).await.unwrap();
+    sqlx::query("UPDATE prizes SET probability = CASE WHEN tier=3 THEN 1 ELSE 0 END WHERE activity_id=$1")
+        .bind(aid).execute(&pool).await.unwrap();
+    // any prize after 2 losses in a row, top prize after 4 draws without it
+    pity_service::insert_rules(&pool, aid, &[PityRule { tier: 3, threshold: 2 }, PityRule { tier: 1, threshold: 4 }])
+        .await.unwrap();
+
+    let mut names = Vec::new();
+    for _ in 0..5 {
+        names.push(lottery_service::draw(&state, uid, aid).await.unwrap().prize_name);
+    }
+    let expected = [None, None, Some("三等奖"), None, Some("一等奖")];
+    assert_eq!(names.iter().map(|n| n.as_deref()).collect::<Vec<_>>(), expected);
+
+    // the top prize qualifies for both rules, so both counters are back to 0
+    let status = pity_service::status_for_user(&pool, None, uid).await.unwrap();
+    assert_eq!(status.iter().map(|s| (s.tier, s.draws_since_win, s.guaranteed_in)).collect::<Vec<_>>(), vec![(1, 0, 5), (3, 0, 3)]);
+}
+
+#[tokio::test]
+async fn provably_fair_draws_verify_after_the_activity_ends() {
+    use fast_lottery_engine::models::ActivityStatus;
+    use fast_lottery_engine::services::activity_service::{self, NewActivity};
+    use fast_lottery_engine::services::fair_service;
+    use fast_lottery_engine::services::prize_service::{self, NewPrize};
+
+    let _ = dotenvy::dotenv();
+    let tdb = TestPg::new(base_url(), Path::new("./migrations"));
+    let pool = tdb.get_pool().await;
+    let state = sql_state(&pool);
+
+    let uid = Uuid::new_v4();
+    user_service::create_user(&pool, uid, "tester", "HASH", &None).await.unwrap();
+
+    let aid = Uuid::new_v4();
+    activity_service::create_activity(&pool, aid, NewActivity {
+        name: "fair".into(),
+        description: None,
+        start_time: chrono::Utc::now() - chrono::Duration::hours(1),
+        end_time: chrono::Utc::now() + chrono::Duration::hours(1),
+        status: ActivityStatus::Ongoing,
+        draw_cooldown_secs: 0,
+        max_draws_total: None,
+        max_draws_daily: None,
+        max_wins_total: None,
+        quota_timezone: "UTC".into(),
+        selection: Default::default(),
+        pity_rules: vec![],
+        provably_fair: true,
+    }).await.unwrap();
+    let mut prize_ids = Vec::new();
+    for (name, probability) in [("A", 20), ("B", 30)] {
+        let id = Uuid::new_v4();
+        prize_service::create_prize(&pool, id, NewPrize {
+            activity_id: aid, name: name.into(), description: None, total_count: 100, probability, tier: 1, is_enabled: true,
+        }).await.unwrap();
+        prize_ids.push(id);
+    }
+
+    // the hash is published up front, the seed is not
+    let before = fair_service::commitment(&pool, aid).await.unwrap();
+    assert!(before.server_seed.is_none());
+
+    let mut proofs = Vec::new();
+    for _ in 0..5 {
+        let res = lottery_service::draw_with_seed(&state, uid, aid, Some("my-seed")).await.unwrap();
+        let proof = res.fair.expect("provably fair draws carry their inputs");
+        assert_eq!(proof.inputs.server_seed_hash, before.server_seed_hash);
+        proofs.push((proof, res.prize_id));
+    }
+    assert_eq!(proofs.iter().map(|(p, _)| p.inputs.nonce).collect::<Vec<_>>(), vec![1, 2, 3, 4, 5]);
+    assert!(lottery_service::draw_with_seed(&state, uid, aid, Some("bad seed")).await.is_err());
+    assert!(fair_service::verify_record(&pool, proofs[0].0.record_id).await.is_err());
+    // later prize edits do not change what past rolls select
+    let patch = prize_service::PrizePatch { probability: Some(70), ..Default::default() };
+    prize_service::update_prize(&pool, None, prize_ids[0], patch).await.unwrap();
+    let patch = prize_service::PrizePatch { is_enabled: Some(false), ..Default::default() };
+    prize_service::update_prize(&pool, None, prize_ids[1], patch).await.unwrap();
+
+    sqlx::query("UPDATE activities SET status='ended' WHERE id=$1").bind(aid).execute(&pool).await.unwrap();
+    let revealed = fair_service::commitment(&pool, aid).await.unwrap();
+    assert_eq!(fair_service::hash_seed(revealed.server_seed.as_deref().unwrap()), before.server_seed_hash);
+    for (proof, prize_id) in proofs {
+        let v = fair_service::verify_record(&pool, proof.record_id).await.unwrap();
+        assert!(v.hash_matches && v.matches, "{:?}", v);
+        assert_eq!(v.recorded_prize_id, prize_id);
+        assert_eq!(v.selection_hash, proof.inputs.selection_hash);
+        assert_eq!(v.selection.unwrap().prizes.iter().map(|p| p.0).collect::<Vec<_>>(), { let mut ids = prize_ids.clone(); ids.sort(); ids });
+    }
+    // with the seed public the activity cannot take draws again
+    for patch in [
+        activity_service::ActivityPatch { status: Some(ActivityStatus::Ongoing), ..Default::default() },
+        activity_service::ActivityPatch { end_time: Some(chrono::Utc::now() + chrono::Duration::days(1)), ..Default::default() },
+    ] {
+        let err = activity_service::update_activity(&pool, aid, patch).await.unwrap_err();
+        assert_eq!(err.reason(), Reason::FairSeedPublic);
+        assert_eq!(axum::response::IntoResponse::into_response(err).status(), axum::http::StatusCode::CONFLICT);
+    }
+    let renamed = activity_service::ActivityPatch { name: Some("fair, ended".into()), ..Default::default() };
+    assert!(activity_service::update_activity(&pool, aid, renamed).await.unwrap().is_some());
+}
+
+#[test]
+fn fair_steps_decode_what_the_draw_script_records() {
+    use fast_lottery_engine::models::SoldOutPolicy;
+    use fast_lottery_engine::services::fair_service::FairSteps;
+
+    // LUA_DRAW appends the wins quota decision and the sold-out candidates to the choice it was given
+    let id = Uuid::new_v4();
+    let steps = FairSteps::decode(&format!("{}:1:{}", FairSteps::encode_choice(Some(2), SoldOutPolicy::Redraw), id)).unwrap();
+    assert_eq!(steps, FairSteps { forced_tier: Some(2), policy: SoldOutPolicy::Redraw, may_win: true, sold_out: vec![id] });
+    let steps = FairSteps::decode(&format!("{}:0:", FairSteps::encode_choice(None, SoldOutPolicy::Lose))).unwrap();
+    assert_eq!(steps, FairSteps { forced_tier: None, policy: SoldOutPolicy::Lose, may_win: false, sold_out: vec![] });
+    assert!(FairSteps::decode("").is_none());
+}
+
+#[tokio::test]
+async fn provably_fair_pity_redraws_and_wins_quota_verify() {
+    use fast_lottery_engine::models::{ActivityStatus, ProbabilityUnit, SoldOutPolicy};
+    use fast_lottery_engine::services::activity_service::{self, NewActivity};
+    use fast_lottery_engine::services::fair_service;
+    use fast_lottery_engine::services::pity_service::PityRule;
+    use fast_lottery_engine::services::prize_selector::SelectionConfig;
+    use fast_lottery_engine::services::prize_service::{self, NewPrize};
+
+    let _ = dotenvy::dotenv();
+    let tdb = TestPg::new(base_url(), Path::new("./migrations"));
+    let pool = tdb.get_pool().await;
+    let state = sql_state(&pool);
+
+    let uid = Uuid::new_v4();
+    user_service::create_user(&pool, uid, "tester", "HASH", &None).await.unwrap();
+
+    // the selection practically never wins; every third draw is guaranteed a tier <= 2 prize, nearly always the
+    // sold-out A, which the guarantee redraws among B and C; the second win uses up the wins quota
+    let aid = Uuid::new_v4();
+    activity_service::create_activity(&pool, aid, NewActivity {
+        name: "fair pity".into(),
+        description: None,
+        start_time: chrono::Utc::now() - chrono::Duration::hours(1),
+        end_time: chrono::Utc::now() + chrono::Duration::hours(1),
+        status: ActivityStatus::Ongoing,
+        draw_cooldown_secs: 0,
+        max_draws_total: None,
+        max_draws_daily: None,
+        max_wins_total: Some(2),
+        quota_timezone: "UTC".into(),
+        selection: SelectionConfig {
+            probability_unit: ProbabilityUnit::Weight,
+            no_win_weight: 1_000_000_000,
+            sold_out_policy: SoldOutPolicy::Downgrade,
+            ..Default::default()
+        },
+        pity_rules: vec![PityRule { tier: 2, threshold: 2 }],
+        provably_fair: true,
+    }).await.unwrap();
+    let mut sold_out_prize = Uuid::nil();
+    for (name, total_count, probability) in [("A", 0, 1000), ("B", 10, 1), ("C", 10, 1)] {
+        let id = Uuid::new_v4();
+        prize_service::create_prize(&pool, id, NewPrize {
+            activity_id: aid, name: name.into(), description: None, total_count, probability, tier: if name == "A" { 1 } else { 2 }, is_enabled: true,
+        }).await.unwrap();
+        if name == "A" {
+            sold_out_prize = id;
+        }
+    }
+
+    let mut records = Vec::new();
+    for _ in 0..9 {
+        let res = lottery_service::draw(&state, uid, aid).await.unwrap();
+        records.push((res.fair.unwrap().record_id, res.won));
+    }
+    assert_eq!(records.iter().map(|r| r.1).collect::<Vec<_>>(), [false, false, true, false, false, true, false, false, false]);
+
+    sqlx::query("UPDATE activities SET status='ended' WHERE id=$1").bind(aid).execute(&pool).await.unwrap();
+    let mut redrawn = false;
+    for (i, (record_id, _)) in records.into_iter().enumerate() {
+        let v = fair_service::verify_record(&pool, record_id).await.unwrap();
+        assert!(v.hash_matches && v.matches, "{:?}", v);
+        let steps = v.steps.unwrap();
+        let forced = i % 3 == 2;
+        assert_eq!(steps.forced_tier, forced.then_some(2));
+        assert_eq!(steps.policy, if forced { SoldOutPolicy::Redraw } else { SoldOutPolicy::Downgrade });
+        assert_eq!(steps.may_win, i < 6);
+        redrawn |= steps.sold_out.contains(&sold_out_prize) && v.recorded_prize_id.is_some();
+    }
+    assert!(redrawn, "a guaranteed draw should have redrawn past the sold-out prize");
+}
+
+#[tokio::test]
+async fn redis_draws_apply_quotas_sold_out_policy_and_pity() {
+    use fast_lottery_engine::models::{ActivityStatus, ProbabilityUnit, SoldOutPolicy};
+    use fast_lottery_engine::services::activity_service::{self, NewActivity};
+    use fast_lottery_engine::services::pity_service::PityRule;
+    use fast_lottery_engine::services::prize_selector::SelectionConfig;
+    use fast_lottery_engine::services::prize_service::{self, NewPrize};
+    use fast_lottery_engine::services::stock_sync;
+
+    let _ = dotenvy::dotenv();
+    let tdb = TestPg::new(base_url(), Path::new("./migrations"));
+    let pool = tdb.get_pool().await;
+    let Some(state) = redis_state(&pool).await else { return };
+    let uid = Uuid::new_v4();
+    user_service::create_user(&pool, uid, "tester", "HASH", &None).await.unwrap();
+
+    // fresh activities and prizes, so the Redis keys are not shared with other tests; returns the prize ids
+    let setup = |no_win_weight: i32, sold_out_policy: SoldOutPolicy, max_draws_daily: Option<i32>, pity_rules: Vec<PityRule>, prizes: Vec<(&'static str, i32, i32, i64)>| {
+        let (pool, state) = (pool.clone(), state.clone());
+        async move {
+            let aid = Uuid::new_v4();
+            activity_service::create_activity(&pool, aid, NewActivity {
+                name: "redis".into(),
+                description: None,
+                start_time: chrono::Utc::now() - chrono::Duration::hours(1),
+                end_time: chrono::Utc::now() + chrono::Duration::hours(1),
+                status: ActivityStatus::Ongoing,
+                draw_cooldown_secs: 0,
+                max_draws_total: None,
+                max_draws_daily,
+                max_wins_total: None,
+                quota_timezone: "UTC".into(),
+                selection: SelectionConfig { probability_unit: ProbabilityUnit::Weight, no_win_weight, sold_out_policy, ..Default::default() },
+                pity_rules,
+                provably_fair: false,
+            }).await.unwrap();
+            let mut ids = Vec::new();
+            for (name, tier, probability, total_count) in prizes {
+                let id = Uuid::new_v4();
+                prize_service::create_prize(&pool, id, NewPrize {
+                    activity_id: aid, name: name.into(), description: None, total_count, probability, tier, is_enabled: true,
+                }).await.unwrap();
+                ids.push(id);
+            }
+            let mut redis = state.redis.clone().unwrap();
+            stock_sync::reconcile_stock(&pool, &mut redis, &state.metrics, Some(&ids), true).await.unwrap();
+            (aid, ids)
+        }
+    };
+
+    // daily quota
+    let (aid, _) = setup(1, SoldOutPolicy::Lose, Some(2), vec![], vec![("一等奖", 1, 1, 10)]).await;
+    lottery_service::draw(&state, uid, aid).await.unwrap();
+    lottery_service::draw(&state, uid, aid).await.unwrap();
+    assert!(matches!(lottery_service::draw(&state, uid, aid).await, Err(AppError::BadRequest { reason: Reason::DailyQuotaExhausted, .. })));
+
+    // only the sold-out top prize can be selected
+    let prizes = vec![("一等奖", 1, 100, 0), ("二等奖", 2, 0, 5)];
+    let (aid, _) = setup(0, SoldOutPolicy::Lose, None, vec![], prizes.clone()).await;
+    let res = lottery_service::draw(&state, uid, aid).await.unwrap();
+    assert!(!res.won && res.sold_out, "policy 'lose' must not hand out another prize");
+    let (aid, ids) = setup(0, SoldOutPolicy::Downgrade, None, vec![], prizes).await;
+    let res = lottery_service::draw(&state, uid, aid).await.unwrap();
+    assert_eq!(res.prize_name.as_deref(), Some("二等奖"));
+    let mut redis = state.redis.clone().unwrap();
+    let stock: Option<i64> = redis::cmd("GET").arg(format!("lottery:stock:{}", ids[1])).query_async(&mut redis).await.unwrap();
+    let sold: Option<i64> = redis::cmd("GET").arg(stock_sync::sold_key(ids[1])).query_async(&mut redis).await.unwrap();
+    assert_eq!((stock, sold), (Some(4), Some(1)));
+
+    // pity: any prize after 2 losses in a row, top prize after 4 draws without it
+    let rules = vec![PityRule { tier: 3, threshold: 2 }, PityRule { tier: 1, threshold: 4 }];
+    let (aid, _) = setup(100000000, SoldOutPolicy::Lose, None, rules, vec![("一等奖", 1, 0, 10), ("三等奖", 3, 1, 10)]).await;
+    let mut names = Vec::new();
+    for _ in 0..5 {
+        names.push(lottery_service::draw(&state, uid, aid).await.unwrap().prize_name);
+    }
+    assert_eq!(names.iter().map(|n| n.as_deref()).collect::<Vec<_>>(), [None, None, Some("三等奖"), None, Some("一等奖")]);
+}
+
+#[tokio::test]
+async fn concurrent_prize_creates_share_one_probability_budget() {
+    use fast_lottery_engine::models::ActivityStatus;
+    use fast_lottery_engine::services::activity_service::{self, NewActivity};
+    use fast_lottery_engine::services::prize_service::{self, NewPrize};
+
+    let _ = dotenvy::dotenv();
+    let tdb = TestPg::new(base_url(), Path::new("./migrations"));
+    let pool = tdb.get_pool().await;
+    let aid = Uuid::new_v4();
+    activity_service::create_activity(&pool, aid, NewActivity {
+        name: "budget".into(),
+        description: None,
+        start_time: chrono::Utc::now(),
+        end_time: chrono::Utc::now() + chrono::Duration::hours(1),
+        status: ActivityStatus::Planned,
+        draw_cooldown_secs: 0,
+        max_draws_total: None,
+        max_draws_daily: None,
+        max_wins_total: None,
+        quota_timezone: "UTC".into(),
+        selection: Default::default(),
+        pity_rules: vec![],
+        provably_fair: false,
+    }).await.unwrap();
+
+    // 60% each: together they would hand out 120%
+    let create = || prize_service::create_prize(&pool, Uuid::new_v4(), NewPrize {
+        activity_id: aid, name: "60%".into(), description: None, total_count: 1, probability: 60, tier: 1, is_enabled: true,
+    });
+    let results = futures_util::future::join_all((0..4).map(|_| create())).await;
+    assert_eq!(results.iter().filter(|r| r.is_ok()).count(), 1);
+    assert!(results.iter().all(|r| r.is_ok() || matches!(r, Err(AppError::Validation(_)))));
 }
@@ -0,0 +1,241 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use fast_lottery_engine::metrics::Metrics;
+use fast_lottery_engine::redis_client::connect_manager;
+use fast_lottery_engine::services::prize_service::{self, NewPrize};
+use fast_lottery_engine::services::stock_sync::{self, flush_once, pending_key, sold_key, PendingDelta, RedisStock, SoldDeltaStore, StockDrift};
+use redis::aio::ConnectionManager as RedisManager;
+use sqlx::{types::Uuid, PgPool};
+use sqlx_db_tester::TestPg;
+
+fn base_url() -> String {
+    std::env::var("TEST_PG_URL").expect("set TEST_PG_URL for tests")
+}
+
+/// In-memory stand-in for the Redis sold/pending keys, same semantics as LUA_CLAIM_SOLD / LUA_ACK_SOLD.
+#[derive(Default)]
+struct MemStore {
+    sold: HashMap<Uuid, i64>,
+    pending: HashMap<Uuid, PendingDelta>,
+    fail_ack: bool,
+}
+
+impl SoldDeltaStore for MemStore {
+    async fn claim(&mut self, prize_id: Uuid) -> anyhow::Result<Option<PendingDelta>> {
+        if let Some(p) = self.pending.get(&prize_id) {
+            return Ok(Some(*p));
+        }
+        let delta = self.sold.remove(&prize_id).unwrap_or(0);
+        if delta <= 0 {
+            return Ok(None);
+        }
+        let p = PendingDelta { batch_id: Uuid::new_v4(), delta };
+        self.pending.insert(prize_id, p);
+        Ok(Some(p))
+    }
+
+    async fn ack(&mut self, prize_id: Uuid, batch_id: Uuid) -> anyhow::Result<()> {
+        if self.fail_ack {
+            anyhow::bail!("connection lost");
+        }
+        if self.pending.get(&prize_id).is_some_and(|p| p.batch_id == batch_id) {
+            self.pending.remove(&prize_id);
+        }
+        Ok(())
+    }
+}
+
+/// Redis for the tests of the Lua scripts; they return early when TEST_REDIS_URL is unset.
+async fn test_redis() -> Option<RedisManager> {
+    let url = std::env::var("TEST_REDIS_URL").ok()?;
+    Some(connect_manager(&url).await.expect("TEST_REDIS_URL is not reachable"))
+}
+
+async fn remaining(pool: &PgPool, pid: Uuid) -> i64 {
+    sqlx::query_scalar("SELECT remaining_count FROM prizes WHERE id=$1").bind(pid).fetch_one(pool).await.unwrap()
+}
+
+async fn any_prize(pool: &PgPool) -> Uuid {
+    sqlx::query_scalar("SELECT id FROM prizes WHERE remaining_count >= 10 LIMIT 1").fetch_one(pool).await.unwrap()
+}
+
+#[tokio::test]
+async fn failed_flush_keeps_delta_pending_until_db_recovers() {
+    let _ = dotenvy::dotenv();
+    let tdb = TestPg::new(base_url(), Path::new("./migrations"));
+    let pool = tdb.get_pool().await;
+    let pid = any_prize(&pool).await;
+    let before = remaining(&pool, pid).await;
+
+    // make every stock update fail, as during a DB outage
+    sqlx::query(
+        r#"CREATE FUNCTION reject_update() RETURNS trigger AS $$ BEGIN RAISE EXCEPTION 'db down'; END $$ LANGUAGE plpgsql"#
+    ).execute(&pool).await.unwrap();
+    sqlx::query("CREATE TRIGGER reject_prize_update BEFORE UPDATE ON prizes FOR EACH ROW EXECUTE FUNCTION reject_update()")
+        .execute(&pool).await.unwrap();
+
+    let mut store = MemStore::default();
+    store.sold.insert(pid, 3);
+    let report = flush_once(&pool, &mut store).await.unwrap();
+    assert_eq!(report.failed_prizes, 1);
+    assert_eq!(store.pending.get(&pid).map(|p| p.delta), Some(3), "delta must stay pending");
+    assert_eq!(remaining(&pool, pid).await, before);
+
+    // draws keep selling meanwhile; they go to the next batch
+    store.sold.insert(pid, 2);
+    sqlx::query("DROP TRIGGER reject_prize_update ON prizes").execute(&pool).await.unwrap();
+
+    let report = flush_once(&pool, &mut store).await.unwrap();
+    assert_eq!((report.flushed, report.failed_prizes), (3, 0));
+    assert_eq!(remaining(&pool, pid).await, before - 3);
+    flush_once(&pool, &mut store).await.unwrap();
+    assert_eq!(remaining(&pool, pid).await, before - 5);
+    assert!(store.pending.is_empty() && store.sold.is_empty());
+}
+
+#[tokio::test]
+async fn batch_replayed_after_lost_ack_is_applied_once() {
+    let _ = dotenvy::dotenv();
+    let tdb = TestPg::new(base_url(), Path::new("./migrations"));
+    let pool = tdb.get_pool().await;
+    let pid = any_prize(&pool).await;
+    let before = remaining(&pool, pid).await;
+
+    // commit succeeds but the process dies before the ack reaches Redis
+    let mut store = MemStore { fail_ack: true, ..Default::default() };
+    store.sold.insert(pid, 4);
+    let report = flush_once(&pool, &mut store).await.unwrap();
+    assert_eq!(report.failed_prizes, 1);
+    assert_eq!(remaining(&pool, pid).await, before - 4);
+
+    store.fail_ack = false;
+    let report = flush_once(&pool, &mut store).await.unwrap();
+    assert_eq!((report.flushed, report.failed_prizes), (0, 0));
+    assert_eq!(remaining(&pool, pid).await, before - 4);
+    assert!(store.pending.is_empty());
+}
+
+#[tokio::test]
+async fn batch_of_a_deleted_prize_is_dropped() {
+    let _ = dotenvy::dotenv();
+    let tdb = TestPg::new(base_url(), Path::new("./migrations"));
+    let pool = tdb.get_pool().await;
+    let pid = any_prize(&pool).await;
+
+    // sold in Redis, then the prize was deleted before the flusher got to it
+    let mut store = MemStore::default();
+    store.sold.insert(pid, 2);
+    sqlx::query("DELETE FROM prizes WHERE id=$1").bind(pid).execute(&pool).await.unwrap();
+
+    assert_eq!(stock_sync::flush_prize(&pool, &mut store, pid).await.unwrap(), (0, 0));
+    assert!(store.pending.is_empty(), "the batch must be acked, not retried");
+    let batches: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM stock_flush_batches").fetch_one(&pool).await.unwrap();
+    assert_eq!(batches, 0);
+}
+
+#[test]
+fn drift_counts_unflushed_sales_and_missing_keys() {
+    let pid = Uuid::new_v4();
+    let measure = |db_remaining, stock, sold, pending, applied_batch| {
+        let d = StockDrift::measure(pid, db_remaining, RedisStock { stock, sold, pending }, applied_batch);
+        (d.unflushed_sold, d.expected, d.drift)
+    };
+    // in sync: 3 of 10 sold and not flushed yet
+    assert_eq!(measure(10, Some(7), 3, None, None), (3, 7, 0));
+    // Redis lost sales to a restart: it would sell 3 units too many
+    assert_eq!(measure(10, Some(10), 3, None, None), (3, 7, 3));
+    // a missing key counts as empty
+    assert_eq!(measure(10, None, 0, None, None), (0, 10, -10));
+    // more sold than Postgres has left never asks for negative stock
+    assert_eq!(measure(2, Some(1), 5, None, None), (5, 0, 1));
+
+    // a claimed batch is still unflushed until Postgres applied it
+    let batch = PendingDelta { batch_id: Uuid::new_v4(), delta: 2 };
+    assert_eq!(measure(10, Some(5), 3, Some(batch), None), (5, 5, 0));
+    assert_eq!(measure(8, Some(5), 3, Some(batch), Some(batch.batch_id)), (3, 5, 0));
+}
+
+#[tokio::test]
+async fn oversold_delta_is_reported_instead_of_silently_dropped() {
+    let _ = dotenvy::dotenv();
+    let tdb = TestPg::new(base_url(), Path::new("./migrations"));
+    let pool = tdb.get_pool().await;
+    let pid = any_prize(&pool).await;
+    let before = remaining(&pool, pid).await;
+
+    let mut store = MemStore::default();
+    store.sold.insert(pid, before + 2);
+    let report = flush_once(&pool, &mut store).await.unwrap();
+    assert_eq!((report.flushed, report.oversold, report.failed_prizes), (before + 2, 2, 0));
+    assert_eq!(remaining(&pool, pid).await, 0);
+}
+
+#[tokio::test]
+async fn reconcile_keeps_unflushed_sales_and_skips_deleted_prizes() {
+    let _ = dotenvy::dotenv();
+    let Some(mut redis) = test_redis().await else { return };
+    let tdb = TestPg::new(base_url(), Path::new("./migrations"));
+    let pool = tdb.get_pool().await;
+    let metrics = Metrics::default();
+    // a fresh prize, so the keys are not shared with other tests on the same Redis
+    let activity_id: Uuid = sqlx::query_scalar("SELECT id FROM activities LIMIT 1").fetch_one(&pool).await.unwrap();
+    let pid = Uuid::new_v4();
+    prize_service::create_prize(&pool, pid, NewPrize {
+        activity_id, name: "reconcile".into(), description: None, total_count: 10, probability: 0, tier: 1, is_enabled: true,
+    }).await.unwrap();
+    let stock = |mut redis: RedisManager| async move {
+        redis::cmd("GET").arg(format!("lottery:stock:{}", pid)).query_async::<_, Option<i64>>(&mut redis).await.unwrap()
+    };
+
+    // 3 sold since the last flush, 2 more claimed by a flush that has not committed yet
+    redis::cmd("SET").arg(sold_key(pid)).arg(3).query_async::<_, ()>(&mut redis).await.unwrap();
+    redis::cmd("HSET").arg(pending_key(pid)).arg("batch").arg(Uuid::new_v4().to_string()).arg("delta").arg(2)
+        .query_async::<_, ()>(&mut redis).await.unwrap();
+    let drift = stock_sync::reconcile_stock(&pool, &mut redis, &metrics, Some(&[pid]), true).await.unwrap();
+    assert_eq!((drift[0].redis_before, drift[0].unflushed_sold, drift[0].expected), (None, 5, 5));
+    assert_eq!(stock(redis.clone()).await, Some(5));
+
+    // once the batch is committed Postgres already counts it
+    let batch = Uuid::new_v4();
+    redis::cmd("HSET").arg(pending_key(pid)).arg("batch").arg(batch.to_string()).query_async::<_, ()>(&mut redis).await.unwrap();
+    sqlx::query("INSERT INTO stock_flush_batches (batch_id, prize_id, delta) VALUES ($1, $2, 2)").bind(batch).bind(pid).execute(&pool).await.unwrap();
+    sqlx::query("UPDATE prizes SET remaining_count = remaining_count - 2 WHERE id=$1").bind(pid).execute(&pool).await.unwrap();
+    let drift = stock_sync::reconcile_stock(&pool, &mut redis, &metrics, Some(&[pid]), true).await.unwrap();
+    assert_eq!((drift[0].unflushed_sold, drift[0].expected, drift[0].drift), (3, 5, 0));
+
+    // a deleted prize is not reconciled back into Redis
+    prize_service::delete_prize(&pool, pid).await.unwrap();
+    stock_sync::forget_prizes(&mut redis, &[pid]).await.unwrap();
+    let drift = stock_sync::reconcile_stock(&pool, &mut redis, &metrics, Some(&[pid]), true).await.unwrap();
+    assert!(drift.is_empty());
+    assert_eq!(stock(redis.clone()).await, None);
+    let sold: Option<i64> = redis::cmd("GET").arg(sold_key(pid)).query_async(&mut redis).await.unwrap();
+    assert_eq!(sold, None);
+}
+
+#[tokio::test]
+async fn take_back_skips_a_pending_batch_postgres_already_applied() {
+    let _ = dotenvy::dotenv();
+    let Some(mut redis) = test_redis().await else { return };
+    let tdb = TestPg::new(base_url(), Path::new("./migrations"));
+    let pool = tdb.get_pool().await;
+    let activity_id: Uuid = sqlx::query_scalar("SELECT id FROM activities LIMIT 1").fetch_one(&pool).await.unwrap();
+    let pid = Uuid::new_v4();
+    prize_service::create_prize(&pool, pid, NewPrize {
+        activity_id, name: "take back".into(), description: None, total_count: 10, probability: 0, tier: 1, is_enabled: false,
+    }).await.unwrap();
+
+    // a batch of 2 committed to Postgres but not acked yet, no stock key (disabled prize)
+    let batch = Uuid::new_v4();
+    redis::cmd("HSET").arg(pending_key(pid)).arg("batch").arg(batch.to_string()).arg("delta").arg(2)
+        .query_async::<_, ()>(&mut redis).await.unwrap();
+    sqlx::query("INSERT INTO stock_flush_batches (batch_id, prize_id, delta) VALUES ($1, $2, 2)").bind(batch).bind(pid).execute(&pool).await.unwrap();
+    sqlx::query("UPDATE prizes SET remaining_count = 8 WHERE id=$1").bind(pid).execute(&pool).await.unwrap();
+
+    let mut tx = pool.begin().await.unwrap();
+    assert_eq!(stock_sync::take_back_stock(&mut tx, &mut redis, pid, 9, 8).await.unwrap(), None);
+    assert_eq!(stock_sync::take_back_stock(&mut tx, &mut redis, pid, 8, 8).await.unwrap(), Some(false));
+    tx.rollback().await.unwrap();
+    stock_sync::forget_prizes(&mut redis, &[pid]).await.unwrap();
+}